@@ -0,0 +1,30 @@
+use pooh::{checksum_reference, BytesExt, DATAGRAM_BUF_SIZE};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+fn bench(name: &str, size: usize, f: impl Fn(&[u8]) -> u16) {
+    let data: Vec<u8> = (0..size).map(|i| (i * 31 + 7) as u8).collect();
+    let mut iters = 0u64;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        for _ in 0..64 {
+            black_box(f(black_box(&data)));
+        }
+        iters += 64;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<10} {:>6} B  {:>10.1} ns/iter  {:>8.1} MiB/s",
+        name,
+        size,
+        elapsed.as_nanos() as f64 / iters as f64,
+        (size as u64 * iters) as f64 / elapsed.as_secs_f64() / (1 << 20) as f64
+    );
+}
+
+fn main() {
+    for &size in &[64, 1500, 9000, DATAGRAM_BUF_SIZE] {
+        bench("reference", size, checksum_reference);
+        bench("checksum", size, |b| b.checksum());
+    }
+}
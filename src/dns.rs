@@ -0,0 +1,996 @@
+use crate::ext::random_u16;
+use crate::net::invalid_addr;
+use crate::{
+    Buffer, BytesExt, DatagramExt, DialOptions, DualAddr, FamilyPreference, ReadPacketExt,
+    WritePacketExt, HAPPY_EYEBALLS_DELAY,
+};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use async_std::prelude::FutureExt;
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DNS_PORT: u16 = 53;
+
+/// How long [`DnsClient`] waits for each answer before retrying.
+pub const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub const DNS_TYPE_A: u16 = 1;
+pub const DNS_TYPE_AAAA: u16 = 28;
+
+/// Defaults of [`DnsCache`]; answers without a TTL are kept for the minimum.
+pub const DNS_CACHE_MIN_TTL: Duration = Duration::from_secs(5);
+pub const DNS_CACHE_MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const DNS_CACHE_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+pub const DNS_CACHE_CAPACITY: usize = 1024;
+
+const DNS_HEADER_LEN: usize = 12;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_FLAG_QR: u16 = 0x8000;
+const DNS_FLAG_TC: u16 = 0x0200;
+const DNS_FLAG_RD: u16 = 0x0100;
+const DNS_RCODE_MASK: u16 = 0x000f;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+const DNS_MAX_NAME_LEN: usize = 253;
+const DNS_MAX_LABEL_LEN: usize = 63;
+
+/// Addresses found for a name.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Lookup {
+    pub addrs: Vec<IpAddr>,
+    /// How long the answer may be reused, when the resolver knows it.
+    pub ttl: Option<Duration>,
+}
+
+impl Lookup {
+    fn merge(&mut self, other: Lookup) {
+        self.addrs.extend(other.addrs);
+        self.ttl = match (self.ttl, other.ttl) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+fn not_found(host: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no addresses found for {:?}", host),
+    )
+}
+
+fn split_host_port(addr: &str) -> io::Result<(&str, u16)> {
+    let (host, port) = addr.rsplit_once(':').ok_or_else(|| invalid_addr(addr))?;
+    let port = port.parse().map_err(|_| invalid_addr(addr))?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']').ok_or_else(|| invalid_addr(addr))?,
+        None => host,
+    };
+    if host.is_empty() {
+        return Err(invalid_addr(addr));
+    }
+    Ok((host, port))
+}
+
+/// Turns host names into addresses for the dialing helpers.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Resolves `host` to its addresses; a name without any is a `NotFound` error.
+    async fn lookup(&self, host: &str) -> io::Result<Lookup>;
+
+    /// Resolves a `host:port` string into a [`DualAddr`]; IP literals skip the lookup.
+    async fn resolve(&self, addr: &str, preference: FamilyPreference) -> io::Result<DualAddr> {
+        let (host, port) = split_host_port(addr)?;
+        let addrs = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            Err(_) => self.lookup(host).await?.addrs,
+        };
+        let addrs = addrs.into_iter().map(|ip| SocketAddr::new(ip, port));
+        DualAddr::from_addrs(addrs, preference).ok_or_else(|| not_found(host))
+    }
+
+    /// Resolves `addr` and connects with [`DualAddr::happy_eyeballs_with`].
+    async fn dial_tcp(&self, addr: &str, opts: &DialOptions) -> io::Result<TcpStream> {
+        self.resolve(addr, FamilyPreference::Both)
+            .await?
+            .happy_eyeballs_with(opts, HAPPY_EYEBALLS_DELAY)
+            .await
+    }
+
+    /// Resolves `addr` and connects a UDP socket to it, preferring IPv4.
+    async fn dial_udp(&self, addr: &str, opts: &DialOptions) -> io::Result<UdpSocket> {
+        let (v4, v6) = self
+            .resolve(addr, FamilyPreference::PreferV4)
+            .await?
+            .dial_udp_with(opts)
+            .await?;
+        Ok(v4.or(v6).expect("dialed a single family"))
+    }
+}
+
+/// Resolver backed by the operating system, as used by `ToSocketAddrs`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn lookup(&self, host: &str) -> io::Result<Lookup> {
+        let mut addrs = Vec::new();
+        for addr in (host, 0).to_socket_addrs().await? {
+            if !addrs.contains(&addr.ip()) {
+                addrs.push(addr.ip());
+            }
+        }
+        if addrs.is_empty() {
+            return Err(not_found(host));
+        }
+        Ok(Lookup { addrs, ttl: None })
+    }
+}
+
+/// Stub DNS client sending A and AAAA queries to a single upstream server.
+///
+/// Queries go over UDP and are retried over TCP when the answer comes back truncated.
+#[derive(Debug, Clone)]
+pub struct DnsClient {
+    server: SocketAddr,
+    timeout: Duration,
+    attempts: usize,
+    dial: DialOptions,
+}
+
+impl DnsClient {
+    pub fn new(server: SocketAddr) -> Self {
+        DnsClient {
+            server,
+            timeout: DNS_TIMEOUT,
+            attempts: 2,
+            dial: DialOptions::default(),
+        }
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// How long to wait for each answer.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many times a UDP query is sent before giving up.
+    pub fn attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// Options for the sockets used to reach the server, e.g. to route them into a tunnel.
+    pub fn dial_options(mut self, dial: DialOptions) -> Self {
+        self.dial = dial;
+        self
+    }
+
+    /// Sends one query of `qtype` ([`DNS_TYPE_A`] or [`DNS_TYPE_AAAA`]) for `name`.
+    ///
+    /// A name that exists without records of that type gives an empty [`Lookup`].
+    pub async fn query(&self, name: &str, qtype: u16) -> io::Result<Lookup> {
+        let id = random_u16();
+        let query = encode_query(id, name, qtype)?;
+        let mut last_err = None;
+        for _ in 0..self.attempts {
+            let res = match self.exchange_udp(&query, id).timeout(self.timeout).await {
+                Ok(res) => res,
+                Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            };
+            match res.and_then(|resp| parse_response(&resp, id, qtype)) {
+                Ok(Some(lookup)) => return Ok(lookup),
+                Ok(None) => return self.query_tcp(&query, id, qtype).await,
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => last_err = Some(err),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::ErrorKind::TimedOut.into()))
+    }
+
+    async fn exchange_udp(&self, query: &[u8], id: u16) -> io::Result<Buffer> {
+        let sock = self.dial.connect_udp(self.server).await?;
+        sock.send_all(query).await?;
+        let mut buf = Buffer::new();
+        loop {
+            sock.recv_buf(&mut buf).await?;
+            // Late answers to an earlier attempt or stray packets are skipped.
+            if buf.try_u16() == Some(id) {
+                return Ok(buf);
+            }
+        }
+    }
+
+    async fn query_tcp(&self, query: &[u8], id: u16, qtype: u16) -> io::Result<Lookup> {
+        let exchange = async {
+            let mut stream = self.dial.connect_tcp(self.server).await?;
+            stream.write_packet(query).await?;
+            let mut buf = Buffer::new();
+            match stream.read_packet(&mut buf).await? {
+                Some(_) => Ok(buf),
+                None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            }
+        };
+        let resp = match exchange.timeout(self.timeout).await {
+            Ok(resp) => resp?,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+        parse_response(&resp, id, qtype)?.ok_or_else(|| malformed("truncated answer over tcp"))
+    }
+}
+
+#[async_trait]
+impl Resolver for DnsClient {
+    async fn lookup(&self, host: &str) -> io::Result<Lookup> {
+        let (v4, v6) = self
+            .query(host, DNS_TYPE_A)
+            .join(self.query(host, DNS_TYPE_AAAA))
+            .await;
+        let mut lookup = Lookup::default();
+        let mut error = None;
+        for res in [v4, v6] {
+            match res {
+                Ok(found) => lookup.merge(found),
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        if lookup.addrs.is_empty() {
+            return Err(error.unwrap_or_else(|| not_found(host)));
+        }
+        Ok(lookup)
+    }
+}
+
+/// Hit and miss counts of a [`DnsCache`].
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CacheStats {
+    /// Lookups answered without asking the inner resolver, including ones that waited for a
+    /// concurrent lookup of the same name.
+    pub hits: u64,
+    /// Lookups passed on to the inner resolver.
+    pub misses: u64,
+}
+
+enum CacheEntry {
+    /// Completed lookup; only `NotFound` errors are kept, by their message.
+    Ready {
+        result: Result<Lookup, String>,
+        expires: Instant,
+    },
+    /// Lookup in flight; the channel closes when it completes or is abandoned.
+    Pending { done: Receiver<()>, id: u64 },
+}
+
+/// Caches answers of another [`Resolver`].
+///
+/// Answers are kept for their TTL clamped to `[min_ttl, max_ttl]` and `NotFound` errors for
+/// `negative_ttl`. Concurrent lookups of a name share one query to the inner resolver.
+pub struct DnsCache<R> {
+    inner: R,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    next_id: AtomicU64,
+}
+
+/// Removes a pending entry whose lookup was dropped before completing.
+struct PendingGuard<'a, R> {
+    cache: &'a DnsCache<R>,
+    name: &'a str,
+    id: u64,
+    _done: Sender<()>,
+}
+
+impl<R> Drop for PendingGuard<'_, R> {
+    fn drop(&mut self) {
+        let mut entries = self.cache.entries.lock().unwrap();
+        remove_pending(&mut entries, self.name, self.id);
+    }
+}
+
+enum CacheState {
+    Hit(io::Result<Lookup>),
+    Wait(Receiver<()>),
+    Miss(Sender<()>, u64),
+}
+
+/// Removes the entry of `name` if it is still the pending lookup `id`; once that lookup's
+/// entry was removed, another lookup may have taken its place.
+fn remove_pending(entries: &mut HashMap<String, CacheEntry>, name: &str, id: u64) {
+    if let Some(CacheEntry::Pending { id: pending, .. }) = entries.get(name) {
+        if *pending == id {
+            entries.remove(name);
+        }
+    }
+}
+
+impl<R: Resolver> DnsCache<R> {
+    pub fn new(inner: R) -> Self {
+        DnsCache {
+            inner,
+            min_ttl: DNS_CACHE_MIN_TTL,
+            max_ttl: DNS_CACHE_MAX_TTL,
+            negative_ttl: DNS_CACHE_NEGATIVE_TTL,
+            capacity: DNS_CACHE_CAPACITY,
+            entries: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn min_ttl(mut self, min_ttl: Duration) -> Self {
+        self.min_ttl = min_ttl;
+        self
+    }
+
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// How long a name that does not exist is remembered; zero disables negative caching.
+    pub fn negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// Number of names kept before expired and then soonest-expiring entries are evicted.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear()
+    }
+
+    fn state(&self, name: &str) -> CacheState {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        match entries.get(name) {
+            Some(CacheEntry::Ready { result, expires }) if *expires > now => {
+                return CacheState::Hit(match result {
+                    Ok(lookup) => Ok(Lookup {
+                        addrs: lookup.addrs.clone(),
+                        ttl: Some(*expires - now),
+                    }),
+                    Err(msg) => Err(io::Error::new(io::ErrorKind::NotFound, msg.clone())),
+                });
+            }
+            Some(CacheEntry::Pending { done, .. }) => return CacheState::Wait(done.clone()),
+            _ => {}
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(name) {
+            entries.retain(|_, entry| match entry {
+                CacheEntry::Ready { expires, .. } => *expires > now,
+                CacheEntry::Pending { .. } => true,
+            });
+            if entries.len() >= self.capacity {
+                let soonest = entries
+                    .iter()
+                    .filter_map(|(name, entry)| match entry {
+                        CacheEntry::Ready { expires, .. } => Some((*expires, name.clone())),
+                        CacheEntry::Pending { .. } => None,
+                    })
+                    .min();
+                if let Some((_, soonest)) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+        let (tx, rx) = channel::bounded(1);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        entries.insert(name.to_string(), CacheEntry::Pending { done: rx, id });
+        CacheState::Miss(tx, id)
+    }
+
+    /// Caches `result` of the pending lookup `id` and returns it with the TTL it is cached for.
+    fn store(&self, name: &str, id: u64, result: io::Result<Lookup>) -> io::Result<Lookup> {
+        let (entry, ttl) = match &result {
+            Ok(lookup) => {
+                let ttl = lookup.ttl.unwrap_or(self.min_ttl);
+                (Ok(lookup.clone()), ttl.max(self.min_ttl).min(self.max_ttl))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                (Err(err.to_string()), self.negative_ttl)
+            }
+            // Transient failures such as timeouts are not cached.
+            Err(_) => (Err(String::new()), Duration::from_secs(0)),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if ttl.is_zero() {
+            remove_pending(&mut entries, name, id);
+        } else {
+            let expires = Instant::now() + ttl;
+            entries.insert(
+                name.to_string(),
+                CacheEntry::Ready {
+                    result: entry,
+                    expires,
+                },
+            );
+        }
+        result.map(|lookup| Lookup {
+            ttl: Some(ttl),
+            ..lookup
+        })
+    }
+}
+
+#[async_trait]
+impl<R: Resolver> Resolver for DnsCache<R> {
+    async fn lookup(&self, host: &str) -> io::Result<Lookup> {
+        let name = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+        loop {
+            match self.state(&name) {
+                CacheState::Hit(result) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return result;
+                }
+                CacheState::Wait(done) => {
+                    // Once the lookup in flight is over its answer is cached, unless it failed
+                    // with an error that is not cached; then the next round asks again.
+                    let _ = done.recv().await;
+                }
+                CacheState::Miss(done, id) => {
+                    let guard = PendingGuard {
+                        cache: self,
+                        name: &name,
+                        id,
+                        _done: done,
+                    };
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    let result = self.store(&name, id, self.inner.lookup(host).await);
+                    drop(guard);
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+fn invalid_name(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid dns name {:?}", name),
+    )
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed dns response: {}", what),
+    )
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<BytesMut> {
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    if trimmed.is_empty() || trimmed.len() > DNS_MAX_NAME_LEN {
+        return Err(invalid_name(name));
+    }
+    let mut buf = BytesMut::with_capacity(DNS_HEADER_LEN + trimmed.len() + 6);
+    buf.put_u16(id);
+    buf.put_u16(DNS_FLAG_RD);
+    buf.put_u16(1);
+    buf.put_slice(&[0; 6]);
+    for label in trimmed.split('.') {
+        if label.is_empty() || label.len() > DNS_MAX_LABEL_LEN {
+            return Err(invalid_name(name));
+        }
+        buf.put_u8(label.len() as u8);
+        buf.put_slice(label.as_bytes());
+    }
+    buf.put_u8(0);
+    buf.put_u16(qtype);
+    buf.put_u16(DNS_CLASS_IN);
+    Ok(buf)
+}
+
+/// Returns the offset just past the (possibly compressed) name at `offset`.
+fn skip_name(msg: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => return Some(offset + 1),
+            0x00 => offset += 1 + len,
+            0xc0 => return msg.get(offset + 1).map(|_| offset + 2),
+            _ => return None,
+        }
+    }
+}
+
+/// Parses an answer to the query `id`; `None` means it was truncated.
+fn parse_response(msg: &[u8], id: u16, qtype: u16) -> io::Result<Option<Lookup>> {
+    let be16 = |offset| msg.read_be::<u16>(offset).ok_or_else(|| malformed("short"));
+    if msg.len() < DNS_HEADER_LEN || be16(0)? != id {
+        return Err(malformed("bad header"));
+    }
+    let flags = be16(2)?;
+    if flags & DNS_FLAG_QR == 0 {
+        return Err(malformed("not a response"));
+    }
+    if flags & DNS_FLAG_TC != 0 {
+        return Ok(None);
+    }
+    match flags & DNS_RCODE_MASK {
+        0 => {}
+        DNS_RCODE_NXDOMAIN => {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such domain"));
+        }
+        rcode => {
+            return Err(io::Error::other(format!(
+                "dns server returned rcode {}",
+                rcode
+            )));
+        }
+    }
+    let mut offset = DNS_HEADER_LEN;
+    for _ in 0..be16(4)? {
+        offset = skip_name(msg, offset).ok_or_else(|| malformed("question"))? + 4;
+    }
+    let mut lookup = Lookup::default();
+    for _ in 0..be16(6)? {
+        offset = skip_name(msg, offset).ok_or_else(|| malformed("answer name"))?;
+        let (rtype, class) = (be16(offset)?, be16(offset + 2)?);
+        let ttl = msg
+            .read_be::<u32>(offset + 4)
+            .ok_or_else(|| malformed("short"))?;
+        let rdata_len = be16(offset + 8)? as usize;
+        let rdata = msg
+            .get(offset + 10..offset + 10 + rdata_len)
+            .ok_or_else(|| malformed("short"))?;
+        offset += 10 + rdata_len;
+        if class != DNS_CLASS_IN || rtype != qtype {
+            continue;
+        }
+        let ip = match rtype {
+            DNS_TYPE_A => <[u8; 4]>::try_from(rdata).map(|b| IpAddr::from(Ipv4Addr::from(b))),
+            DNS_TYPE_AAAA => <[u8; 16]>::try_from(rdata).map(|b| IpAddr::from(Ipv6Addr::from(b))),
+            _ => continue,
+        };
+        lookup
+            .addrs
+            .push(ip.map_err(|_| malformed("address length"))?);
+        lookup.merge(Lookup {
+            addrs: vec![],
+            ttl: Some(Duration::from_secs(ttl as u64)),
+        });
+    }
+    Ok(Some(lookup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Answers `example.test` (A and AAAA), `v4.test` (A only) and `local.test` (127.0.0.1);
+    /// every other name is NXDOMAIN.
+    fn answer(query: &[u8], truncate: bool) -> Vec<u8> {
+        let end = skip_name(query, DNS_HEADER_LEN).unwrap();
+        let qtype = query.read_be::<u16>(end).unwrap();
+        let mut name = Vec::new();
+        let mut offset = DNS_HEADER_LEN;
+        while query[offset] != 0 {
+            let len = query[offset] as usize;
+            name.push(String::from_utf8_lossy(&query[offset + 1..offset + 1 + len]).to_string());
+            offset += 1 + len;
+        }
+        let records: Vec<(IpAddr, u32)> = match name.join(".").as_str() {
+            "example.test" => vec![
+                ("192.0.2.1".parse().unwrap(), 300),
+                ("192.0.2.2".parse().unwrap(), 200),
+                ("2001:db8::1".parse().unwrap(), 60),
+            ],
+            "v4.test" => vec![("192.0.2.3".parse().unwrap(), 300)],
+            "local.test" => vec![("127.0.0.1".parse().unwrap(), 300)],
+            _ => return [&query[..2], &[0x81, 0x83], &query[4..]].concat(),
+        };
+        let records: Vec<_> = records
+            .into_iter()
+            .filter(|(ip, _)| ip.is_ipv4() == (qtype == DNS_TYPE_A))
+            .collect();
+        let mut resp = BytesMut::new();
+        resp.put_slice(&query[..2]);
+        resp.put_u16(if truncate { 0x8380 } else { 0x8180 });
+        resp.put_u16(1);
+        resp.put_u16(if truncate { 0 } else { records.len() as u16 });
+        resp.put_slice(&[0; 4]);
+        resp.put_slice(&query[DNS_HEADER_LEN..end + 4]);
+        if truncate {
+            return resp.to_vec();
+        }
+        for (ip, ttl) in records {
+            resp.put_u16(0xc000 | DNS_HEADER_LEN as u16);
+            resp.put_u16(qtype);
+            resp.put_u16(DNS_CLASS_IN);
+            resp.put_u32(ttl);
+            match ip {
+                IpAddr::V4(ip) => {
+                    resp.put_u16(4);
+                    resp.put_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    resp.put_u16(16);
+                    resp.put_slice(&ip.octets());
+                }
+            }
+        }
+        resp.to_vec()
+    }
+
+    /// Fake upstream on UDP and TCP of the same port; returns the address and TCP query count.
+    async fn fake_dns(truncate_udp: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let (udp, tcp) = loop {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            if let Ok(tcp) = TcpListener::bind(udp.local_addr().unwrap()).await {
+                break (udp, tcp);
+            }
+        };
+        let addr = udp.local_addr().unwrap();
+        task::spawn(async move {
+            let mut buf = Buffer::new();
+            loop {
+                let peer = udp.recv_buf_from(&mut buf).await.unwrap();
+                let resp = answer(&buf, truncate_udp);
+                udp.send_all_to(&resp, peer).await.unwrap();
+            }
+        });
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+        let counter = tcp_queries.clone();
+        task::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut buf = Buffer::new();
+                while stream.read_packet(&mut buf).await.unwrap().is_some() {
+                    stream.write_packet(&answer(&buf, false)).await.unwrap();
+                }
+            }
+        });
+        (addr, tcp_queries)
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "example.com.", DNS_TYPE_AAAA).unwrap();
+        assert_eq!(
+            hex::encode(&query),
+            "123401000001000000000000076578616d706c6503636f6d00001c0001"
+        );
+        let long = "a".repeat(64);
+        for name in &["", ".", "a..b", long.as_str()] {
+            assert_eq!(
+                encode_query(0, name, DNS_TYPE_A).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_response_errors() {
+        let query = encode_query(7, "x.test", DNS_TYPE_A).unwrap();
+        assert_eq!(
+            parse_response(&query, 7, DNS_TYPE_A).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let resp = answer(&query, false);
+        assert_eq!(
+            parse_response(&resp, 7, DNS_TYPE_A).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        let query = encode_query(7, "v4.test", DNS_TYPE_A).unwrap();
+        let resp = answer(&query, false);
+        for len in DNS_HEADER_LEN..resp.len() {
+            assert!(parse_response(&resp[..len], 7, DNS_TYPE_A).is_err());
+        }
+        assert_eq!(
+            parse_response(&resp, 8, DNS_TYPE_A).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_dns_client() {
+        task::block_on(async {
+            let (server, tcp_queries) = fake_dns(false).await;
+            let client = DnsClient::new(server);
+            let lookup = client.lookup("example.test").await.unwrap();
+            assert_eq!(
+                lookup.addrs,
+                vec![
+                    "192.0.2.1".parse::<IpAddr>().unwrap(),
+                    "192.0.2.2".parse().unwrap(),
+                    "2001:db8::1".parse().unwrap(),
+                ]
+            );
+            assert_eq!(lookup.ttl, Some(Duration::from_secs(60)));
+            let lookup = client.query("v4.test.", DNS_TYPE_AAAA).await.unwrap();
+            assert_eq!(lookup, Lookup::default());
+            assert_eq!(client.lookup("v4.test").await.unwrap().addrs.len(), 1);
+            assert_eq!(
+                client.lookup("missing.test").await.unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+            assert_eq!(tcp_queries.load(Ordering::Relaxed), 0);
+
+            assert_eq!(
+                client
+                    .resolve("example.test:443", FamilyPreference::Both)
+                    .await
+                    .unwrap()
+                    .to_string(),
+                "192.0.2.1:443,[2001:db8::1]:443"
+            );
+            assert_eq!(
+                client
+                    .resolve("[::1]:53", FamilyPreference::Both)
+                    .await
+                    .unwrap()
+                    .to_string(),
+                "[::1]:53"
+            );
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let stream = client
+                .dial_tcp(&format!("local.test:{}", port), &DialOptions::new())
+                .await
+                .unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_dns_client_tcp_fallback() {
+        task::block_on(async {
+            let (server, tcp_queries) = fake_dns(true).await;
+            let lookup = DnsClient::new(server).lookup("example.test").await.unwrap();
+            assert_eq!(lookup.addrs.len(), 3);
+            assert_eq!(tcp_queries.load(Ordering::Relaxed), 2);
+        });
+    }
+
+    #[test]
+    fn test_dns_client_timeout() {
+        task::block_on(async {
+            let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let client = DnsClient::new(silent.local_addr().unwrap())
+                .timeout(Duration::from_millis(50))
+                .attempts(2);
+            assert_eq!(
+                client.lookup("example.test").await.unwrap_err().kind(),
+                io::ErrorKind::TimedOut
+            );
+        });
+    }
+
+    /// Counts lookups; `slow.test` takes 100ms, `missing.test` does not exist and `broken.test`
+    /// times out.
+    #[derive(Default)]
+    struct CountingResolver {
+        lookups: AtomicUsize,
+        ttl: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl Resolver for CountingResolver {
+        async fn lookup(&self, host: &str) -> io::Result<Lookup> {
+            let n = self.lookups.fetch_add(1, Ordering::SeqCst);
+            match host {
+                "slow.test" => task::sleep(Duration::from_millis(100)).await,
+                "missing.test" => return Err(not_found(host)),
+                "broken.test" => return Err(io::ErrorKind::TimedOut.into()),
+                _ => {}
+            }
+            Ok(Lookup {
+                addrs: vec![IpAddr::from([10, 0, 0, n as u8])],
+                ttl: self.ttl,
+            })
+        }
+    }
+
+    #[test]
+    fn test_dns_cache() {
+        task::block_on(async {
+            let cache = DnsCache::new(CountingResolver {
+                ttl: Some(Duration::from_secs(600)),
+                ..Default::default()
+            })
+            .max_ttl(Duration::from_secs(60));
+            let first = cache.lookup("a.test").await.unwrap();
+            assert_eq!(first.ttl, Some(Duration::from_secs(60)));
+            let again = cache.lookup("A.test.").await.unwrap();
+            assert_eq!(again.addrs, first.addrs);
+            assert!(again.ttl.unwrap() <= Duration::from_secs(60));
+            assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+
+            for _ in 0..2 {
+                assert_eq!(
+                    cache.lookup("missing.test").await.unwrap_err().kind(),
+                    io::ErrorKind::NotFound
+                );
+                assert_eq!(
+                    cache.lookup("broken.test").await.unwrap_err().kind(),
+                    io::ErrorKind::TimedOut
+                );
+            }
+            assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 4 });
+            assert_eq!(cache.len(), 2);
+            cache.clear();
+            assert!(cache.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_dns_cache_expiry() {
+        task::block_on(async {
+            let cache = DnsCache::new(CountingResolver {
+                ttl: Some(Duration::from_secs(0)),
+                ..Default::default()
+            })
+            .min_ttl(Duration::from_millis(100))
+            .negative_ttl(Duration::from_millis(100))
+            .capacity(2);
+            let first = cache.lookup("a.test").await.unwrap();
+            assert_eq!(first.ttl, Some(Duration::from_millis(100)));
+            assert_eq!(cache.lookup("a.test").await.unwrap().addrs, first.addrs);
+            cache.lookup("missing.test").await.unwrap_err();
+            task::sleep(Duration::from_millis(150)).await;
+            assert_ne!(cache.lookup("a.test").await.unwrap().addrs, first.addrs);
+            cache.lookup("missing.test").await.unwrap_err();
+            assert_eq!(cache.get_ref().lookups.load(Ordering::SeqCst), 4);
+
+            // A full cache evicts the entry closest to expiry.
+            cache.lookup("b.test").await.unwrap();
+            assert_eq!(cache.len(), 2);
+            assert_eq!(cache.get_ref().lookups.load(Ordering::SeqCst), 5);
+        });
+    }
+
+    #[test]
+    fn test_dns_cache_coalescing() {
+        task::block_on(async {
+            let cache = Arc::new(DnsCache::new(CountingResolver::default()));
+            let lookups: Vec<_> = (0..10)
+                .map(|_| {
+                    let cache = cache.clone();
+                    task::spawn(async move { cache.lookup("slow.test").await.unwrap() })
+                })
+                .collect();
+            for lookup in lookups {
+                assert_eq!(lookup.await.addrs, vec![IpAddr::from([10, 0, 0, 0])]);
+            }
+            assert_eq!(cache.get_ref().lookups.load(Ordering::SeqCst), 1);
+            assert_eq!(cache.stats(), CacheStats { hits: 9, misses: 1 });
+
+            // An abandoned lookup does not leave waiters stuck.
+            let slow = cache.clone();
+            slow.clear();
+            let abandoned = task::spawn(async move { slow.lookup("slow.test").await });
+            task::sleep(Duration::from_millis(20)).await;
+            abandoned.cancel().await;
+            assert!(cache.is_empty());
+            assert!(cache.lookup("slow.test").await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_dns_cache_pending_guard() {
+        let cache = DnsCache::new(CountingResolver::default());
+        let miss = |cache: &DnsCache<_>| match cache.state("a.test") {
+            CacheState::Miss(done, id) => (done, id),
+            _ => panic!("expected a miss"),
+        };
+        let (done, id) = miss(&cache);
+        let guard = PendingGuard {
+            cache: &cache,
+            name: "a.test",
+            id,
+            _done: done,
+        };
+        // An uncached failure clears the entry and another lookup starts before the guard drops.
+        cache
+            .store("a.test", id, Err(io::ErrorKind::TimedOut.into()))
+            .unwrap_err();
+        let (_done, other) = miss(&cache);
+        drop(guard);
+        assert!(matches!(cache.state("a.test"), CacheState::Wait(_)));
+
+        // Nor does a result of a cleared lookup remove the one that replaced it.
+        cache.clear();
+        let (_, replaced) = miss(&cache);
+        cache
+            .store("a.test", other, Err(io::ErrorKind::TimedOut.into()))
+            .unwrap_err();
+        assert_ne!(replaced, other);
+        assert!(matches!(cache.state("a.test"), CacheState::Wait(_)));
+    }
+
+    #[test]
+    fn test_dns_cache_dial() {
+        task::block_on(async {
+            let (server, _) = fake_dns(false).await;
+            let cache = Arc::new(DnsCache::new(DnsClient::new(server)));
+            let opts = DialOptions::new().resolver(cache.clone());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = format!("local.test:{}", listener.local_addr().unwrap().port());
+            // Repeated dials of a name share one upstream lookup.
+            for _ in 0..2 {
+                let stream = DualAddr::resolve_with(&addr, FamilyPreference::Both, &opts)
+                    .await
+                    .unwrap()
+                    .happy_eyeballs_with(&opts, HAPPY_EYEBALLS_DELAY)
+                    .await
+                    .unwrap();
+                assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+            }
+            assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+        });
+    }
+
+    #[test]
+    fn test_system_resolver() {
+        task::block_on(async {
+            let lookup = SystemResolver.lookup("localhost").await.unwrap();
+            assert!(lookup.addrs.iter().all(IpAddr::is_loopback));
+            assert_eq!(
+                SystemResolver
+                    .resolve("127.0.0.1:80", FamilyPreference::Both)
+                    .await
+                    .unwrap()
+                    .to_string(),
+                "127.0.0.1:80"
+            );
+            assert_eq!(
+                SystemResolver
+                    .resolve("localhost", FamilyPreference::Both)
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::InvalidInput
+            );
+        });
+    }
+}
@@ -1,12 +1,199 @@
-use async_trait::async_trait;
-use std::future::Future;
+use bytes::BufMut;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const VARINT_MAX_LEN: usize = 10;
+
+/// Fixed-size integers that can be decoded from a byte slice of exactly `SIZE` bytes.
+pub trait FromBytes: Sized {
+    const SIZE: usize;
+    fn from_be_slice(b: &[u8]) -> Self;
+    fn from_le_slice(b: &[u8]) -> Self;
+}
+
+/// Fixed-size integers that can be encoded into a byte slice of exactly `FromBytes::SIZE` bytes.
+pub trait ToBytes: FromBytes {
+    fn to_be_slice(self, b: &mut [u8]);
+    fn to_le_slice(self, b: &mut [u8]);
+}
+
+macro_rules! impl_from_bytes {
+    ($($t:ty),*) => {
+        $(
+            impl FromBytes for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn from_be_slice(b: &[u8]) -> Self {
+                    let mut a = [0u8; std::mem::size_of::<$t>()];
+                    a.copy_from_slice(b);
+                    <$t>::from_be_bytes(a)
+                }
+
+                fn from_le_slice(b: &[u8]) -> Self {
+                    let mut a = [0u8; std::mem::size_of::<$t>()];
+                    a.copy_from_slice(b);
+                    <$t>::from_le_bytes(a)
+                }
+            }
+
+            impl ToBytes for $t {
+                fn to_be_slice(self, b: &mut [u8]) {
+                    b.copy_from_slice(&self.to_be_bytes())
+                }
+
+                fn to_le_slice(self, b: &mut [u8]) {
+                    b.copy_from_slice(&self.to_le_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_bytes!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
 
 pub trait BytesExt {
+    /// Big-endian value of the first bytes, zero-extended when the slice is short.
     fn u16(&self) -> u16;
     fn u32(&self) -> u32;
     fn u64(&self) -> u64;
     fn usize(&self) -> usize;
+    /// Big-endian value of the first bytes, or `None` when the slice is short.
+    fn try_u16(&self) -> Option<u16>;
+    fn try_u32(&self) -> Option<u32>;
+    fn try_u64(&self) -> Option<u64>;
+    fn try_usize(&self) -> Option<usize>;
+    /// Reads a big-endian integer at `offset`, or `None` when the slice is short.
+    fn read_be<T: FromBytes>(&self, offset: usize) -> Option<T>;
+    /// Reads a little-endian integer at `offset`, or `None` when the slice is short.
+    fn read_le<T: FromBytes>(&self, offset: usize) -> Option<T>;
+    /// Reads an unsigned LEB128 varint at `offset`, returning the value and its encoded length.
+    fn read_varint(&self, offset: usize) -> Option<(u64, usize)>;
     fn checksum(&self) -> u16;
+    /// Transport checksum over an IPv4 pseudo-header and `self`, with the checksum field zeroed.
+    fn checksum_ipv4(&self, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8) -> u16;
+    /// Transport checksum over an IPv6 pseudo-header and `self`, with the checksum field zeroed.
+    fn checksum_ipv6(&self, src: Ipv6Addr, dst: Ipv6Addr, next_header: u8) -> u16;
+    fn verify_checksum_ipv4(&self, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8) -> bool;
+    fn verify_checksum_ipv6(&self, src: Ipv6Addr, dst: Ipv6Addr, next_header: u8) -> bool;
+}
+
+fn add_carry(a: u64, b: u64) -> u64 {
+    let (sum, carry) = a.overflowing_add(b);
+    sum + carry as u64
+}
+
+// Ones-complement sums are byte-order independent (RFC 1071), so the wide routines add
+// native-endian words and the result is swapped back once at the end.
+fn sum_native_scalar(b: &[u8]) -> u64 {
+    let word = |c: &[u8]| {
+        let mut w = [0u8; 8];
+        w.copy_from_slice(c);
+        u64::from_ne_bytes(w)
+    };
+    let mut acc = [0u64; 4];
+    let mut chunks = b.chunks_exact(32);
+    for c in &mut chunks {
+        acc[0] = add_carry(acc[0], word(&c[0..8]));
+        acc[1] = add_carry(acc[1], word(&c[8..16]));
+        acc[2] = add_carry(acc[2], word(&c[16..24]));
+        acc[3] = add_carry(acc[3], word(&c[24..32]));
+    }
+    let mut sum = acc.iter().fold(0, |a, b| add_carry(a, *b));
+    let mut words = chunks.remainder().chunks_exact(8);
+    for c in &mut words {
+        sum = add_carry(sum, word(c));
+    }
+    let mut last = [0u8; 8];
+    last[..words.remainder().len()].copy_from_slice(words.remainder());
+    add_carry(sum, u64::from_ne_bytes(last))
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn sum_native_avx2(b: &[u8]) -> u64 {
+    use std::arch::x86_64::*;
+    // Each 32-byte block adds at most 2 * 0xffff to a 32-bit lane.
+    const FLUSH_BLOCKS: usize = 1 << 15;
+    let zero = _mm256_setzero_si256();
+    let mut sum = 0u64;
+    let mut chunks = b.chunks_exact(32);
+    loop {
+        let mut acc = zero;
+        let mut n = 0;
+        for c in chunks.by_ref().take(FLUSH_BLOCKS) {
+            let v = _mm256_loadu_si256(c.as_ptr() as *const __m256i);
+            acc = _mm256_add_epi32(acc, _mm256_unpacklo_epi16(v, zero));
+            acc = _mm256_add_epi32(acc, _mm256_unpackhi_epi16(v, zero));
+            n += 1;
+        }
+        let mut lanes = [0u32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+        sum = lanes.iter().fold(sum, |a, b| add_carry(a, *b as u64));
+        if n < FLUSH_BLOCKS {
+            break;
+        }
+    }
+    add_carry(sum, sum_native_scalar(chunks.remainder()))
+}
+
+fn sum_native(b: &[u8]) -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if b.len() >= 256 && is_x86_feature_detected!("avx2") {
+            return unsafe { sum_native_avx2(b) };
+        }
+    }
+    sum_native_scalar(b)
+}
+
+fn fold64(sum: u64) -> u16 {
+    let mut sum = (sum >> 32) + (sum & 0xffff_ffff);
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff)
+    }
+    u16::from_be(sum as u16)
+}
+
+/// The two-bytes-at-a-time routine `BytesExt::checksum` used before the wide implementation,
+/// for tests and the benchmark. Panics on an empty slice, as it always did.
+#[cfg(any(test, feature = "bench"))]
+#[doc(hidden)]
+#[allow(clippy::manual_is_multiple_of)]
+pub fn checksum_reference(b: &[u8]) -> u16 {
+    let length = b.len() - 1;
+    let mut csum = 0u32;
+    for i in (0..length).step_by(2) {
+        csum += ((b[i] as u32) << 8) + (b[i + 1] as u32)
+    }
+    if length % 2 == 0 {
+        csum += (b[length] as u32) << 8
+    }
+    while csum > 0xffff {
+        csum = (csum >> 16) + (csum & 0xffff)
+    }
+    !csum as u16
+}
+
+fn sum16(csum: u32, b: &[u8]) -> u32 {
+    csum + fold64(sum_native(b)) as u32
+}
+
+fn fold16(mut csum: u32) -> u16 {
+    while csum > 0xffff {
+        csum = (csum >> 16) + (csum & 0xffff)
+    }
+    !csum as u16
+}
+
+fn pseudo_ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let csum = sum16(sum16(0, &src.octets()), &dst.octets());
+    csum + protocol as u32 + (len as u32 >> 16) + (len as u32 & 0xffff)
+}
+
+fn pseudo_ipv6(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, len: usize) -> u32 {
+    let csum = sum16(sum16(0, &src.octets()), &dst.octets());
+    csum + next_header as u32 + (len as u32 >> 16) + (len as u32 & 0xffff)
 }
 
 impl BytesExt for [u8] {
@@ -34,22 +221,201 @@ impl BytesExt for [u8] {
             .fold(0usize, |a, b| (a << 8) + (*b as usize))
     }
 
-    fn checksum(&self) -> u16 {
-        let length = self.len() - 1;
-        let mut csum = 0u32;
-        for i in (0..length).step_by(2) {
-            csum += ((self[i] as u32) << 8) + (self[i + 1] as u32)
+    fn try_u16(&self) -> Option<u16> {
+        self.read_be(0)
+    }
+
+    fn try_u32(&self) -> Option<u32> {
+        self.read_be(0)
+    }
+
+    fn try_u64(&self) -> Option<u64> {
+        self.read_be(0)
+    }
+
+    fn try_usize(&self) -> Option<usize> {
+        self.read_be(0)
+    }
+
+    fn read_be<T: FromBytes>(&self, offset: usize) -> Option<T> {
+        let end = offset.checked_add(T::SIZE)?;
+        self.get(offset..end).map(T::from_be_slice)
+    }
+
+    fn read_le<T: FromBytes>(&self, offset: usize) -> Option<T> {
+        let end = offset.checked_add(T::SIZE)?;
+        self.get(offset..end).map(T::from_le_slice)
+    }
+
+    fn read_varint(&self, offset: usize) -> Option<(u64, usize)> {
+        let mut value = 0u64;
+        for (i, b) in self.get(offset..)?.iter().take(VARINT_MAX_LEN).enumerate() {
+            let bits = (*b & 0x7f) as u64;
+            if i == VARINT_MAX_LEN - 1 && bits > 1 {
+                return None;
+            }
+            value |= bits << (7 * i);
+            if b & 0x80 == 0 {
+                return Some((value, i + 1));
+            }
         }
-        if length % 2 == 0 {
-            csum += (self[length] as u32) << 8
+        None
+    }
+
+    fn checksum(&self) -> u16 {
+        fold16(sum16(0, self))
+    }
+
+    fn checksum_ipv4(&self, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8) -> u16 {
+        fold16(sum16(pseudo_ipv4(src, dst, protocol, self.len()), self))
+    }
+
+    fn checksum_ipv6(&self, src: Ipv6Addr, dst: Ipv6Addr, next_header: u8) -> u16 {
+        fold16(sum16(pseudo_ipv6(src, dst, next_header, self.len()), self))
+    }
+
+    fn verify_checksum_ipv4(&self, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8) -> bool {
+        self.checksum_ipv4(src, dst, protocol) == 0
+    }
+
+    fn verify_checksum_ipv6(&self, src: Ipv6Addr, dst: Ipv6Addr, next_header: u8) -> bool {
+        self.checksum_ipv6(src, dst, next_header) == 0
+    }
+}
+
+pub trait BytesMutExt {
+    /// Writes a big-endian integer at `offset`, or returns `None` when the slice is short.
+    fn write_be<T: ToBytes>(&mut self, offset: usize, value: T) -> Option<()>;
+    /// Writes a little-endian integer at `offset`, or returns `None` when the slice is short.
+    fn write_le<T: ToBytes>(&mut self, offset: usize, value: T) -> Option<()>;
+    /// Computes the checksum of `self` and stores it in the 16-bit field at `offset`.
+    fn fill_checksum(&mut self, offset: usize) -> Option<()>;
+    /// Like [`fill_checksum`](BytesMutExt::fill_checksum) with an IPv4 pseudo-header.
+    fn fill_checksum_ipv4(
+        &mut self,
+        offset: usize,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        protocol: u8,
+    ) -> Option<()>;
+    /// Like [`fill_checksum`](BytesMutExt::fill_checksum) with an IPv6 pseudo-header.
+    fn fill_checksum_ipv6(
+        &mut self,
+        offset: usize,
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+        next_header: u8,
+    ) -> Option<()>;
+}
+
+impl BytesMutExt for [u8] {
+    fn write_be<T: ToBytes>(&mut self, offset: usize, value: T) -> Option<()> {
+        let end = offset.checked_add(T::SIZE)?;
+        self.get_mut(offset..end).map(|b| value.to_be_slice(b))
+    }
+
+    fn write_le<T: ToBytes>(&mut self, offset: usize, value: T) -> Option<()> {
+        let end = offset.checked_add(T::SIZE)?;
+        self.get_mut(offset..end).map(|b| value.to_le_slice(b))
+    }
+
+    fn fill_checksum(&mut self, offset: usize) -> Option<()> {
+        self.write_be(offset, 0u16)?;
+        let checksum = self.checksum();
+        self.write_be(offset, checksum)
+    }
+
+    fn fill_checksum_ipv4(
+        &mut self,
+        offset: usize,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        protocol: u8,
+    ) -> Option<()> {
+        self.write_be(offset, 0u16)?;
+        let checksum = self.checksum_ipv4(src, dst, protocol);
+        self.write_be(offset, checksum)
+    }
+
+    fn fill_checksum_ipv6(
+        &mut self,
+        offset: usize,
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+        next_header: u8,
+    ) -> Option<()> {
+        self.write_be(offset, 0u16)?;
+        let checksum = self.checksum_ipv6(src, dst, next_header);
+        self.write_be(offset, checksum)
+    }
+}
+
+/// Appending writers for growable buffers such as `BytesMut`.
+pub trait BufMutExt: BufMut {
+    /// Appends `value` as an unsigned LEB128 varint.
+    fn put_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.put_u8(value as u8 | 0x80);
+            value >>= 7;
         }
-        while csum > 0xffff {
-            csum = (csum >> 16) + (csum & 0xffff)
+        self.put_u8(value as u8)
+    }
+}
+
+impl<T: BufMut + ?Sized> BufMutExt for T {}
+
+/// Incremental checksum updates (RFC 1624) for rewriting fields without summing the whole packet.
+///
+/// Implemented for the ones-complement checksum as read from the packet. Rewritten fields must
+/// start at an even offset within the checksummed data.
+pub trait ChecksumExt {
+    fn update_u16(self, old: u16, new: u16) -> u16;
+    fn update_u32(self, old: u32, new: u32) -> u16;
+    /// Returns `None` if `old` and `new` differ in length.
+    fn update_bytes(self, old: &[u8], new: &[u8]) -> Option<u16>;
+    fn update_ipv4(self, old: Ipv4Addr, new: Ipv4Addr) -> u16;
+    fn update_ipv6(self, old: Ipv6Addr, new: Ipv6Addr) -> u16;
+}
+
+impl ChecksumExt for u16 {
+    fn update_u16(self, old: u16, new: u16) -> u16 {
+        // HC' = ~(~HC + ~m + m')
+        fold16(!self as u32 + !old as u32 + new as u32)
+    }
+
+    fn update_u32(self, old: u32, new: u32) -> u16 {
+        self.update_u16((old >> 16) as u16, (new >> 16) as u16)
+            .update_u16(old as u16, new as u16)
+    }
+
+    fn update_bytes(self, old: &[u8], new: &[u8]) -> Option<u16> {
+        if old.len() != new.len() {
+            return None;
         }
-        !csum as u16
+        Some(update_chunks(self, old, new))
+    }
+
+    fn update_ipv4(self, old: Ipv4Addr, new: Ipv4Addr) -> u16 {
+        self.update_u32(u32::from(old), u32::from(new))
+    }
+
+    fn update_ipv6(self, old: Ipv6Addr, new: Ipv6Addr) -> u16 {
+        update_chunks(self, &old.octets(), &new.octets())
     }
 }
 
+/// Updates `csum` for fields of equal length.
+fn update_chunks(csum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut csum = !csum as u32;
+    for (o, n) in old.chunks(2).zip(new.chunks(2)) {
+        csum += !(o.u16() << (8 * (2 - o.len()))) as u32;
+        csum += (n.u16() << (8 * (2 - n.len()))) as u32;
+        // Keep the accumulator from overflowing on long rewrites.
+        csum = (csum >> 16) + (csum & 0xffff);
+    }
+    fold16(csum)
+}
+
 pub trait AnyExt: Sized {
     fn type_name(&self) -> &'static str;
 
@@ -76,9 +442,16 @@ pub fn type_of2<T>(v: T) -> (&'static str, T) {
     (std::any::type_name::<T>(), v)
 }
 
+/// A random 16-bit value, e.g. for query ids and ICMP identifiers.
+pub(crate) fn random_u16() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::DATAGRAM_BUF_SIZE;
+    use bytes::BytesMut;
 
     #[test]
     fn test_u16() {
@@ -123,6 +496,108 @@ mod tests {
         assert_eq!([0x12, 0x34].usize(), 0x1234);
     }
 
+    #[test]
+    fn test_try_read() {
+        assert_eq!([0x12].try_u16(), None);
+        assert_eq!([0x12, 0x34, 0x56].try_u16(), Some(0x1234));
+        assert_eq!([0x12, 0x34, 0x56].try_u32(), None);
+        assert_eq!([0, 0, 0, 1].try_u32(), Some(1));
+        assert_eq!([0; 7].try_u64(), None);
+        assert_eq!([0, 0, 0, 0, 0, 0, 1, 0].try_u64(), Some(0x100));
+        assert_eq!(vec![0u8; (usize::BITS / 8) as usize - 1].try_usize(), None);
+        assert_eq!(
+            vec![0xffu8; (usize::BITS / 8) as usize].try_usize(),
+            Some(usize::MAX)
+        );
+    }
+
+    #[test]
+    fn test_read_at() {
+        let b = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+        assert_eq!(b.read_be::<u16>(1), Some(0x3456));
+        assert_eq!(b.read_le::<u16>(1), Some(0x5634));
+        assert_eq!(b.read_be::<u32>(4), Some(0x9abcdef0));
+        assert_eq!(b.read_le::<u32>(4), Some(0xf0debc9a));
+        assert_eq!(b.read_be::<u64>(0), Some(0x123456789abcdef0));
+        assert_eq!(b.read_le::<u64>(0), Some(0xf0debc9a78563412));
+        assert_eq!(b.read_be::<u8>(7), Some(0xf0));
+        assert_eq!(b.read_be::<i8>(7), Some(-16));
+        assert_eq!(b.read_be::<i16>(6), Some(-8464));
+        assert_eq!(b.read_le::<i16>(6), Some(-3874));
+        assert_eq!(b.read_be::<i32>(4), Some(-1698898192));
+        assert_eq!([0xff; 8].read_le::<i64>(0), Some(-1));
+        assert_eq!(b.read_be::<u16>(7), None);
+        assert_eq!(b.read_be::<u32>(5), None);
+        assert_eq!(b.read_le::<u64>(1), None);
+        assert_eq!(b.read_be::<u8>(8), None);
+        assert_eq!(b.read_be::<u16>(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_write_at() {
+        let mut b = [0u8; 8];
+        assert_eq!(b.write_be(1, 0x1234u16), Some(()));
+        assert_eq!(b.write_le(4, 0x1234_5678u32), Some(()));
+        assert_eq!(b, [0, 0x12, 0x34, 0, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(b.write_be(0, -2i64), Some(()));
+        assert_eq!(b, [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(b.write_be(7, 0u16), None);
+        assert_eq!(b.write_le(1, 0u64), None);
+        assert_eq!(b.write_be(usize::MAX, 0u8), None);
+
+        let mut buf = BytesMut::from(&[0u8; 4][..]);
+        buf.write_be(0, 0xdeadbeefu32).unwrap();
+        assert_eq!(buf.read_be::<u32>(0), Some(0xdeadbeef));
+    }
+
+    #[test]
+    fn test_fill_checksum() {
+        let mut b =
+            hex::decode("00000000a91dc7365cc861240a090002ffffff000a0900010808080801010101051408bad5e789aafe821aca0aedc5538d2f3d").unwrap();
+        b.fill_checksum(0).unwrap();
+        assert_eq!(b.read_be::<u16>(0), Some(0x8b78));
+        assert_eq!(b.checksum(), 0);
+        assert_eq!([0u8].fill_checksum(0), None);
+
+        let src = Ipv4Addr::new(192, 168, 0, 1);
+        let dst = Ipv4Addr::new(192, 168, 0, 199);
+        let mut udp = BytesMut::from(&hex::decode("1f900035000cffff61626364").unwrap()[..]);
+        udp.fill_checksum_ipv4(6, src, dst, 17).unwrap();
+        assert_eq!(udp.read_be::<u16>(6), Some(0x9931));
+
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let dst: Ipv6Addr = "fe80::2".parse().unwrap();
+        let mut echo = hex::decode("800000001234000170696e67").unwrap();
+        echo.fill_checksum_ipv6(2, src, dst, 58).unwrap();
+        assert!(echo.verify_checksum_ipv6(src, dst, 58));
+    }
+
+    #[test]
+    fn test_varint() {
+        for &v in &[0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u64::MAX] {
+            let mut buf = BytesMut::new();
+            buf.put_u8(0xaa);
+            buf.put_varint(v);
+            let (decoded, len) = buf.read_varint(1).unwrap();
+            assert_eq!(decoded, v);
+            assert_eq!(len, buf.len() - 1);
+        }
+        let mut buf = BytesMut::new();
+        buf.put_varint(300);
+        assert_eq!(&buf[..], &[0xac, 0x02]);
+        assert_eq!([0x80, 0x80].read_varint(0), None);
+        assert_eq!(
+            [0xff; 9]
+                .iter()
+                .chain(&[0x02])
+                .copied()
+                .collect::<Vec<u8>>()
+                .read_varint(0),
+            None
+        );
+        assert_eq!([0x01].read_varint(2), None);
+    }
+
     #[test]
     fn test_checksum() {
         assert_eq!(
@@ -136,6 +611,111 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_checksum_wide() {
+        let mut seed = 0x2545f491u32;
+        let data: Vec<u8> = (0..DATAGRAM_BUF_SIZE + 64)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        for start in 0..9 {
+            for len in (1..600).chain([1499, 1500, 4097, DATAGRAM_BUF_SIZE].iter().copied()) {
+                let b = &data[start..start + len];
+                assert_eq!(b.checksum(), checksum_reference(b), "{} {}", start, len);
+                assert_eq!(fold64(sum_native_scalar(b)), !checksum_reference(b));
+            }
+        }
+        // Past the reference's u32 accumulator: all-ones words sum to 0xffff.
+        let ones = vec![0xffu8; 1 << 22];
+        assert_eq!(ones.checksum(), 0);
+    }
+
+    #[test]
+    fn test_checksum_empty() {
+        assert_eq!([0u8; 0].checksum(), 0xffffu16);
+        assert_eq!([0x12u8].checksum(), !0x1200u16);
+    }
+
+    #[test]
+    fn test_checksum_pseudo_header() {
+        let src = Ipv4Addr::new(192, 168, 0, 1);
+        let dst = Ipv4Addr::new(192, 168, 0, 199);
+        let mut udp = hex::decode("1f900035000c000061626364").unwrap();
+        assert_eq!(udp.checksum_ipv4(src, dst, 17), 0x9931);
+        udp[6..8].copy_from_slice(&[0x99, 0x31]);
+        assert!(udp.verify_checksum_ipv4(src, dst, 17));
+        assert!(!udp.verify_checksum_ipv4(dst, dst, 17));
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut udp = hex::decode("1f900035000d00006162636465").unwrap();
+        assert_eq!(udp.checksum_ipv6(src, dst, 17), 0x5ad3);
+        udp[6..8].copy_from_slice(&[0x5a, 0xd3]);
+        assert!(udp.verify_checksum_ipv6(src, dst, 17));
+
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let dst: Ipv6Addr = "fe80::2".parse().unwrap();
+        let echo = hex::decode("800000001234000170696e67").unwrap();
+        assert_eq!(echo.checksum_ipv6(src, dst, 58), 0x91ae);
+    }
+
+    #[test]
+    fn test_checksum_update() {
+        for v in &[
+            "00000000a91dc7365cc861240a090002ffffff000a0900010808080801010101051408bad5e789aafe821aca0aedc5538d2f3d",
+            "00000000",
+            "000000001234123412341234",
+        ] {
+            let b = hex::decode(v).unwrap();
+            let csum = b.checksum();
+
+            let mut u = b.clone();
+            u[2..4].copy_from_slice(&[0xbe, 0xef]);
+            assert_eq!(csum.update_u16(b[2..4].u16(), 0xbeef), u.checksum());
+
+            let mut u = b.clone();
+            u[0..4].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+            assert_eq!(csum.update_u32(b[0..4].u32(), 0xffffffff), u.checksum());
+            assert_eq!(csum.update_bytes(&b[0..4], &u[0..4]), Some(u.checksum()));
+            assert_eq!(csum.update_bytes(&b[0..4], &u[0..2]), None);
+
+            let mut u = b.clone();
+            u.iter_mut().for_each(|x| *x = !*x);
+            assert_eq!(csum.update_bytes(&b, &u), Some(u.checksum()));
+
+            let mut u = b.clone();
+            let last = u.len() - 1;
+            u[last] = 0x5a;
+            assert_eq!(
+                csum.update_bytes(&b[last & !1..], &u[last & !1..]),
+                Some(u.checksum())
+            );
+        }
+    }
+
+    #[test]
+    fn test_checksum_update_address() {
+        let src = Ipv4Addr::new(192, 168, 0, 1);
+        let nat = Ipv4Addr::new(203, 0, 113, 7);
+        let dst = Ipv4Addr::new(192, 168, 0, 199);
+        let udp = hex::decode("1f900035000c000061626364").unwrap();
+        assert_eq!(
+            udp.checksum_ipv4(src, dst, 17).update_ipv4(src, nat),
+            udp.checksum_ipv4(nat, dst, 17)
+        );
+
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let nat: Ipv6Addr = "2001:db8::42".parse().unwrap();
+        let dst: Ipv6Addr = "fe80::2".parse().unwrap();
+        let echo = hex::decode("800000001234000170696e67").unwrap();
+        assert_eq!(
+            echo.checksum_ipv6(src, dst, 58).update_ipv6(src, nat),
+            echo.checksum_ipv6(nat, dst, 58)
+        );
+    }
+
     #[test]
     fn test_debug() {
         let a: f32 = 112.3;
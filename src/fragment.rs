@@ -0,0 +1,494 @@
+use crate::{Ipv4Builder, Ipv4Packet, PacketError, IPV4_HEADER_LEN};
+use bytes::{BufMut, BytesMut};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// Time a datagram has to arrive whole after its first fragment.
+pub const IPV4_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes buffered across all incomplete datagrams.
+pub const IPV4_REASSEMBLY_MEMORY: usize = 4 << 20;
+
+/// Splits `packet` into fragments of at most `mtu` bytes, or returns it whole if it fits.
+///
+/// Only options flagged for copying are repeated after the first fragment (RFC 791), and
+/// fragments of a fragment keep their place in the original datagram. Fails with
+/// `InvalidInput` if the packet has the don't fragment flag set or `mtu` cannot carry its
+/// header and 8 bytes of payload.
+pub fn fragment_ipv4(packet: &[u8], mtu: usize) -> io::Result<Vec<BytesMut>> {
+    let ip = Ipv4Packet::new(packet)?;
+    if ip.as_bytes().len() <= mtu {
+        return Ok(vec![BytesMut::from(ip.as_bytes())]);
+    }
+    if ip.dont_fragment() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "packet of {} bytes exceeds mtu {} and must not be fragmented",
+                ip.total_len(),
+                mtu
+            ),
+        ));
+    }
+    if mtu < ip.header_len() + 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("mtu {} too small to fragment", mtu),
+        ));
+    }
+    let copied = copied_options(ip.options());
+    let payload = ip.payload();
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() {
+        let options = if offset == 0 { ip.options() } else { &copied };
+        let room = (mtu - IPV4_HEADER_LEN - options.len()) & !7;
+        let end = payload.len().min(offset + room);
+        let fragment_offset = ip.fragment_offset() as usize + offset;
+        if fragment_offset > u16::MAX as usize {
+            return Err(PacketError::TooLarge(fragment_offset).into());
+        }
+        let fragment = Ipv4Builder::new(ip.src_addr(), ip.dst_addr(), ip.protocol())
+            .dscp(ip.dscp())
+            .ecn(ip.ecn())
+            .identification(ip.identification())
+            .ttl(ip.ttl())
+            .options(options)
+            .fragment_offset(fragment_offset as u16)
+            .more_fragments(end < payload.len() || ip.more_fragments())
+            .emit(&payload[offset..end])?;
+        fragments.push(fragment);
+        offset = end;
+    }
+    Ok(fragments)
+}
+
+/// Options with the copied flag set, padded to a multiple of 4 bytes.
+fn copied_options(options: &[u8]) -> Vec<u8> {
+    let mut copied = Vec::new();
+    let mut i = 0;
+    while i < options.len() {
+        let kind = options[i];
+        let len = match kind {
+            0 => break,
+            1 => 1,
+            _ => options.get(i + 1).map_or(0, |len| *len as usize),
+        };
+        if (kind != 1 && len < 2) || i + len > options.len() {
+            break;
+        }
+        if kind & 0x80 != 0 {
+            copied.extend_from_slice(&options[i..i + len]);
+        }
+        i += len;
+    }
+    while !copied.len().is_multiple_of(4) {
+        copied.push(0);
+    }
+    copied
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct DatagramKey {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    identification: u16,
+}
+
+#[derive(Debug)]
+struct Datagram {
+    /// Header of the first fragment, once it arrived.
+    header: Option<Vec<u8>>,
+    /// Fragment payloads by offset; they never overlap.
+    parts: BTreeMap<usize, Vec<u8>>,
+    /// Payload length, known once the last fragment arrived.
+    len: Option<usize>,
+    received: usize,
+    memory: usize,
+    expires: Instant,
+}
+
+impl Datagram {
+    /// Adds a fragment, returning the bytes it takes, or `None` if it conflicts with the
+    /// fragments already there.
+    fn insert(&mut self, header: &[u8], offset: usize, data: &[u8], last: bool) -> Option<usize> {
+        let end = offset + data.len();
+        // An identical fragment again is a duplicate, not an overlap.
+        if self.parts.get(&offset).map(Vec::len) == Some(data.len()) {
+            return Some(0);
+        }
+        match self.len {
+            Some(len) if end > len || (last && end != len) => return None,
+            None if last => {
+                let received_end = self.parts.iter().next_back().map(|(o, p)| o + p.len());
+                if received_end.is_some_and(|received_end| received_end > end) {
+                    return None;
+                }
+                self.len = Some(end);
+            }
+            _ => {}
+        }
+        if let Some((o, p)) = self.parts.range(..offset).next_back() {
+            if o + p.len() > offset {
+                return None;
+            }
+        }
+        if let Some((o, _)) = self.parts.range(offset..).next() {
+            if *o < end || (*o == offset && data.is_empty()) {
+                return None;
+            }
+        }
+        let mut memory = data.len();
+        if offset == 0 {
+            self.header = Some(header.to_vec());
+            memory += header.len();
+        }
+        self.parts.insert(offset, data.to_vec());
+        self.received += data.len();
+        self.memory += memory;
+        Some(memory)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.len == Some(self.received)
+    }
+
+    /// Joins the fragments under the first one's header, or `None` if too large.
+    fn assemble(self) -> Option<BytesMut> {
+        let header = self.header?;
+        let total_len = header.len() + self.received;
+        if total_len > u16::MAX as usize {
+            return None;
+        }
+        let mut buf = BytesMut::with_capacity(total_len);
+        buf.put_slice(&header);
+        for part in self.parts.values() {
+            buf.put_slice(part);
+        }
+        let mut ip = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ip.set_total_len(total_len as u16);
+        ip.set_more_fragments(false);
+        ip.set_fragment_offset(0);
+        ip.fill_checksum();
+        Some(buf)
+    }
+}
+
+/// Reassembles fragmented IPv4 datagrams, keyed by source, destination, protocol and
+/// identification (RFC 791).
+///
+/// Incomplete datagrams are dropped once they time out, when their fragments overlap or
+/// disagree on the length, and oldest first when the memory limit is reached.
+#[derive(Debug)]
+pub struct Ipv4Reassembler {
+    timeout: Duration,
+    memory_limit: usize,
+    datagrams: HashMap<DatagramKey, Datagram>,
+    memory: usize,
+}
+
+impl Default for Ipv4Reassembler {
+    fn default() -> Self {
+        Ipv4Reassembler {
+            timeout: IPV4_REASSEMBLY_TIMEOUT,
+            memory_limit: IPV4_REASSEMBLY_MEMORY,
+            datagrams: HashMap::new(),
+            memory: 0,
+        }
+    }
+}
+
+impl Ipv4Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time a datagram has to arrive whole after its first fragment.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Bytes buffered across all incomplete datagrams.
+    pub fn memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Number of incomplete datagrams.
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// Bytes buffered across all incomplete datagrams.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    pub fn clear(&mut self) {
+        self.datagrams.clear();
+        self.memory = 0;
+    }
+
+    /// Feeds one packet, returning it back if it is not a fragment, or the whole datagram
+    /// once its last missing fragment arrives.
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<BytesMut>, PacketError> {
+        let ip = Ipv4Packet::new(packet)?;
+        if !ip.more_fragments() && ip.fragment_offset() == 0 {
+            return Ok(Some(BytesMut::from(ip.as_bytes())));
+        }
+        let now = Instant::now();
+        let key = DatagramKey {
+            src: ip.src_addr(),
+            dst: ip.dst_addr(),
+            protocol: ip.protocol(),
+            identification: ip.identification(),
+        };
+        let (offset, payload) = (ip.fragment_offset() as usize, ip.payload());
+        if self
+            .datagrams
+            .get(&key)
+            .is_some_and(|datagram| datagram.expires <= now)
+        {
+            self.remove(&key);
+        }
+        // Every fragment but the last carries a multiple of 8 bytes.
+        if ip.more_fragments() && (payload.is_empty() || !payload.len().is_multiple_of(8)) {
+            self.remove(&key);
+            return Ok(None);
+        }
+
+        let memory = payload.len() + if offset == 0 { ip.header_len() } else { 0 };
+        if memory > self.memory_limit {
+            return Ok(None);
+        }
+        if self.memory + memory > self.memory_limit {
+            self.expire();
+        }
+        while self.memory + memory > self.memory_limit {
+            let oldest = self
+                .datagrams
+                .iter()
+                .filter(|(k, _)| **k != key)
+                .min_by_key(|(_, datagram)| datagram.expires)
+                .map(|(k, _)| *k);
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => return Ok(None),
+            }
+        }
+
+        let timeout = self.timeout;
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            header: None,
+            parts: BTreeMap::new(),
+            len: None,
+            received: 0,
+            memory: 0,
+            expires: now + timeout,
+        });
+        match datagram.insert(ip.header(), offset, payload, !ip.more_fragments()) {
+            Some(memory) => self.memory += memory,
+            None => {
+                self.remove(&key);
+                return Ok(None);
+            }
+        }
+        if !datagram.is_complete() {
+            return Ok(None);
+        }
+        let datagram = self.datagrams.remove(&key).unwrap();
+        self.memory -= datagram.memory;
+        Ok(datagram.assemble())
+    }
+
+    /// Drops timed out datagrams, returning how many there were.
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let len = self.datagrams.len();
+        let mut freed = 0;
+        self.datagrams.retain(|_, datagram| {
+            let live = datagram.expires > now;
+            if !live {
+                freed += datagram.memory;
+            }
+            live
+        });
+        self.memory -= freed;
+        len - self.datagrams.len()
+    }
+
+    fn remove(&mut self, key: &DatagramKey) {
+        if let Some(datagram) = self.datagrams.remove(key) {
+            self.memory -= datagram.memory;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IP_PROTO_UDP;
+    use std::thread;
+
+    fn datagram(len: usize, options: &[u8]) -> BytesMut {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        Ipv4Builder::new(
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            IP_PROTO_UDP,
+        )
+        .identification(0x1234)
+        .ttl(33)
+        .options(options)
+        .emit(&payload)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_fragment_ipv4() {
+        let packet = datagram(3000, &[]);
+        let fragments = fragment_ipv4(&packet, 1500).unwrap();
+        assert_eq!(fragments.len(), 3);
+        let mut offset = 0;
+        for (i, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.len() <= 1500);
+            let ip = Ipv4Packet::new(&fragment[..]).unwrap();
+            assert!(ip.verify_checksum());
+            assert_eq!(ip.identification(), 0x1234);
+            assert_eq!(ip.ttl(), 33);
+            assert_eq!(ip.fragment_offset() as usize, offset);
+            assert_eq!(ip.more_fragments(), i < 2);
+            assert_eq!(
+                ip.payload(),
+                &packet[20 + offset..20 + offset + ip.payload().len()]
+            );
+            offset += ip.payload().len();
+        }
+        assert_eq!(offset, 3000);
+
+        let mut reassembler = Ipv4Reassembler::new();
+        for fragment in fragments[1..].iter().rev() {
+            assert_eq!(reassembler.push(fragment).unwrap(), None);
+        }
+        assert_eq!(reassembler.len(), 1);
+        assert_eq!(reassembler.memory(), 1480 + 40);
+        assert_eq!(reassembler.push(&fragments[0]).unwrap().unwrap(), packet);
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+
+        // Packets that fit are left alone, including by the reassembler.
+        assert_eq!(fragment_ipv4(&packet, 3020).unwrap(), vec![packet.clone()]);
+        assert_eq!(reassembler.push(&packet).unwrap().unwrap(), packet);
+
+        let mut df = packet.to_vec();
+        df[6] |= 0x40;
+        assert_eq!(
+            fragment_ipv4(&df, 1500).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            fragment_ipv4(&packet, 27).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            reassembler.push(&packet[..19]).unwrap_err(),
+            PacketError::Truncated
+        );
+    }
+
+    #[test]
+    fn test_fragment_ipv4_options() {
+        // Router alert is copied into every fragment, record route only into the first.
+        let options = [0x94, 4, 0, 0, 0x07, 3, 4, 0];
+        let packet = datagram(100, &options);
+        let fragments = fragment_ipv4(&packet, 28 + 40).unwrap();
+        let first = Ipv4Packet::new(&fragments[0][..]).unwrap();
+        assert_eq!(first.options(), options);
+        assert_eq!(first.payload().len(), 40);
+        for fragment in &fragments[1..] {
+            let ip = Ipv4Packet::new(&fragment[..]).unwrap();
+            assert_eq!(ip.options(), [0x94, 4, 0, 0]);
+        }
+
+        // Fragments of a fragment keep their place in the datagram.
+        let packet = datagram(2000, &[]);
+        let fragments = fragment_ipv4(&packet, 1500).unwrap();
+        let refragmented: Vec<_> = fragments
+            .iter()
+            .flat_map(|fragment| fragment_ipv4(fragment, 576).unwrap())
+            .collect();
+        assert_eq!(refragmented.len(), 4);
+        let mut reassembler = Ipv4Reassembler::new();
+        let (last, rest) = refragmented.split_last().unwrap();
+        for fragment in rest {
+            assert_eq!(reassembler.push(fragment).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(last).unwrap().unwrap(), packet);
+    }
+
+    #[test]
+    fn test_reassembly_limits() {
+        let packet = datagram(3000, &[]);
+        let fragments = fragment_ipv4(&packet, 1500).unwrap();
+
+        // Duplicates are ignored, overlaps drop the datagram.
+        let mut reassembler = Ipv4Reassembler::new();
+        reassembler.push(&fragments[0]).unwrap();
+        reassembler.push(&fragments[0]).unwrap();
+        assert_eq!(reassembler.memory(), 1500);
+        let mut overlap = Ipv4Packet::new(fragments[1].to_vec()).unwrap();
+        overlap.set_fragment_offset(1000);
+        overlap.fill_checksum();
+        assert_eq!(reassembler.push(overlap.as_bytes()).unwrap(), None);
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.memory(), 0);
+
+        // Misaligned middle fragments are invalid.
+        let mut short = fragments[0].to_vec();
+        short.truncate(1499);
+        Ipv4Packet::new_unchecked(&mut short[..]).set_total_len(1499);
+        assert_eq!(reassembler.push(&short).unwrap(), None);
+        assert!(reassembler.is_empty());
+
+        let mut reassembler = Ipv4Reassembler::new().timeout(Duration::from_millis(50));
+        reassembler.push(&fragments[0]).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(reassembler.expire(), 1);
+        assert_eq!(reassembler.memory(), 0);
+        // A late fragment starts over rather than completing a timed out datagram.
+        reassembler.push(&fragments[0]).unwrap();
+        reassembler.push(&fragments[1]).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(reassembler.push(&fragments[2]).unwrap(), None);
+        assert_eq!(reassembler.len(), 1);
+
+        // The oldest datagram makes room for new ones.
+        let mut reassembler = Ipv4Reassembler::new().memory_limit(2000);
+        reassembler.push(&fragments[0]).unwrap();
+        let other = datagram(3000, &[]);
+        let mut other = fragment_ipv4(&other, 1500).unwrap();
+        for fragment in &mut other {
+            let mut ip = Ipv4Packet::new(&mut fragment[..]).unwrap();
+            ip.set_identification(0x4321);
+            ip.fill_checksum();
+        }
+        reassembler.push(&other[1]).unwrap();
+        assert_eq!(reassembler.len(), 1);
+        assert_eq!(reassembler.memory(), 1480);
+        assert_eq!(reassembler.push(&fragments[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&other[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&other[2]).unwrap(), None);
+        assert_eq!(reassembler.memory(), 1500 + 40);
+        assert_eq!(
+            reassembler.memory_limit(1000).push(&fragments[0]).unwrap(),
+            None
+        );
+    }
+}
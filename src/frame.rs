@@ -0,0 +1,255 @@
+use crate::{BytesExt, FRAME_LENGTH_SIZE, STREAM_BUF_SIZE};
+use async_std::io::{Read, Write, WriteExt};
+use async_std::net::TcpStream;
+use async_std::stream::{Stream, StreamExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Largest payload a `FRAME_LENGTH_SIZE` length prefix can describe.
+pub const MAX_FRAME_SIZE: usize = (1 << (8 * FRAME_LENGTH_SIZE)) - 1;
+
+fn frame_too_large(len: usize, max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {} bytes exceeds limit of {}", len, max),
+    )
+}
+
+/// Reads length-prefixed frames from a byte stream.
+///
+/// Every frame is a `FRAME_LENGTH_SIZE` big-endian length followed by that many bytes.
+/// Frames are yielded through [`Stream`], or [`recv`](FrameReader::recv).
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    // The first `filled` bytes were received, the rest is zeroed room for the next read.
+    buf: BytesMut,
+    filled: usize,
+    max_frame_size: usize,
+}
+
+impl<R: Read + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_max_frame_size(inner, MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(inner: R, max_frame_size: usize) -> Self {
+        FrameReader {
+            inner,
+            buf: BytesMut::with_capacity(STREAM_BUF_SIZE),
+            filled: 0,
+            max_frame_size: max_frame_size.min(MAX_FRAME_SIZE),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns the inner reader; frames already buffered are lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Receives the next frame, or `None` once the stream ends on a frame boundary.
+    pub async fn recv(&mut self) -> io::Result<Option<Bytes>> {
+        self.next().await.transpose()
+    }
+
+    fn decode(&mut self) -> io::Result<Option<Bytes>> {
+        if self.filled < FRAME_LENGTH_SIZE {
+            return Ok(None);
+        }
+        let len = self.buf[..FRAME_LENGTH_SIZE].usize();
+        if len > self.max_frame_size {
+            return Err(frame_too_large(len, self.max_frame_size));
+        }
+        if self.filled < FRAME_LENGTH_SIZE + len {
+            return Ok(None);
+        }
+        self.filled -= FRAME_LENGTH_SIZE + len;
+        let _ = self.buf.split_to(FRAME_LENGTH_SIZE);
+        Ok(Some(self.buf.split_to(len).freeze()))
+    }
+}
+
+impl<R: Read + Unpin> Stream for FrameReader<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.decode() {
+                Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                Ok(None) => {}
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+            // Only the room used by earlier reads is zeroed again, not a whole read per wakeup.
+            let room = this.filled + STREAM_BUF_SIZE;
+            if this.buf.len() < room {
+                this.buf.resize(room, 0);
+            }
+            let res = Pin::new(&mut this.inner).poll_read(cx, &mut this.buf[this.filled..]);
+            let n = match res {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Poll::Pending => return Poll::Pending,
+            };
+            this.filled += n;
+            if n == 0 {
+                return Poll::Ready(if this.filled == 0 {
+                    None
+                } else {
+                    Some(Err(io::ErrorKind::UnexpectedEof.into()))
+                });
+            }
+        }
+    }
+}
+
+/// Writes length-prefixed frames to a byte stream, see [`FrameReader`] for the format.
+#[derive(Debug)]
+pub struct FrameWriter<W> {
+    inner: W,
+    buf: BytesMut,
+    max_frame_size: usize,
+}
+
+impl<W: Write + Unpin> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_max_frame_size(inner, MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(inner: W, max_frame_size: usize) -> Self {
+        FrameWriter {
+            inner,
+            buf: BytesMut::with_capacity(STREAM_BUF_SIZE),
+            max_frame_size: max_frame_size.min(MAX_FRAME_SIZE),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Writes one frame; the length prefix and payload go out in a single write.
+    pub async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > self.max_frame_size {
+            return Err(frame_too_large(frame.len(), self.max_frame_size));
+        }
+        self.buf.clear();
+        self.buf.put_uint(frame.len() as u64, FRAME_LENGTH_SIZE);
+        self.buf.put_slice(frame);
+        self.inner.write_all(&self.buf).await
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+}
+
+/// Splits a TCP stream into a frame reader and writer sharing the connection.
+pub fn framed(stream: TcpStream) -> (FrameReader<TcpStream>, FrameWriter<TcpStream>) {
+    (FrameReader::new(stream.clone()), FrameWriter::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+    use async_std::net::TcpListener;
+    use async_std::task;
+
+    /// Hands out at most one byte per read, with every other read pending, to exercise
+    /// partial frames.
+    struct Trickle(Cursor<Vec<u8>>, bool);
+
+    impl Read for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.1 = !self.1;
+            if self.1 {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let len = buf.len().min(1);
+            Pin::new(&mut self.0).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        task::block_on(async {
+            let mut w = FrameWriter::new(Cursor::new(Vec::new()));
+            w.send(b"hello").await.unwrap();
+            w.send(b"").await.unwrap();
+            w.send(&[7u8; MAX_FRAME_SIZE]).await.unwrap();
+            let b = w.into_inner().into_inner();
+            assert_eq!(&b[..7], b"\x00\x05hello");
+            assert_eq!(b.len(), 7 + 2 + 2 + MAX_FRAME_SIZE);
+
+            let mut r = FrameReader::new(Trickle(Cursor::new(b), false));
+            assert_eq!(r.recv().await.unwrap().unwrap(), &b"hello"[..]);
+            assert_eq!(r.recv().await.unwrap().unwrap(), &b""[..]);
+            assert_eq!(r.recv().await.unwrap().unwrap().len(), MAX_FRAME_SIZE);
+            assert!(r.recv().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        task::block_on(async {
+            let mut w = FrameWriter::with_max_frame_size(Cursor::new(Vec::new()), 4);
+            assert_eq!(
+                w.send(b"hello").await.unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+
+            let mut r = FrameReader::with_max_frame_size(Cursor::new(b"\x00\x05hello".to_vec()), 4);
+            assert_eq!(
+                r.recv().await.unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+
+            let mut r = FrameReader::new(Cursor::new(b"\x00\x05hel".to_vec()));
+            assert_eq!(
+                r.recv().await.unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        });
+    }
+
+    #[test]
+    fn test_frame_tcp() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (mut r, mut w) = framed(stream);
+                while let Some(frame) = r.next().await {
+                    w.send(&frame.unwrap()).await.unwrap();
+                }
+            });
+            let (mut r, mut w) = framed(TcpStream::connect(addr).await.unwrap());
+            for i in 0..100u8 {
+                w.send(&vec![i; i as usize * 100]).await.unwrap();
+            }
+            for i in 0..100u8 {
+                assert_eq!(r.recv().await.unwrap().unwrap(), vec![i; i as usize * 100]);
+            }
+            w.get_ref().shutdown(std::net::Shutdown::Write).unwrap();
+            assert!(r.recv().await.unwrap().is_none());
+            server.await;
+        });
+    }
+}
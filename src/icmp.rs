@@ -0,0 +1,660 @@
+use crate::{
+    BytesExt, BytesMutExt, Ipv4Builder, Ipv4Packet, Ipv6Builder, Ipv6Packet, PacketError,
+    IPV4_HEADER_LEN, IPV6_HEADER_LEN, IP_PROTO_ICMP, IP_PROTO_ICMPV6,
+};
+use bytes::{BufMut, BytesMut};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const ICMP_HEADER_LEN: usize = 8;
+
+pub const ICMPV4_ECHO_REPLY: u8 = 0;
+pub const ICMPV4_DEST_UNREACHABLE: u8 = 3;
+pub const ICMPV4_SOURCE_QUENCH: u8 = 4;
+pub const ICMPV4_REDIRECT: u8 = 5;
+pub const ICMPV4_ECHO_REQUEST: u8 = 8;
+pub const ICMPV4_TIME_EXCEEDED: u8 = 11;
+pub const ICMPV4_PARAMETER_PROBLEM: u8 = 12;
+
+pub const ICMPV4_NET_UNREACHABLE: u8 = 0;
+pub const ICMPV4_HOST_UNREACHABLE: u8 = 1;
+pub const ICMPV4_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const ICMPV4_PORT_UNREACHABLE: u8 = 3;
+pub const ICMPV4_FRAGMENTATION_NEEDED: u8 = 4;
+pub const ICMPV4_ADMIN_PROHIBITED: u8 = 13;
+
+pub const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+pub const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+pub const ICMPV6_TIME_EXCEEDED: u8 = 3;
+pub const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+pub const ICMPV6_NO_ROUTE: u8 = 0;
+pub const ICMPV6_ADMIN_PROHIBITED: u8 = 1;
+pub const ICMPV6_ADDRESS_UNREACHABLE: u8 = 3;
+pub const ICMPV6_PORT_UNREACHABLE: u8 = 4;
+
+/// Time Exceeded code for a hop limit or TTL that reached zero, for both families.
+pub const ICMP_HOP_LIMIT_EXCEEDED: u8 = 0;
+/// Time Exceeded code for a reassembly that timed out, for both families.
+pub const ICMP_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+/// Largest ICMPv4 error packet, RFC 1812 section 4.3.2.3.
+pub const ICMPV4_ERROR_MAX_LEN: usize = 576;
+/// Largest ICMPv6 error packet, the IPv6 minimum MTU (RFC 4443 section 2.4).
+pub const ICMPV6_ERROR_MAX_LEN: usize = 1280;
+
+/// Zero-copy view over an ICMPv4 or ICMPv6 message; both share the same header layout.
+#[derive(Debug, Clone)]
+pub struct IcmpPacket<T: AsRef<[u8]>> {
+    buf: T,
+}
+
+impl<T: AsRef<[u8]>> IcmpPacket<T> {
+    /// Wraps `buf` without looking at it. Accessors may panic on a malformed buffer.
+    pub fn new_unchecked(buf: T) -> Self {
+        IcmpPacket { buf }
+    }
+
+    /// Wraps `buf` after checking it holds a whole header.
+    pub fn new(buf: T) -> Result<Self, PacketError> {
+        if buf.as_ref().len() < ICMP_HEADER_LEN {
+            return Err(PacketError::Truncated);
+        }
+        Ok(IcmpPacket { buf })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buf
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buf.as_ref()
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        self.buf.as_ref()[0]
+    }
+
+    pub fn code(&self) -> u8 {
+        self.buf.as_ref()[1]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.buf.as_ref()[2..].u16()
+    }
+
+    /// The four type-specific header bytes after the checksum.
+    pub fn rest_of_header(&self) -> u32 {
+        self.buf.as_ref()[4..].u32()
+    }
+
+    /// Everything after the header: echo data, or the quoted packet of an error message.
+    pub fn payload(&self) -> &[u8] {
+        &self.buf.as_ref()[ICMP_HEADER_LEN..]
+    }
+
+    pub fn verify_checksum_v4(&self) -> bool {
+        self.buf.as_ref().checksum() == 0
+    }
+
+    pub fn verify_checksum_v6(&self, src: Ipv6Addr, dst: Ipv6Addr) -> bool {
+        self.buf
+            .as_ref()
+            .verify_checksum_ipv6(src, dst, IP_PROTO_ICMPV6)
+    }
+
+    pub fn icmpv4_message(&self) -> Icmpv4Message {
+        let rest = self.rest_of_header();
+        let (identifier, sequence) = ((rest >> 16) as u16, rest as u16);
+        match self.msg_type() {
+            ICMPV4_ECHO_REPLY => Icmpv4Message::EchoReply {
+                identifier,
+                sequence,
+            },
+            ICMPV4_ECHO_REQUEST => Icmpv4Message::EchoRequest {
+                identifier,
+                sequence,
+            },
+            ICMPV4_DEST_UNREACHABLE => Icmpv4Message::DestUnreachable {
+                code: self.code(),
+                next_hop_mtu: rest as u16,
+            },
+            ICMPV4_TIME_EXCEEDED => Icmpv4Message::TimeExceeded { code: self.code() },
+            ICMPV4_PARAMETER_PROBLEM => Icmpv4Message::ParameterProblem {
+                code: self.code(),
+                pointer: (rest >> 24) as u8,
+            },
+            msg_type => Icmpv4Message::Other {
+                msg_type,
+                code: self.code(),
+                rest_of_header: rest,
+            },
+        }
+    }
+
+    pub fn icmpv6_message(&self) -> Icmpv6Message {
+        let rest = self.rest_of_header();
+        let (identifier, sequence) = ((rest >> 16) as u16, rest as u16);
+        match self.msg_type() {
+            ICMPV6_ECHO_REQUEST => Icmpv6Message::EchoRequest {
+                identifier,
+                sequence,
+            },
+            ICMPV6_ECHO_REPLY => Icmpv6Message::EchoReply {
+                identifier,
+                sequence,
+            },
+            ICMPV6_DEST_UNREACHABLE => Icmpv6Message::DestUnreachable { code: self.code() },
+            ICMPV6_PACKET_TOO_BIG => Icmpv6Message::PacketTooBig { mtu: rest },
+            ICMPV6_TIME_EXCEEDED => Icmpv6Message::TimeExceeded { code: self.code() },
+            ICMPV6_PARAMETER_PROBLEM => Icmpv6Message::ParameterProblem {
+                code: self.code(),
+                pointer: rest,
+            },
+            msg_type => Icmpv6Message::Other {
+                msg_type,
+                code: self.code(),
+                rest_of_header: rest,
+            },
+        }
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> IcmpPacket<T> {
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut()[ICMP_HEADER_LEN..]
+    }
+
+    pub fn fill_checksum_v4(&mut self) {
+        self.buf.as_mut().fill_checksum(2).unwrap();
+    }
+
+    pub fn fill_checksum_v6(&mut self, src: Ipv6Addr, dst: Ipv6Addr) {
+        self.buf
+            .as_mut()
+            .fill_checksum_ipv6(2, src, dst, IP_PROTO_ICMPV6)
+            .unwrap();
+    }
+}
+
+/// Header fields of the common ICMPv4 messages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Icmpv4Message {
+    EchoReply {
+        identifier: u16,
+        sequence: u16,
+    },
+    EchoRequest {
+        identifier: u16,
+        sequence: u16,
+    },
+    /// `next_hop_mtu` is only meaningful with [`ICMPV4_FRAGMENTATION_NEEDED`] (RFC 1191).
+    DestUnreachable {
+        code: u8,
+        next_hop_mtu: u16,
+    },
+    TimeExceeded {
+        code: u8,
+    },
+    /// `pointer` is the offset of the offending byte in the quoted packet.
+    ParameterProblem {
+        code: u8,
+        pointer: u8,
+    },
+    Other {
+        msg_type: u8,
+        code: u8,
+        rest_of_header: u32,
+    },
+}
+
+impl Icmpv4Message {
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            Icmpv4Message::EchoReply { .. } => ICMPV4_ECHO_REPLY,
+            Icmpv4Message::EchoRequest { .. } => ICMPV4_ECHO_REQUEST,
+            Icmpv4Message::DestUnreachable { .. } => ICMPV4_DEST_UNREACHABLE,
+            Icmpv4Message::TimeExceeded { .. } => ICMPV4_TIME_EXCEEDED,
+            Icmpv4Message::ParameterProblem { .. } => ICMPV4_PARAMETER_PROBLEM,
+            Icmpv4Message::Other { msg_type, .. } => *msg_type,
+        }
+    }
+
+    /// Whether this message reports an error about another packet.
+    pub fn is_error(&self) -> bool {
+        is_icmpv4_error(self.msg_type())
+    }
+
+    fn code_and_rest(&self) -> (u8, u32) {
+        match *self {
+            Icmpv4Message::EchoReply {
+                identifier,
+                sequence,
+            }
+            | Icmpv4Message::EchoRequest {
+                identifier,
+                sequence,
+            } => (0, (identifier as u32) << 16 | sequence as u32),
+            Icmpv4Message::DestUnreachable { code, next_hop_mtu } => (code, next_hop_mtu as u32),
+            Icmpv4Message::TimeExceeded { code } => (code, 0),
+            Icmpv4Message::ParameterProblem { code, pointer } => (code, (pointer as u32) << 24),
+            Icmpv4Message::Other {
+                code,
+                rest_of_header,
+                ..
+            } => (code, rest_of_header),
+        }
+    }
+
+    /// Writes the message followed by `payload`, filling in the checksum.
+    pub fn emit(&self, payload: &[u8]) -> BytesMut {
+        let (code, rest) = self.code_and_rest();
+        let mut buf = emit(self.msg_type(), code, rest, payload);
+        buf.fill_checksum(2).unwrap();
+        buf
+    }
+}
+
+/// Header fields of the common ICMPv6 messages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Icmpv6Message {
+    DestUnreachable {
+        code: u8,
+    },
+    PacketTooBig {
+        mtu: u32,
+    },
+    TimeExceeded {
+        code: u8,
+    },
+    /// `pointer` is the offset of the offending byte in the quoted packet.
+    ParameterProblem {
+        code: u8,
+        pointer: u32,
+    },
+    EchoRequest {
+        identifier: u16,
+        sequence: u16,
+    },
+    EchoReply {
+        identifier: u16,
+        sequence: u16,
+    },
+    Other {
+        msg_type: u8,
+        code: u8,
+        rest_of_header: u32,
+    },
+}
+
+impl Icmpv6Message {
+    pub fn msg_type(&self) -> u8 {
+        match self {
+            Icmpv6Message::DestUnreachable { .. } => ICMPV6_DEST_UNREACHABLE,
+            Icmpv6Message::PacketTooBig { .. } => ICMPV6_PACKET_TOO_BIG,
+            Icmpv6Message::TimeExceeded { .. } => ICMPV6_TIME_EXCEEDED,
+            Icmpv6Message::ParameterProblem { .. } => ICMPV6_PARAMETER_PROBLEM,
+            Icmpv6Message::EchoRequest { .. } => ICMPV6_ECHO_REQUEST,
+            Icmpv6Message::EchoReply { .. } => ICMPV6_ECHO_REPLY,
+            Icmpv6Message::Other { msg_type, .. } => *msg_type,
+        }
+    }
+
+    /// Whether this message reports an error about another packet.
+    pub fn is_error(&self) -> bool {
+        self.msg_type() < 128
+    }
+
+    fn code_and_rest(&self) -> (u8, u32) {
+        match *self {
+            Icmpv6Message::DestUnreachable { code } | Icmpv6Message::TimeExceeded { code } => {
+                (code, 0)
+            }
+            Icmpv6Message::PacketTooBig { mtu } => (0, mtu),
+            Icmpv6Message::ParameterProblem { code, pointer } => (code, pointer),
+            Icmpv6Message::EchoRequest {
+                identifier,
+                sequence,
+            }
+            | Icmpv6Message::EchoReply {
+                identifier,
+                sequence,
+            } => (0, (identifier as u32) << 16 | sequence as u32),
+            Icmpv6Message::Other {
+                code,
+                rest_of_header,
+                ..
+            } => (code, rest_of_header),
+        }
+    }
+
+    /// Writes the message followed by `payload`, with the checksum computed over the
+    /// pseudo-header of `src` and `dst`.
+    pub fn emit(&self, src: Ipv6Addr, dst: Ipv6Addr, payload: &[u8]) -> BytesMut {
+        let mut buf = self.emit_unchecked(payload);
+        buf.fill_checksum_ipv6(2, src, dst, IP_PROTO_ICMPV6)
+            .unwrap();
+        buf
+    }
+
+    /// Writes the message with a zero checksum, for sockets where the kernel fills it in.
+    pub fn emit_unchecked(&self, payload: &[u8]) -> BytesMut {
+        let (code, rest) = self.code_and_rest();
+        emit(self.msg_type(), code, rest, payload)
+    }
+}
+
+fn emit(msg_type: u8, code: u8, rest: u32, payload: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(ICMP_HEADER_LEN + payload.len());
+    buf.put_u8(msg_type);
+    buf.put_u8(code);
+    buf.put_u16(0);
+    buf.put_u32(rest);
+    buf.put_slice(payload);
+    buf
+}
+
+fn is_icmpv4_error(msg_type: u8) -> bool {
+    matches!(
+        msg_type,
+        ICMPV4_DEST_UNREACHABLE
+            | ICMPV4_SOURCE_QUENCH
+            | ICMPV4_REDIRECT
+            | ICMPV4_TIME_EXCEEDED
+            | ICMPV4_PARAMETER_PROBLEM
+    )
+}
+
+/// Whether RFC 1122 allows an ICMPv4 error about `packet`: not about an ICMP error, a
+/// non-first fragment, or a packet without a unicast source and destination.
+pub fn icmpv4_error_allowed<T: AsRef<[u8]>>(packet: &Ipv4Packet<T>) -> bool {
+    let (src, dst) = (packet.src_addr(), packet.dst_addr());
+    if dst.is_broadcast() || dst.is_multicast() {
+        return false;
+    }
+    if src.is_unspecified() || src.is_broadcast() || src.is_multicast() {
+        return false;
+    }
+    if packet.fragment_offset() != 0 {
+        return false;
+    }
+    !(packet.protocol() == IP_PROTO_ICMP
+        && packet
+            .payload()
+            .first()
+            .is_some_and(|t| is_icmpv4_error(*t)))
+}
+
+/// Whether RFC 4443 allows an ICMPv6 error about `packet`: not about an ICMPv6 error or a
+/// packet from an unspecified or multicast source. Multicast destinations only get Packet
+/// Too Big.
+pub fn icmpv6_error_allowed<T: AsRef<[u8]>>(
+    packet: &Ipv6Packet<T>,
+    message: &Icmpv6Message,
+) -> bool {
+    let src = packet.src_addr();
+    if src.is_unspecified() || src.is_multicast() {
+        return false;
+    }
+    if packet.dst_addr().is_multicast() && message.msg_type() != ICMPV6_PACKET_TOO_BIG {
+        return false;
+    }
+    // Only the first fragment starts with the ICMPv6 header.
+    if packet.fragment().is_some_and(|f| f.offset != 0) {
+        return true;
+    }
+    !(packet.protocol() == IP_PROTO_ICMPV6
+        && packet
+            .upper_layer_payload()
+            .first()
+            .is_some_and(|t| *t < 128))
+}
+
+/// Builds an IPv4 packet from `src` carrying `message` about `packet`, addressed to its sender.
+///
+/// As much of `packet` is quoted as fits in [`ICMPV4_ERROR_MAX_LEN`].
+pub fn icmpv4_error(
+    src: Ipv4Addr,
+    packet: &[u8],
+    message: Icmpv4Message,
+) -> Result<BytesMut, PacketError> {
+    let packet = Ipv4Packet::new(packet)?;
+    let quoted = packet.as_bytes();
+    let quoted = &quoted[..quoted
+        .len()
+        .min(ICMPV4_ERROR_MAX_LEN - IPV4_HEADER_LEN - ICMP_HEADER_LEN)];
+    Ipv4Builder::new(src, packet.src_addr(), IP_PROTO_ICMP).emit(&message.emit(quoted))
+}
+
+/// Builds an IPv6 packet from `src` carrying `message` about `packet`, addressed to its sender.
+///
+/// As much of `packet` is quoted as fits in [`ICMPV6_ERROR_MAX_LEN`].
+pub fn icmpv6_error(
+    src: Ipv6Addr,
+    packet: &[u8],
+    message: Icmpv6Message,
+) -> Result<BytesMut, PacketError> {
+    let packet = Ipv6Packet::new(packet)?;
+    let dst = packet.src_addr();
+    let quoted = packet.as_bytes();
+    let quoted = &quoted[..quoted
+        .len()
+        .min(ICMPV6_ERROR_MAX_LEN - IPV6_HEADER_LEN - ICMP_HEADER_LEN)];
+    Ipv6Builder::new(src, dst, IP_PROTO_ICMPV6).emit(&message.emit(src, dst, quoted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IP_PROTO_UDP;
+
+    #[test]
+    fn test_icmpv4_echo() {
+        let message = Icmpv4Message::EchoRequest {
+            identifier: 0x1234,
+            sequence: 7,
+        };
+        let b = message.emit(b"abcd");
+        assert_eq!(hex::encode(&b), "080020fe1234000761626364");
+        let p = IcmpPacket::new(&b[..]).unwrap();
+        assert!(p.verify_checksum_v4());
+        assert_eq!(p.icmpv4_message(), message);
+        assert_eq!(p.payload(), b"abcd");
+        assert!(!message.is_error());
+        assert_eq!(
+            IcmpPacket::new(&b[..7]).unwrap_err(),
+            PacketError::Truncated
+        );
+    }
+
+    #[test]
+    fn test_icmpv4_messages() {
+        for message in &[
+            Icmpv4Message::DestUnreachable {
+                code: ICMPV4_FRAGMENTATION_NEEDED,
+                next_hop_mtu: 1400,
+            },
+            Icmpv4Message::TimeExceeded {
+                code: ICMP_HOP_LIMIT_EXCEEDED,
+            },
+            Icmpv4Message::ParameterProblem {
+                code: 0,
+                pointer: 9,
+            },
+            // Code 2, bad length.
+            Icmpv4Message::ParameterProblem {
+                code: 2,
+                pointer: 2,
+            },
+            Icmpv4Message::Other {
+                msg_type: 13,
+                code: 0,
+                rest_of_header: 0xdeadbeef,
+            },
+        ] {
+            let b = message.emit(&[1, 2, 3]);
+            let p = IcmpPacket::new(&b[..]).unwrap();
+            assert!(p.verify_checksum_v4());
+            assert_eq!(p.icmpv4_message(), *message);
+        }
+    }
+
+    #[test]
+    fn test_icmpv6_messages() {
+        let src: Ipv6Addr = "fe80::1".parse().unwrap();
+        let dst: Ipv6Addr = "fe80::2".parse().unwrap();
+        for message in &[
+            Icmpv6Message::DestUnreachable {
+                code: ICMPV6_PORT_UNREACHABLE,
+            },
+            Icmpv6Message::PacketTooBig { mtu: 1280 },
+            Icmpv6Message::TimeExceeded {
+                code: ICMP_REASSEMBLY_TIME_EXCEEDED,
+            },
+            Icmpv6Message::ParameterProblem {
+                code: 1,
+                pointer: 40,
+            },
+            Icmpv6Message::EchoReply {
+                identifier: 1,
+                sequence: 2,
+            },
+        ] {
+            let b = message.emit(src, dst, b"data");
+            let p = IcmpPacket::new(&b[..]).unwrap();
+            assert!(p.verify_checksum_v6(src, dst));
+            assert!(!p.verify_checksum_v6(dst, "fe80::3".parse().unwrap()));
+            assert_eq!(p.icmpv6_message(), *message);
+            assert_eq!(message.is_error(), message.msg_type() < 128);
+        }
+        let mut b = Icmpv6Message::EchoRequest {
+            identifier: 1,
+            sequence: 2,
+        }
+        .emit_unchecked(&[]);
+        assert_eq!(hex::encode(&b), "8000000000010002");
+        let mut p = IcmpPacket::new(&mut b[..]).unwrap();
+        p.fill_checksum_v6(src, dst);
+        assert!(p.verify_checksum_v6(src, dst));
+    }
+
+    #[test]
+    fn test_icmpv4_error() {
+        let client = Ipv4Addr::new(10, 0, 0, 2);
+        let router = Ipv4Addr::new(10, 0, 0, 1);
+        let original = Ipv4Builder::new(client, Ipv4Addr::new(192, 0, 2, 1), IP_PROTO_UDP)
+            .ttl(1)
+            .emit(&[0x55; 1000])
+            .unwrap();
+        let op = Ipv4Packet::new(&original[..]).unwrap();
+        assert!(icmpv4_error_allowed(&op));
+
+        let message = Icmpv4Message::TimeExceeded {
+            code: ICMP_HOP_LIMIT_EXCEEDED,
+        };
+        let b = icmpv4_error(router, &original, message).unwrap();
+        assert_eq!(b.len(), ICMPV4_ERROR_MAX_LEN);
+        let ip = Ipv4Packet::new(&b[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!(ip.src_addr(), router);
+        assert_eq!(ip.dst_addr(), client);
+        assert_eq!(ip.protocol(), IP_PROTO_ICMP);
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum_v4());
+        assert_eq!(icmp.icmpv4_message(), message);
+        assert_eq!(icmp.payload(), &original[..icmp.payload().len()]);
+
+        // Short packets are quoted whole.
+        let short = Ipv4Builder::new(client, router, IP_PROTO_UDP)
+            .emit(&[1; 8])
+            .unwrap();
+        let b = icmpv4_error(router, &short, message).unwrap();
+        assert_eq!(&b[IPV4_HEADER_LEN + ICMP_HEADER_LEN..], &short[..]);
+
+        // No errors about errors, fragments or broadcasts.
+        let ip = Ipv4Packet::new(&b[..]).unwrap();
+        assert!(!icmpv4_error_allowed(&ip));
+        let echo = Ipv4Builder::new(client, router, IP_PROTO_ICMP)
+            .emit(
+                &Icmpv4Message::EchoRequest {
+                    identifier: 1,
+                    sequence: 1,
+                }
+                .emit(&[]),
+            )
+            .unwrap();
+        assert!(icmpv4_error_allowed(&Ipv4Packet::new(&echo[..]).unwrap()));
+        let fragment = Ipv4Builder::new(client, router, IP_PROTO_UDP)
+            .fragment_offset(8)
+            .emit(&[0; 8])
+            .unwrap();
+        assert!(!icmpv4_error_allowed(
+            &Ipv4Packet::new(&fragment[..]).unwrap()
+        ));
+        let broadcast = Ipv4Builder::new(client, Ipv4Addr::BROADCAST, IP_PROTO_UDP)
+            .emit(&[0; 8])
+            .unwrap();
+        assert!(!icmpv4_error_allowed(
+            &Ipv4Packet::new(&broadcast[..]).unwrap()
+        ));
+        assert_eq!(
+            icmpv4_error(router, &original[..10], message).unwrap_err(),
+            PacketError::Truncated
+        );
+    }
+
+    #[test]
+    fn test_icmpv6_error() {
+        let client: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let router: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let original = Ipv6Builder::new(client, "2001:db8:1::1".parse().unwrap(), IP_PROTO_UDP)
+            .emit(&[0x55; 1400])
+            .unwrap();
+        let message = Icmpv6Message::PacketTooBig { mtu: 1280 };
+        let op = Ipv6Packet::new(&original[..]).unwrap();
+        assert!(icmpv6_error_allowed(&op, &message));
+
+        let b = icmpv6_error(router, &original, message).unwrap();
+        assert_eq!(b.len(), ICMPV6_ERROR_MAX_LEN);
+        let ip = Ipv6Packet::new(&b[..]).unwrap();
+        assert_eq!(ip.src_addr(), router);
+        assert_eq!(ip.dst_addr(), client);
+        assert_eq!(ip.protocol(), IP_PROTO_ICMPV6);
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum_v6(router, client));
+        assert_eq!(icmp.icmpv6_message(), message);
+        assert_eq!(icmp.payload(), &original[..icmp.payload().len()]);
+
+        assert!(!icmpv6_error_allowed(&ip, &message));
+        let multicast = Ipv6Builder::new(client, "ff02::1".parse().unwrap(), IP_PROTO_UDP)
+            .emit(&[0; 8])
+            .unwrap();
+        let multicast = Ipv6Packet::new(&multicast[..]).unwrap();
+        assert!(icmpv6_error_allowed(&multicast, &message));
+        assert!(!icmpv6_error_allowed(
+            &multicast,
+            &Icmpv6Message::DestUnreachable {
+                code: ICMPV6_NO_ROUTE
+            }
+        ));
+
+        // A later fragment of an echo request whose data starts with an error type byte.
+        let fragment = Ipv6Builder::new(client, router, IP_PROTO_ICMPV6)
+            .fragment(1232, false, 7)
+            .emit(&[1; 8])
+            .unwrap();
+        assert!(icmpv6_error_allowed(
+            &Ipv6Packet::new(&fragment[..]).unwrap(),
+            &message
+        ));
+        let first = Ipv6Builder::new(client, router, IP_PROTO_ICMPV6)
+            .fragment(0, true, 7)
+            .emit(&[1; 8])
+            .unwrap();
+        assert!(!icmpv6_error_allowed(
+            &Ipv6Packet::new(&first[..]).unwrap(),
+            &message
+        ));
+    }
+}
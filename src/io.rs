@@ -0,0 +1,241 @@
+use crate::constant::BUFFER_SIZE;
+use crate::{BytesExt, FRAME_LENGTH_SIZE};
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std::net::{SocketAddr, UdpSocket};
+use async_trait::async_trait;
+use std::io;
+use std::ops::{Deref, DerefMut};
+
+/// Heap buffer of `BUFFER_SIZE` bytes, large enough for any IP packet, meant to be reused
+/// across reads.
+///
+/// Derefs to the filled part; [`storage_mut`](Buffer::storage_mut) and
+/// [`set_len`](Buffer::set_len) fill it.
+#[derive(Clone)]
+pub struct Buffer {
+    data: Box<[u8]>,
+    len: usize,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer {
+            data: vec![0u8; BUFFER_SIZE].into_boxed_slice(),
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0
+    }
+
+    /// The whole backing storage, regardless of how much of it is filled.
+    pub fn storage_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Marks the first `len` bytes of the storage as filled.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.capacity(), "buffer length out of range");
+        self.len = len
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Buffer")
+            .field("len", &self.len)
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+/// Whole-packet I/O on datagram sockets.
+#[async_trait]
+pub trait DatagramExt {
+    /// Sends `buf` as one datagram, failing if only part of it was sent.
+    async fn send_all(&self, buf: &[u8]) -> io::Result<()>;
+    /// Sends `buf` as one datagram to `addr`, failing if only part of it was sent.
+    async fn send_all_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<()>;
+    /// Receives one datagram into `buf`, replacing its contents.
+    async fn recv_buf(&self, buf: &mut Buffer) -> io::Result<usize>;
+    /// Receives one datagram into `buf`, replacing its contents, and returns the sender.
+    async fn recv_buf_from(&self, buf: &mut Buffer) -> io::Result<SocketAddr>;
+}
+
+fn check_sent(n: usize, len: usize) -> io::Result<()> {
+    if n != len {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            format!("sent {} of {} bytes", n, len),
+        ));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl DatagramExt for UdpSocket {
+    async fn send_all(&self, buf: &[u8]) -> io::Result<()> {
+        check_sent(self.send(buf).await?, buf.len())
+    }
+
+    async fn send_all_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
+        check_sent(self.send_to(buf, addr).await?, buf.len())
+    }
+
+    async fn recv_buf(&self, buf: &mut Buffer) -> io::Result<usize> {
+        let n = self.recv(buf.storage_mut()).await?;
+        buf.set_len(n);
+        Ok(n)
+    }
+
+    async fn recv_buf_from(&self, buf: &mut Buffer) -> io::Result<SocketAddr> {
+        let (n, addr) = self.recv_from(buf.storage_mut()).await?;
+        buf.set_len(n);
+        Ok(addr)
+    }
+}
+
+/// Reads `FRAME_LENGTH_SIZE` length-prefixed messages without buffering past them.
+///
+/// Unlike [`FrameReader`](crate::FrameReader) this never reads ahead, so the stream can be
+/// handed to something else between messages.
+#[async_trait]
+pub trait ReadPacketExt: Read + Unpin + Send {
+    /// Reads one message into `buf`. Returns `None` if the stream ended before a new message.
+    async fn read_packet(&mut self, buf: &mut Buffer) -> io::Result<Option<usize>> {
+        let mut len = [0u8; FRAME_LENGTH_SIZE];
+        let n = self.read(&mut len).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.read_exact(&mut len[n..]).await?;
+        let len = len.usize();
+        if len > buf.capacity() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("packet of {} bytes exceeds buffer", len),
+            ));
+        }
+        buf.clear();
+        self.read_exact(&mut buf.storage_mut()[..len]).await?;
+        buf.set_len(len);
+        Ok(Some(len))
+    }
+}
+
+impl<T: Read + Unpin + Send + ?Sized> ReadPacketExt for T {}
+
+/// Writes messages in the format read by [`ReadPacketExt`].
+#[async_trait]
+pub trait WritePacketExt: Write + Unpin + Send {
+    async fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        if packet.len() >> (8 * FRAME_LENGTH_SIZE) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("packet of {} bytes too large to frame", packet.len()),
+            ));
+        }
+        let len = (packet.len() as u64).to_be_bytes();
+        self.write_all(&len[len.len() - FRAME_LENGTH_SIZE..])
+            .await?;
+        self.write_all(packet).await
+    }
+}
+
+impl<T: Write + Unpin + Send + ?Sized> WritePacketExt for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_udp_pair;
+    use async_std::io::Cursor;
+    use async_std::task;
+
+    #[test]
+    fn test_buffer() {
+        let mut buf = Buffer::new();
+        assert_eq!(buf.capacity(), BUFFER_SIZE);
+        assert!(buf.is_empty());
+        buf.storage_mut()[..3].copy_from_slice(&[1, 2, 3]);
+        buf.set_len(3);
+        assert_eq!(&buf[..], &[1, 2, 3]);
+        buf[0] = 9;
+        assert_eq!(&buf[..], &[9, 2, 3]);
+        buf.clear();
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_packet_stream() {
+        task::block_on(async {
+            let mut w = Cursor::new(Vec::new());
+            w.write_packet(b"hello").await.unwrap();
+            w.write_packet(&[]).await.unwrap();
+            w.write_packet(&[7u8; 65535]).await.unwrap();
+            assert_eq!(
+                w.write_packet(&[0u8; 65536]).await.unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+
+            let mut r = Cursor::new(w.into_inner());
+            let mut buf = Buffer::new();
+            assert_eq!(r.read_packet(&mut buf).await.unwrap(), Some(5));
+            assert_eq!(&buf[..], b"hello");
+            assert_eq!(r.read_packet(&mut buf).await.unwrap(), Some(0));
+            assert!(buf.is_empty());
+            assert_eq!(r.read_packet(&mut buf).await.unwrap(), Some(65535));
+            assert_eq!(&buf[..], &[7u8; 65535][..]);
+            assert_eq!(r.read_packet(&mut buf).await.unwrap(), None);
+
+            let mut truncated = Cursor::new(b"\x00\x05hel".to_vec());
+            assert_eq!(
+                truncated.read_packet(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof
+            );
+        });
+    }
+
+    #[test]
+    fn test_datagram() {
+        task::block_on(async {
+            let (a, b) = new_udp_pair().unwrap();
+            let mut buf = Buffer::new();
+            a.send_all(&[1, 2, 3, 4]).await.unwrap();
+            assert_eq!(b.recv_buf(&mut buf).await.unwrap(), 4);
+            assert_eq!(&buf[..], &[1, 2, 3, 4]);
+            b.send_all_to(&[5; 1000], a.local_addr().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                a.recv_buf_from(&mut buf).await.unwrap(),
+                b.local_addr().unwrap()
+            );
+            assert_eq!(&buf[..], &[5; 1000][..]);
+        });
+    }
+}
@@ -0,0 +1,435 @@
+use crate::{BytesExt, PacketError};
+use bytes::{BufMut, BytesMut};
+use std::net::Ipv4Addr;
+
+pub const IPV4_HEADER_LEN: usize = 20;
+
+pub const IPV4_MAX_HEADER_LEN: usize = 60;
+
+/// Zero-copy view over an IPv4 packet.
+#[derive(Debug, Clone)]
+pub struct Ipv4Packet<T: AsRef<[u8]>> {
+    buf: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv4Packet<T> {
+    /// Wraps `buf` without looking at it. Accessors may panic on a malformed buffer.
+    pub fn new_unchecked(buf: T) -> Self {
+        Ipv4Packet { buf }
+    }
+
+    /// Wraps `buf` after checking the version, header length and total length against it.
+    pub fn new(buf: T) -> Result<Self, PacketError> {
+        let packet = Ipv4Packet { buf };
+        packet.check()?;
+        Ok(packet)
+    }
+
+    fn check(&self) -> Result<(), PacketError> {
+        let b = self.buf.as_ref();
+        if b.len() < IPV4_HEADER_LEN {
+            return Err(PacketError::Truncated);
+        }
+        if self.version() != 4 {
+            return Err(PacketError::Version(self.version()));
+        }
+        let header_len = self.header_len();
+        if header_len < IPV4_HEADER_LEN {
+            return Err(PacketError::HeaderLen(header_len));
+        }
+        if header_len > b.len() {
+            return Err(PacketError::Truncated);
+        }
+        let total_len = self.total_len() as usize;
+        if total_len < header_len {
+            return Err(PacketError::TotalLen(total_len));
+        }
+        if total_len > b.len() {
+            return Err(PacketError::Truncated);
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buf
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf.as_ref()[..self.total_len() as usize]
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buf.as_ref()[0] >> 4
+    }
+
+    pub fn header_len(&self) -> usize {
+        ((self.buf.as_ref()[0] & 0x0f) as usize) << 2
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.buf.as_ref()[1] >> 2
+    }
+
+    pub fn ecn(&self) -> u8 {
+        self.buf.as_ref()[1] & 0x03
+    }
+
+    pub fn total_len(&self) -> u16 {
+        self.buf.as_ref()[2..4].u16()
+    }
+
+    pub fn identification(&self) -> u16 {
+        self.buf.as_ref()[4..6].u16()
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.buf.as_ref()[6] & 0x40 != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.buf.as_ref()[6] & 0x20 != 0
+    }
+
+    /// Fragment offset in bytes.
+    pub fn fragment_offset(&self) -> u16 {
+        (self.buf.as_ref()[6..8].u16() & 0x1fff) << 3
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.buf.as_ref()[8]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.buf.as_ref()[9]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.buf.as_ref()[10..12].u16()
+    }
+
+    pub fn src_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.buf.as_ref()[12..16].u32())
+    }
+
+    pub fn dst_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.buf.as_ref()[16..20].u32())
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.buf.as_ref()[..self.header_len()]
+    }
+
+    pub fn options(&self) -> &[u8] {
+        &self.buf.as_ref()[IPV4_HEADER_LEN..self.header_len()]
+    }
+
+    /// Payload up to the total length; trailing bytes in the buffer are ignored.
+    pub fn payload(&self) -> &[u8] {
+        &self.buf.as_ref()[self.header_len()..self.total_len() as usize]
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        self.header().checksum() == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv4Packet<T> {
+    pub fn set_dscp(&mut self, dscp: u8) {
+        let b = self.buf.as_mut();
+        b[1] = (b[1] & 0x03) | (dscp << 2);
+    }
+
+    pub fn set_ecn(&mut self, ecn: u8) {
+        let b = self.buf.as_mut();
+        b[1] = (b[1] & !0x03) | (ecn & 0x03);
+    }
+
+    pub fn set_total_len(&mut self, len: u16) {
+        self.buf.as_mut()[2..4].copy_from_slice(&len.to_be_bytes());
+    }
+
+    pub fn set_identification(&mut self, id: u16) {
+        self.buf.as_mut()[4..6].copy_from_slice(&id.to_be_bytes());
+    }
+
+    pub fn set_more_fragments(&mut self, mf: bool) {
+        let b = self.buf.as_mut();
+        b[6] = if mf { b[6] | 0x20 } else { b[6] & !0x20 };
+    }
+
+    /// Fragment offset in bytes, must be a multiple of 8.
+    pub fn set_fragment_offset(&mut self, offset: u16) {
+        let b = self.buf.as_mut();
+        let flags = b[6] & 0xe0;
+        b[6..8].copy_from_slice(&((offset >> 3) & 0x1fff).to_be_bytes());
+        b[6] |= flags;
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.buf.as_mut()[8] = ttl;
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.buf.as_mut()[9] = protocol;
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.buf.as_mut()[10..12].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    pub fn set_src_addr(&mut self, addr: Ipv4Addr) {
+        self.buf.as_mut()[12..16].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_dst_addr(&mut self, addr: Ipv4Addr) {
+        self.buf.as_mut()[16..20].copy_from_slice(&addr.octets());
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.header_len(), self.total_len() as usize);
+        &mut self.buf.as_mut()[start..end]
+    }
+
+    /// Recomputes the header checksum after the header has been modified.
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0);
+        let checksum = self.header().checksum();
+        self.set_checksum(checksum);
+    }
+}
+
+/// Builder for IPv4 packets, see [`Ipv4Packet`] for the field meanings.
+#[derive(Debug, Clone)]
+pub struct Ipv4Builder {
+    dscp: u8,
+    ecn: u8,
+    identification: u16,
+    dont_fragment: bool,
+    more_fragments: bool,
+    fragment_offset: u16,
+    ttl: u8,
+    protocol: u8,
+    src_addr: Ipv4Addr,
+    dst_addr: Ipv4Addr,
+    options: Vec<u8>,
+}
+
+impl Ipv4Builder {
+    pub fn new(src_addr: Ipv4Addr, dst_addr: Ipv4Addr, protocol: u8) -> Self {
+        Ipv4Builder {
+            dscp: 0,
+            ecn: 0,
+            identification: 0,
+            dont_fragment: false,
+            more_fragments: false,
+            fragment_offset: 0,
+            ttl: 64,
+            protocol,
+            src_addr,
+            dst_addr,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.dscp = dscp & 0x3f;
+        self
+    }
+
+    pub fn ecn(mut self, ecn: u8) -> Self {
+        self.ecn = ecn & 0x03;
+        self
+    }
+
+    pub fn identification(mut self, id: u16) -> Self {
+        self.identification = id;
+        self
+    }
+
+    pub fn dont_fragment(mut self, df: bool) -> Self {
+        self.dont_fragment = df;
+        self
+    }
+
+    pub fn more_fragments(mut self, mf: bool) -> Self {
+        self.more_fragments = mf;
+        self
+    }
+
+    /// Fragment offset in bytes, must be a multiple of 8.
+    pub fn fragment_offset(mut self, offset: u16) -> Self {
+        self.fragment_offset = offset;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u8) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Raw option bytes, must be padded to a multiple of 4 and at most 40 bytes.
+    pub fn options(mut self, options: &[u8]) -> Self {
+        self.options = options.to_vec();
+        self
+    }
+
+    pub fn header_len(&self) -> usize {
+        IPV4_HEADER_LEN + self.options.len()
+    }
+
+    /// Writes the header followed by `payload`, filling in the header checksum.
+    pub fn emit(&self, payload: &[u8]) -> Result<BytesMut, PacketError> {
+        let header_len = self.header_len();
+        if !self.options.len().is_multiple_of(4) || header_len > IPV4_MAX_HEADER_LEN {
+            return Err(PacketError::Options(self.options.len()));
+        }
+        let total_len = header_len + payload.len();
+        if total_len > u16::MAX as usize {
+            return Err(PacketError::TooLarge(total_len));
+        }
+        let mut flags = (self.fragment_offset >> 3) & 0x1fff;
+        if self.dont_fragment {
+            flags |= 0x4000;
+        }
+        if self.more_fragments {
+            flags |= 0x2000;
+        }
+        let mut buf = BytesMut::with_capacity(total_len);
+        buf.put_u8(0x40 | (header_len >> 2) as u8);
+        buf.put_u8((self.dscp << 2) | self.ecn);
+        buf.put_u16(total_len as u16);
+        buf.put_u16(self.identification);
+        buf.put_u16(flags);
+        buf.put_u8(self.ttl);
+        buf.put_u8(self.protocol);
+        buf.put_u16(0);
+        buf.put_slice(&self.src_addr.octets());
+        buf.put_slice(&self.dst_addr.octets());
+        buf.put_slice(&self.options);
+        let checksum = buf[..header_len].checksum();
+        buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+        buf.put_slice(payload);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IP_PROTO_UDP;
+
+    fn sample() -> Vec<u8> {
+        let mut b = hex::decode("45000073000040004011b861c0a80001c0a800c7").unwrap();
+        b.resize(0x73, 0xab);
+        b
+    }
+
+    #[test]
+    fn test_ipv4_parse() {
+        let b = sample();
+        let p = Ipv4Packet::new(&b[..]).unwrap();
+        assert_eq!(p.version(), 4);
+        assert_eq!(p.header_len(), 20);
+        assert_eq!(p.total_len(), 0x73);
+        assert!(p.dont_fragment());
+        assert!(!p.more_fragments());
+        assert_eq!(p.fragment_offset(), 0);
+        assert_eq!(p.ttl(), 64);
+        assert_eq!(p.protocol(), IP_PROTO_UDP);
+        assert_eq!(p.checksum(), 0xb861);
+        assert_eq!(p.src_addr(), Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(p.dst_addr(), Ipv4Addr::new(192, 168, 0, 199));
+        assert_eq!(p.payload().len(), 0x73 - 20);
+        assert!(p.verify_checksum());
+    }
+
+    #[test]
+    fn test_ipv4_errors() {
+        let b = sample();
+        assert_eq!(
+            Ipv4Packet::new(&b[..19]).unwrap_err(),
+            PacketError::Truncated
+        );
+        assert_eq!(
+            Ipv4Packet::new(&b[..0x72]).unwrap_err(),
+            PacketError::Truncated
+        );
+        let mut v6 = b.clone();
+        v6[0] = 0x65;
+        assert_eq!(
+            Ipv4Packet::new(&v6[..]).unwrap_err(),
+            PacketError::Version(6)
+        );
+        let mut ihl = b.clone();
+        ihl[0] = 0x44;
+        assert_eq!(
+            Ipv4Packet::new(&ihl[..]).unwrap_err(),
+            PacketError::HeaderLen(16)
+        );
+        let mut total = b;
+        total[2..4].copy_from_slice(&[0, 10]);
+        assert_eq!(
+            Ipv4Packet::new(&total[..]).unwrap_err(),
+            PacketError::TotalLen(10)
+        );
+    }
+
+    #[test]
+    fn test_ipv4_trailing_bytes() {
+        let mut b = sample();
+        b.extend_from_slice(&[0; 6]);
+        let p = Ipv4Packet::new(&b[..]).unwrap();
+        assert_eq!(p.payload().len(), 0x73 - 20);
+        assert_eq!(p.as_bytes().len(), 0x73);
+    }
+
+    #[test]
+    fn test_ipv4_build() {
+        let b = sample();
+        let built = Ipv4Builder::new(
+            Ipv4Addr::new(192, 168, 0, 1),
+            Ipv4Addr::new(192, 168, 0, 199),
+            IP_PROTO_UDP,
+        )
+        .dont_fragment(true)
+        .emit(&b[20..])
+        .unwrap();
+        assert_eq!(&built[..], &b[..]);
+
+        let built = Ipv4Builder::new(Ipv4Addr::LOCALHOST, Ipv4Addr::BROADCAST, 253)
+            .identification(0x1234)
+            .more_fragments(true)
+            .fragment_offset(1480)
+            .options(&[1, 1, 1, 0])
+            .emit(&[1, 2, 3])
+            .unwrap();
+        let p = Ipv4Packet::new(&built[..]).unwrap();
+        assert_eq!(p.header_len(), 24);
+        assert_eq!(p.options(), &[1, 1, 1, 0]);
+        assert_eq!(p.identification(), 0x1234);
+        assert!(p.more_fragments());
+        assert_eq!(p.fragment_offset(), 1480);
+        assert_eq!(p.payload(), &[1, 2, 3]);
+        assert!(p.verify_checksum());
+
+        assert_eq!(
+            Ipv4Builder::new(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, 0)
+                .options(&[1, 1])
+                .emit(&[])
+                .unwrap_err(),
+            PacketError::Options(2)
+        );
+    }
+
+    #[test]
+    fn test_ipv4_rewrite() {
+        let mut b = sample();
+        let mut p = Ipv4Packet::new(&mut b[..]).unwrap();
+        p.set_src_addr(Ipv4Addr::new(10, 0, 0, 1));
+        p.set_ttl(1);
+        assert!(!p.verify_checksum());
+        p.fill_checksum();
+        assert!(p.verify_checksum());
+        assert_eq!(p.src_addr(), Ipv4Addr::new(10, 0, 0, 1));
+    }
+}
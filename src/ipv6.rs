@@ -0,0 +1,513 @@
+use crate::{
+    BytesExt, PacketError, IP_PROTO_HOPOPT, IP_PROTO_IPV6_FRAG, IP_PROTO_IPV6_OPTS,
+    IP_PROTO_IPV6_ROUTE,
+};
+use bytes::{BufMut, BytesMut};
+use std::net::Ipv6Addr;
+
+pub const IPV6_HEADER_LEN: usize = 40;
+
+const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+
+fn is_extension_header(next_header: u8) -> bool {
+    matches!(
+        next_header,
+        IP_PROTO_HOPOPT | IP_PROTO_IPV6_ROUTE | IP_PROTO_IPV6_FRAG | IP_PROTO_IPV6_OPTS
+    )
+}
+
+/// One extension header found between the fixed header and the upper-layer payload.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Ipv6ExtHeader<'a> {
+    /// Protocol number identifying this header.
+    pub kind: u8,
+    /// Protocol number of the header that follows.
+    pub next_header: u8,
+    /// The whole header, including the next header and length bytes.
+    pub data: &'a [u8],
+}
+
+/// Fields of an IPv6 fragment extension header.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Ipv6Fragment {
+    /// Fragment offset in bytes.
+    pub offset: u16,
+    pub more_fragments: bool,
+    pub identification: u32,
+}
+
+impl<'a> Ipv6ExtHeader<'a> {
+    pub fn fragment(&self) -> Option<Ipv6Fragment> {
+        if self.kind != IP_PROTO_IPV6_FRAG {
+            return None;
+        }
+        let flags = self.data[2..4].u16();
+        Some(Ipv6Fragment {
+            offset: flags & !0x7,
+            more_fragments: flags & 0x1 != 0,
+            identification: self.data[4..8].u32(),
+        })
+    }
+}
+
+/// Iterator over the extension headers of an [`Ipv6Packet`].
+#[derive(Debug, Clone)]
+pub struct Ipv6ExtHeaders<'a> {
+    buf: &'a [u8],
+    next_header: u8,
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Ipv6ExtHeaders<'a> {
+    /// Protocol number of the header following the last yielded one.
+    pub fn next_header(&self) -> u8 {
+        self.next_header
+    }
+
+    /// Offset of the header following the last yielded one.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for Ipv6ExtHeaders<'a> {
+    type Item = Result<Ipv6ExtHeader<'a>, PacketError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || !is_extension_header(self.next_header) {
+            return None;
+        }
+        let rest = &self.buf[self.offset..];
+        if rest.len() < 2 {
+            self.done = true;
+            return Some(Err(PacketError::Truncated));
+        }
+        let len = match self.next_header {
+            IP_PROTO_IPV6_FRAG => IPV6_FRAGMENT_HEADER_LEN,
+            _ => (rest[1] as usize + 1) << 3,
+        };
+        if rest.len() < len {
+            self.done = true;
+            return Some(Err(PacketError::Truncated));
+        }
+        let header = Ipv6ExtHeader {
+            kind: self.next_header,
+            next_header: rest[0],
+            data: &rest[..len],
+        };
+        self.next_header = header.next_header;
+        self.offset += len;
+        // Only the first fragment carries the upper-layer header.
+        if let Some(fragment) = header.fragment() {
+            if fragment.offset != 0 {
+                self.done = true;
+            }
+        }
+        Some(Ok(header))
+    }
+}
+
+/// Zero-copy view over an IPv6 packet.
+#[derive(Debug, Clone)]
+pub struct Ipv6Packet<T: AsRef<[u8]>> {
+    buf: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv6Packet<T> {
+    /// Wraps `buf` without looking at it. Accessors may panic on a malformed buffer.
+    pub fn new_unchecked(buf: T) -> Self {
+        Ipv6Packet { buf }
+    }
+
+    /// Wraps `buf` after checking the version, payload length and extension header chain.
+    pub fn new(buf: T) -> Result<Self, PacketError> {
+        let packet = Ipv6Packet { buf };
+        packet.check()?;
+        Ok(packet)
+    }
+
+    fn check(&self) -> Result<(), PacketError> {
+        let b = self.buf.as_ref();
+        if b.len() < IPV6_HEADER_LEN {
+            return Err(PacketError::Truncated);
+        }
+        if self.version() != 6 {
+            return Err(PacketError::Version(self.version()));
+        }
+        if IPV6_HEADER_LEN + self.payload_len() as usize > b.len() {
+            return Err(PacketError::Truncated);
+        }
+        for header in self.extension_headers() {
+            header?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buf
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf.as_ref()[..IPV6_HEADER_LEN + self.payload_len() as usize]
+    }
+
+    pub fn version(&self) -> u8 {
+        self.buf.as_ref()[0] >> 4
+    }
+
+    pub fn traffic_class(&self) -> u8 {
+        (self.buf.as_ref()[0..2].u16() >> 4) as u8
+    }
+
+    pub fn flow_label(&self) -> u32 {
+        self.buf.as_ref()[0..4].u32() & 0x000f_ffff
+    }
+
+    pub fn payload_len(&self) -> u16 {
+        self.buf.as_ref()[4..6].u16()
+    }
+
+    /// Protocol number of the first header after the fixed header.
+    pub fn next_header(&self) -> u8 {
+        self.buf.as_ref()[6]
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.buf.as_ref()[7]
+    }
+
+    pub fn src_addr(&self) -> Ipv6Addr {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&self.buf.as_ref()[8..24]);
+        Ipv6Addr::from(octets)
+    }
+
+    pub fn dst_addr(&self) -> Ipv6Addr {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&self.buf.as_ref()[24..40]);
+        Ipv6Addr::from(octets)
+    }
+
+    /// Everything after the fixed header, extension headers included.
+    pub fn payload(&self) -> &[u8] {
+        &self.as_bytes()[IPV6_HEADER_LEN..]
+    }
+
+    pub fn extension_headers(&self) -> Ipv6ExtHeaders<'_> {
+        Ipv6ExtHeaders {
+            buf: self.as_bytes(),
+            next_header: self.next_header(),
+            offset: IPV6_HEADER_LEN,
+            done: false,
+        }
+    }
+
+    fn walk(&self) -> (u8, usize) {
+        let mut headers = self.extension_headers();
+        while let Some(Ok(_)) = headers.next() {}
+        (headers.next_header(), headers.offset())
+    }
+
+    /// Upper-layer protocol number after skipping the extension headers.
+    pub fn protocol(&self) -> u8 {
+        self.walk().0
+    }
+
+    /// Offset of the upper-layer payload from the start of the packet.
+    pub fn upper_layer_offset(&self) -> usize {
+        self.walk().1
+    }
+
+    pub fn upper_layer_payload(&self) -> &[u8] {
+        &self.as_bytes()[self.upper_layer_offset()..]
+    }
+
+    pub fn fragment(&self) -> Option<Ipv6Fragment> {
+        self.extension_headers()
+            .filter_map(|header| header.ok())
+            .find_map(|header| header.fragment())
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv6Packet<T> {
+    pub fn set_traffic_class(&mut self, traffic_class: u8) {
+        let b = self.buf.as_mut();
+        b[0] = (b[0] & 0xf0) | (traffic_class >> 4);
+        b[1] = (b[1] & 0x0f) | (traffic_class << 4);
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.buf.as_mut()[7] = hop_limit;
+    }
+
+    pub fn set_src_addr(&mut self, addr: Ipv6Addr) {
+        self.buf.as_mut()[8..24].copy_from_slice(&addr.octets());
+    }
+
+    pub fn set_dst_addr(&mut self, addr: Ipv6Addr) {
+        self.buf.as_mut()[24..40].copy_from_slice(&addr.octets());
+    }
+
+    pub fn upper_layer_payload_mut(&mut self) -> &mut [u8] {
+        let end = IPV6_HEADER_LEN + self.payload_len() as usize;
+        let start = self.upper_layer_offset();
+        &mut self.buf.as_mut()[start..end]
+    }
+}
+
+/// Builder for IPv6 packets, see [`Ipv6Packet`] for the field meanings.
+#[derive(Debug, Clone)]
+pub struct Ipv6Builder {
+    traffic_class: u8,
+    flow_label: u32,
+    hop_limit: u8,
+    protocol: u8,
+    src_addr: Ipv6Addr,
+    dst_addr: Ipv6Addr,
+    extensions: Vec<(u8, Vec<u8>)>,
+}
+
+impl Ipv6Builder {
+    pub fn new(src_addr: Ipv6Addr, dst_addr: Ipv6Addr, protocol: u8) -> Self {
+        Ipv6Builder {
+            traffic_class: 0,
+            flow_label: 0,
+            hop_limit: 64,
+            protocol,
+            src_addr,
+            dst_addr,
+            extensions: Vec::new(),
+        }
+    }
+
+    pub fn traffic_class(mut self, traffic_class: u8) -> Self {
+        self.traffic_class = traffic_class;
+        self
+    }
+
+    pub fn flow_label(mut self, flow_label: u32) -> Self {
+        self.flow_label = flow_label & 0x000f_ffff;
+        self
+    }
+
+    pub fn hop_limit(mut self, hop_limit: u8) -> Self {
+        self.hop_limit = hop_limit;
+        self
+    }
+
+    /// Appends an extension header. `body` is everything after the next header
+    /// and length bytes, and must make the header a multiple of 8 bytes long.
+    pub fn extension(mut self, kind: u8, body: &[u8]) -> Self {
+        self.extensions.push((kind, body.to_vec()));
+        self
+    }
+
+    /// Appends a fragment header, `offset` is in bytes and must be a multiple of 8.
+    pub fn fragment(self, offset: u16, more_fragments: bool, identification: u32) -> Self {
+        let mut body = Vec::with_capacity(6);
+        body.extend_from_slice(&((offset & !0x7) | more_fragments as u16).to_be_bytes());
+        body.extend_from_slice(&identification.to_be_bytes());
+        self.extension(IP_PROTO_IPV6_FRAG, &body)
+    }
+
+    pub fn header_len(&self) -> usize {
+        IPV6_HEADER_LEN
+            + self
+                .extensions
+                .iter()
+                .map(|(_, body)| body.len() + 2)
+                .sum::<usize>()
+    }
+
+    /// Writes the fixed header and extension headers followed by `payload`.
+    pub fn emit(&self, payload: &[u8]) -> Result<BytesMut, PacketError> {
+        for (kind, body) in &self.extensions {
+            let len = body.len() + 2;
+            let valid = match *kind {
+                IP_PROTO_IPV6_FRAG => len == IPV6_FRAGMENT_HEADER_LEN,
+                _ => len.is_multiple_of(8) && len <= 256 * 8,
+            };
+            if !valid {
+                return Err(PacketError::Options(len));
+            }
+        }
+        let header_len = self.header_len();
+        let payload_len = header_len - IPV6_HEADER_LEN + payload.len();
+        if payload_len > u16::MAX as usize {
+            return Err(PacketError::TooLarge(payload_len));
+        }
+        let mut buf = BytesMut::with_capacity(header_len + payload.len());
+        buf.put_u32(
+            (6 << 28) | ((self.traffic_class as u32) << 20) | (self.flow_label & 0x000f_ffff),
+        );
+        buf.put_u16(payload_len as u16);
+        buf.put_u8(
+            self.extensions
+                .first()
+                .map_or(self.protocol, |(kind, _)| *kind),
+        );
+        buf.put_u8(self.hop_limit);
+        buf.put_slice(&self.src_addr.octets());
+        buf.put_slice(&self.dst_addr.octets());
+        for (i, (kind, body)) in self.extensions.iter().enumerate() {
+            buf.put_u8(
+                self.extensions
+                    .get(i + 1)
+                    .map_or(self.protocol, |(kind, _)| *kind),
+            );
+            buf.put_u8(match *kind {
+                IP_PROTO_IPV6_FRAG => 0,
+                _ => ((body.len() + 2) / 8 - 1) as u8,
+            });
+            buf.put_slice(body);
+        }
+        buf.put_slice(payload);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IP_PROTO_ICMPV6, IP_PROTO_TCP, IP_PROTO_UDP};
+
+    fn addrs() -> (Ipv6Addr, Ipv6Addr) {
+        (
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_ipv6_parse() {
+        let b = hex::decode(
+            "6e012345000811402001\
+             0db8000000000000000000000001\
+             20010db8000000000000000000000002\
+             1f9000350008abcd",
+        )
+        .unwrap();
+        let p = Ipv6Packet::new(&b[..]).unwrap();
+        let (src, dst) = addrs();
+        assert_eq!(p.version(), 6);
+        assert_eq!(p.traffic_class(), 0xe0);
+        assert_eq!(p.flow_label(), 0x12345);
+        assert_eq!(p.payload_len(), 8);
+        assert_eq!(p.next_header(), IP_PROTO_UDP);
+        assert_eq!(p.hop_limit(), 64);
+        assert_eq!(p.src_addr(), src);
+        assert_eq!(p.dst_addr(), dst);
+        assert_eq!(p.protocol(), IP_PROTO_UDP);
+        assert_eq!(p.upper_layer_offset(), IPV6_HEADER_LEN);
+        assert_eq!(p.upper_layer_payload(), &b[40..]);
+        assert_eq!(p.extension_headers().count(), 0);
+        assert_eq!(p.fragment(), None);
+    }
+
+    #[test]
+    fn test_ipv6_extension_headers() {
+        let (src, dst) = addrs();
+        let b = Ipv6Builder::new(src, dst, IP_PROTO_TCP)
+            .extension(IP_PROTO_HOPOPT, &[1, 4, 0, 0, 0, 0])
+            .extension(IP_PROTO_IPV6_ROUTE, &[0, 0, 0, 0, 0, 0])
+            .fragment(0, true, 0xdeadbeef)
+            .extension(
+                IP_PROTO_IPV6_OPTS,
+                &[1, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            )
+            .emit(&[9; 20])
+            .unwrap();
+        let p = Ipv6Packet::new(&b[..]).unwrap();
+        assert_eq!(p.next_header(), IP_PROTO_HOPOPT);
+        let kinds: Vec<u8> = p.extension_headers().map(|h| h.unwrap().kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                IP_PROTO_HOPOPT,
+                IP_PROTO_IPV6_ROUTE,
+                IP_PROTO_IPV6_FRAG,
+                IP_PROTO_IPV6_OPTS
+            ]
+        );
+        assert_eq!(p.protocol(), IP_PROTO_TCP);
+        assert_eq!(p.upper_layer_offset(), IPV6_HEADER_LEN + 8 + 8 + 8 + 16);
+        assert_eq!(p.upper_layer_payload(), &[9; 20]);
+        assert_eq!(
+            p.fragment(),
+            Some(Ipv6Fragment {
+                offset: 0,
+                more_fragments: true,
+                identification: 0xdeadbeef
+            })
+        );
+    }
+
+    #[test]
+    fn test_ipv6_non_first_fragment() {
+        let (src, dst) = addrs();
+        let b = Ipv6Builder::new(src, dst, IP_PROTO_ICMPV6)
+            .fragment(1232, false, 7)
+            .emit(&[0; 8])
+            .unwrap();
+        let p = Ipv6Packet::new(&b[..]).unwrap();
+        assert_eq!(p.protocol(), IP_PROTO_ICMPV6);
+        assert_eq!(p.upper_layer_offset(), IPV6_HEADER_LEN + 8);
+        assert_eq!(p.fragment().unwrap().offset, 1232);
+    }
+
+    #[test]
+    fn test_ipv6_errors() {
+        let (src, dst) = addrs();
+        let b = Ipv6Builder::new(src, dst, IP_PROTO_UDP)
+            .extension(IP_PROTO_IPV6_OPTS, &[1, 4, 0, 0, 0, 0])
+            .emit(&[])
+            .unwrap();
+        assert_eq!(
+            Ipv6Packet::new(&b[..39]).unwrap_err(),
+            PacketError::Truncated
+        );
+        assert_eq!(
+            Ipv6Packet::new(&b[..47]).unwrap_err(),
+            PacketError::Truncated
+        );
+        let mut v4 = b.to_vec();
+        v4[0] = 0x45;
+        assert_eq!(
+            Ipv6Packet::new(&v4[..]).unwrap_err(),
+            PacketError::Version(4)
+        );
+        let mut ext = b.to_vec();
+        ext[41] = 1;
+        assert_eq!(
+            Ipv6Packet::new(&ext[..]).unwrap_err(),
+            PacketError::Truncated
+        );
+        assert_eq!(
+            Ipv6Builder::new(src, dst, IP_PROTO_UDP)
+                .extension(IP_PROTO_IPV6_OPTS, &[0; 3])
+                .emit(&[])
+                .unwrap_err(),
+            PacketError::Options(5)
+        );
+    }
+
+    #[test]
+    fn test_ipv6_rewrite() {
+        let (src, dst) = addrs();
+        let mut b = Ipv6Builder::new(src, dst, IP_PROTO_UDP)
+            .traffic_class(0xb8)
+            .emit(&[1, 2, 3, 4])
+            .unwrap();
+        let mut p = Ipv6Packet::new(&mut b[..]).unwrap();
+        assert_eq!(p.traffic_class(), 0xb8);
+        p.set_hop_limit(1);
+        p.set_src_addr(Ipv6Addr::LOCALHOST);
+        p.set_traffic_class(0x04);
+        p.upper_layer_payload_mut()[0] = 9;
+        assert_eq!(p.hop_limit(), 1);
+        assert_eq!(p.src_addr(), Ipv6Addr::LOCALHOST);
+        assert_eq!(p.traffic_class(), 0x04);
+        assert_eq!(p.upper_layer_payload(), &[9, 2, 3, 4]);
+    }
+}
@@ -1,5 +1,3 @@
-#![feature(int_bits_const)]
-#![feature(async_closure)]
 #[macro_use]
 mod macros;
 
@@ -7,11 +5,44 @@ mod macros;
 mod constant;
 
 pub use bytes::*;
+mod device;
+mod dns;
 mod ext;
+mod fragment;
+mod frame;
+mod icmp;
 mod io;
+mod ipv4;
+mod ipv6;
+mod nat;
 mod net;
+mod packet;
+mod ping;
+mod relay;
+mod stack;
+mod tcp;
+#[cfg(target_os = "linux")]
+mod tun;
+mod udp;
+mod udp_over_tcp;
 
+pub use device::*;
+pub use dns::*;
 pub use ext::*;
+pub use fragment::*;
+pub use frame::*;
+pub use icmp::*;
 pub use io::*;
-pub use macros::*;
+pub use ipv4::*;
+pub use ipv6::*;
+pub use nat::*;
 pub use net::*;
+pub use packet::*;
+pub use ping::*;
+pub use relay::*;
+pub use stack::*;
+pub use tcp::*;
+#[cfg(target_os = "linux")]
+pub use tun::*;
+pub use udp::*;
+pub use udp_over_tcp::*;
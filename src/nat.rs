@@ -0,0 +1,983 @@
+use crate::{
+    BytesExt, BytesMutExt, ChecksumExt, IcmpPacket, Ipv4Packet, Ipv6Packet, PacketError,
+    ICMPV4_DEST_UNREACHABLE, ICMPV4_ECHO_REPLY, ICMPV4_ECHO_REQUEST, ICMPV4_PARAMETER_PROBLEM,
+    ICMPV4_TIME_EXCEEDED, ICMPV6_DEST_UNREACHABLE, ICMPV6_ECHO_REPLY, ICMPV6_ECHO_REQUEST,
+    ICMPV6_PACKET_TOO_BIG, ICMPV6_PARAMETER_PROBLEM, ICMPV6_TIME_EXCEEDED, ICMP_HEADER_LEN,
+    IPV4_HEADER_LEN, IPV6_HEADER_LEN, IP_PROTO_ICMP, IP_PROTO_ICMPV6, IP_PROTO_TCP, IP_PROTO_UDP,
+    TCP_FIN, TCP_HEADER_LEN, TCP_RST, UDP_HEADER_LEN,
+};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// Idle timeout of established TCP mappings, RFC 5382 REQ-5.
+pub const NAT_TCP_TIMEOUT: Duration = Duration::from_secs(2 * 3600 + 4 * 60);
+/// Idle timeout of TCP mappings while opening or closing, RFC 5382 REQ-5.
+pub const NAT_TCP_TRANSITORY_TIMEOUT: Duration = Duration::from_secs(4 * 60);
+/// Idle timeout of UDP mappings, RFC 4787 REQ-5.
+pub const NAT_UDP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Idle timeout of ICMP query mappings, RFC 5508 REQ-1.
+pub const NAT_ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// One translation of an internal endpoint to an external one.
+///
+/// For ICMP the ports are the echo identifiers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct NatMapping {
+    pub protocol: u8,
+    pub internal: SocketAddr,
+    pub external: SocketAddr,
+    /// When the mapping expires unless more packets refresh it.
+    pub expires: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    mapping: NatMapping,
+    seen_egress: bool,
+    seen_ingress: bool,
+    closing: bool,
+}
+
+type Key = (u8, SocketAddr);
+
+/// Source NAT for IPv4 and IPv6 packets, masquerading internal hosts behind one public
+/// address per family.
+///
+/// [`egress`](Nat::egress) rewrites the source address and port (or ICMP echo identifier)
+/// of outgoing packets, [`ingress`](Nat::ingress) reverses it on replies, ICMP errors
+/// about translated packets included. Checksums are updated incrementally. Mappings are
+/// endpoint independent (RFC 4787): an internal endpoint keeps its external port whatever
+/// it talks to, for as long as it stays active.
+#[derive(Debug, Clone)]
+pub struct Nat {
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    port_range: RangeInclusive<u16>,
+    tcp_timeout: Duration,
+    tcp_transitory_timeout: Duration,
+    udp_timeout: Duration,
+    icmp_timeout: Duration,
+    entries: HashMap<Key, Entry>,
+    external: HashMap<Key, Key>,
+}
+
+impl Default for Nat {
+    fn default() -> Self {
+        Nat {
+            ipv4: None,
+            ipv6: None,
+            port_range: 1024..=65535,
+            tcp_timeout: NAT_TCP_TIMEOUT,
+            tcp_transitory_timeout: NAT_TCP_TRANSITORY_TIMEOUT,
+            udp_timeout: NAT_UDP_TIMEOUT,
+            icmp_timeout: NAT_ICMP_TIMEOUT,
+            entries: HashMap::new(),
+            external: HashMap::new(),
+        }
+    }
+}
+
+impl Nat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Public address of translated IPv4 packets.
+    pub fn ipv4(mut self, addr: Ipv4Addr) -> Self {
+        self.ipv4 = Some(addr);
+        self
+    }
+
+    /// Public address of translated IPv6 packets.
+    pub fn ipv6(mut self, addr: Ipv6Addr) -> Self {
+        self.ipv6 = Some(addr);
+        self
+    }
+
+    /// External ports and ICMP identifiers handed out; internal ones are kept when free.
+    pub fn port_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.port_range = range;
+        self
+    }
+
+    pub fn tcp_timeout(mut self, timeout: Duration) -> Self {
+        self.tcp_timeout = timeout;
+        self
+    }
+
+    pub fn tcp_transitory_timeout(mut self, timeout: Duration) -> Self {
+        self.tcp_transitory_timeout = timeout;
+        self
+    }
+
+    pub fn udp_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_timeout = timeout;
+        self
+    }
+
+    pub fn icmp_timeout(mut self, timeout: Duration) -> Self {
+        self.icmp_timeout = timeout;
+        self
+    }
+
+    /// Live mappings.
+    pub fn mappings(&self) -> impl Iterator<Item = NatMapping> + '_ {
+        let now = Instant::now();
+        self.entries
+            .values()
+            .map(|entry| entry.mapping)
+            .filter(move |mapping| mapping.expires > now)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.external.clear();
+    }
+
+    /// Drops expired mappings, returning how many there were.
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        let len = self.entries.len();
+        self.entries.retain(|_, entry| entry.mapping.expires > now);
+        let entries = &self.entries;
+        self.external
+            .retain(|_, internal| entries.contains_key(internal));
+        len - self.entries.len()
+    }
+
+    /// Translates a packet leaving the internal network, creating its mapping if needed.
+    ///
+    /// ICMP errors about packets that arrived through [`ingress`](Nat::ingress) are
+    /// translated too. Fails with `InvalidInput` for packets that cannot be translated:
+    /// fragments, which [`Ipv4Reassembler`](crate::Ipv4Reassembler) can join first, other
+    /// protocols and ICMP messages, or a family without public address.
+    /// Fails with `AddrNotAvailable` when the port range is exhausted.
+    pub fn egress(&mut self, packet: &mut [u8]) -> io::Result<()> {
+        let headers = Headers::parse(packet)?;
+        let public = self.public(headers.src)?;
+        let now = Instant::now();
+        match Transport::parse(packet, &headers, true)? {
+            Transport::Flow { port, tcp_flags } => {
+                let internal = SocketAddr::new(headers.src, port);
+                let external = self.map(headers.protocol, internal, public, now)?;
+                self.touch((headers.protocol, internal), true, tcp_flags, now);
+                rewrite(packet, &headers, Side::Src, external);
+            }
+            Transport::Error => {
+                // The quoted packet went from a remote host to the internal one.
+                let quoted = Headers::parse_quoted(packet, &headers)?;
+                let port = quoted_port(packet, &quoted, Side::Dst)?;
+                let internal = SocketAddr::new(quoted.dst, port);
+                let entry = self.lookup_internal((quoted.protocol, internal), now)?;
+                let external = entry.mapping.external;
+                rewrite(packet, &quoted, Side::Dst, external);
+                rewrite_addr(packet, &headers, Side::Src, external.ip());
+                fill_icmp_checksum(packet, &headers);
+            }
+        }
+        Ok(())
+    }
+
+    /// Translates a packet arriving at a public address back to the internal endpoint.
+    ///
+    /// Fails with `NotFound` when no live mapping matches, and with `InvalidInput` for
+    /// packets that cannot be translated.
+    pub fn ingress(&mut self, packet: &mut [u8]) -> io::Result<()> {
+        let headers = Headers::parse(packet)?;
+        if self.public(headers.dst)? != headers.dst {
+            return Err(no_mapping());
+        }
+        let now = Instant::now();
+        match Transport::parse(packet, &headers, false)? {
+            Transport::Flow { port, tcp_flags } => {
+                let external = SocketAddr::new(headers.dst, port);
+                let key = self.lookup_external((headers.protocol, external), now)?;
+                self.touch(key, false, tcp_flags, now);
+                rewrite(packet, &headers, Side::Dst, key.1);
+            }
+            Transport::Error => {
+                // The quoted packet went from the internal host, already translated.
+                let quoted = Headers::parse_quoted(packet, &headers)?;
+                if quoted.src != headers.dst {
+                    return Err(no_mapping());
+                }
+                let port = quoted_port(packet, &quoted, Side::Src)?;
+                let external = SocketAddr::new(quoted.src, port);
+                let (_, internal) = self.lookup_external((quoted.protocol, external), now)?;
+                rewrite(packet, &quoted, Side::Src, internal);
+                rewrite_addr(packet, &headers, Side::Dst, internal.ip());
+                fill_icmp_checksum(packet, &headers);
+            }
+        }
+        Ok(())
+    }
+
+    fn public(&self, addr: IpAddr) -> io::Result<IpAddr> {
+        match addr {
+            IpAddr::V4(_) => self.ipv4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.ipv6.map(IpAddr::V6),
+        }
+        .ok_or_else(|| untranslatable("no public address for the packet's family"))
+    }
+
+    fn map(
+        &mut self,
+        protocol: u8,
+        internal: SocketAddr,
+        public: IpAddr,
+        now: Instant,
+    ) -> io::Result<SocketAddr> {
+        if let Some(entry) = self.entries.get(&(protocol, internal)) {
+            return Ok(entry.mapping.external);
+        }
+        let external = self.allocate(protocol, internal.port(), public, now)?;
+        self.entries.insert(
+            (protocol, internal),
+            Entry {
+                mapping: NatMapping {
+                    protocol,
+                    internal,
+                    external,
+                    expires: now,
+                },
+                seen_egress: false,
+                seen_ingress: false,
+                closing: false,
+            },
+        );
+        self.external
+            .insert((protocol, external), (protocol, internal));
+        Ok(external)
+    }
+
+    /// Finds a free external port, starting from `preferred` (RFC 4787 REQ-3).
+    fn allocate(
+        &mut self,
+        protocol: u8,
+        preferred: u16,
+        public: IpAddr,
+        now: Instant,
+    ) -> io::Result<SocketAddr> {
+        let (low, high) = (
+            *self.port_range.start() as u32,
+            *self.port_range.end() as u32,
+        );
+        if low > high {
+            return Err(exhausted());
+        }
+        let span = high - low + 1;
+        let start = (preferred as u32).saturating_sub(low) % span;
+        for i in 0..span {
+            let port = (low + (start + i) % span) as u16;
+            let external = SocketAddr::new(public, port);
+            match self.external.get(&(protocol, external)) {
+                None => return Ok(external),
+                Some(internal) if self.entries[internal].mapping.expires <= now => {
+                    let internal = *internal;
+                    self.entries.remove(&internal);
+                    self.external.remove(&(protocol, external));
+                    return Ok(external);
+                }
+                Some(_) => {}
+            }
+        }
+        Err(exhausted())
+    }
+
+    fn lookup_internal(&self, key: Key, now: Instant) -> io::Result<&Entry> {
+        self.entries
+            .get(&key)
+            .filter(|entry| entry.mapping.expires > now)
+            .ok_or_else(no_mapping)
+    }
+
+    /// Returns the internal key of a live mapping.
+    fn lookup_external(&self, key: Key, now: Instant) -> io::Result<Key> {
+        let internal = *self.external.get(&key).ok_or_else(no_mapping)?;
+        self.lookup_internal(internal, now)?;
+        Ok(internal)
+    }
+
+    fn touch(&mut self, key: Key, egress: bool, tcp_flags: u8, now: Instant) {
+        let entry = self.entries.get_mut(&key).unwrap();
+        if egress {
+            entry.seen_egress = true;
+        } else {
+            entry.seen_ingress = true;
+        }
+        let timeout = match key.0 {
+            IP_PROTO_TCP => {
+                if tcp_flags & (TCP_FIN | TCP_RST) != 0 {
+                    entry.closing = true;
+                }
+                // Established once both directions got through, until a FIN or RST.
+                if entry.seen_egress && entry.seen_ingress && !entry.closing {
+                    self.tcp_timeout
+                } else {
+                    self.tcp_transitory_timeout
+                }
+            }
+            IP_PROTO_UDP => self.udp_timeout,
+            _ => self.icmp_timeout,
+        };
+        entry.mapping.expires = now + timeout;
+    }
+}
+
+fn untranslatable(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn no_mapping() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no nat mapping")
+}
+
+fn exhausted() -> io::Error {
+    io::Error::new(io::ErrorKind::AddrNotAvailable, "nat port range exhausted")
+}
+
+/// Where the addresses and the transport header of an IP packet are.
+struct Headers {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    /// Offset of the IP header in the packet.
+    ip: usize,
+    /// Offsets of the transport header and the end of the packet.
+    start: usize,
+    end: usize,
+}
+
+impl Headers {
+    fn parse(packet: &[u8]) -> io::Result<Self> {
+        match packet.first().map(|b| b >> 4) {
+            Some(4) => {
+                let ip = Ipv4Packet::new(packet)?;
+                if ip.more_fragments() || ip.fragment_offset() != 0 {
+                    return Err(untranslatable("fragmented packet"));
+                }
+                Ok(Headers {
+                    src: ip.src_addr().into(),
+                    dst: ip.dst_addr().into(),
+                    protocol: ip.protocol(),
+                    ip: 0,
+                    start: ip.header_len(),
+                    end: ip.total_len() as usize,
+                })
+            }
+            Some(6) => {
+                let ip = Ipv6Packet::new(packet)?;
+                if ip.fragment().is_some() {
+                    return Err(untranslatable("fragmented packet"));
+                }
+                Ok(Headers {
+                    src: ip.src_addr().into(),
+                    dst: ip.dst_addr().into(),
+                    protocol: ip.protocol(),
+                    ip: 0,
+                    start: ip.upper_layer_offset(),
+                    end: IPV6_HEADER_LEN + ip.payload_len() as usize,
+                })
+            }
+            Some(version) => Err(PacketError::Version(version).into()),
+            None => Err(PacketError::Truncated.into()),
+        }
+    }
+
+    /// Locates the packet quoted by the ICMP error in `outer`, which may be truncated.
+    fn parse_quoted(packet: &[u8], outer: &Headers) -> io::Result<Self> {
+        let ip = outer.start + ICMP_HEADER_LEN;
+        let quoted = &packet[ip..outer.end];
+        let headers = match (outer.src, quoted.first().map(|b| b >> 4)) {
+            (IpAddr::V4(_), Some(4)) if quoted.len() >= IPV4_HEADER_LEN => {
+                let header = Ipv4Packet::new_unchecked(quoted);
+                Headers {
+                    src: header.src_addr().into(),
+                    dst: header.dst_addr().into(),
+                    protocol: header.protocol(),
+                    ip,
+                    start: ip + header.header_len(),
+                    end: outer.end,
+                }
+            }
+            // Quoted extension headers are not followed.
+            (IpAddr::V6(_), Some(6)) if quoted.len() >= IPV6_HEADER_LEN => {
+                let header = Ipv6Packet::new_unchecked(quoted);
+                Headers {
+                    src: header.src_addr().into(),
+                    dst: header.dst_addr().into(),
+                    protocol: header.next_header(),
+                    ip,
+                    start: ip + IPV6_HEADER_LEN,
+                    end: outer.end,
+                }
+            }
+            _ => return Err(untranslatable("malformed icmp error")),
+        };
+        if headers.start < ip + IPV4_HEADER_LEN || headers.start + UDP_HEADER_LEN > headers.end {
+            return Err(untranslatable("malformed icmp error"));
+        }
+        Ok(headers)
+    }
+}
+
+enum Transport {
+    /// A TCP or UDP segment, or an ICMP echo, with the port or identifier to translate.
+    Flow { port: u16, tcp_flags: u8 },
+    /// An ICMP error quoting another packet.
+    Error,
+}
+
+impl Transport {
+    fn parse(packet: &[u8], headers: &Headers, egress: bool) -> io::Result<Self> {
+        let l4 = &packet[headers.start..headers.end];
+        let (port_offset, min_len) = match headers.protocol {
+            IP_PROTO_TCP => (if egress { 0 } else { 2 }, TCP_HEADER_LEN),
+            IP_PROTO_UDP => (if egress { 0 } else { 2 }, UDP_HEADER_LEN),
+            IP_PROTO_ICMP | IP_PROTO_ICMPV6 => {
+                let v6 = headers.protocol == IP_PROTO_ICMPV6;
+                if v6 != headers.src.is_ipv6() || l4.len() < ICMP_HEADER_LEN {
+                    return Err(untranslatable("malformed icmp message"));
+                }
+                let echo = match (v6, egress) {
+                    (false, true) => ICMPV4_ECHO_REQUEST,
+                    (false, false) => ICMPV4_ECHO_REPLY,
+                    (true, true) => ICMPV6_ECHO_REQUEST,
+                    (true, false) => ICMPV6_ECHO_REPLY,
+                };
+                return match (v6, l4[0]) {
+                    (_, msg_type) if msg_type == echo => Ok(Transport::Flow {
+                        port: l4[4..].u16(),
+                        tcp_flags: 0,
+                    }),
+                    (false, ICMPV4_DEST_UNREACHABLE)
+                    | (false, ICMPV4_TIME_EXCEEDED)
+                    | (false, ICMPV4_PARAMETER_PROBLEM)
+                    | (true, ICMPV6_DEST_UNREACHABLE)
+                    | (true, ICMPV6_PACKET_TOO_BIG)
+                    | (true, ICMPV6_TIME_EXCEEDED)
+                    | (true, ICMPV6_PARAMETER_PROBLEM) => Ok(Transport::Error),
+                    _ => Err(untranslatable("untranslatable icmp message")),
+                };
+            }
+            _ => return Err(untranslatable("untranslatable protocol")),
+        };
+        if l4.len() < min_len {
+            return Err(PacketError::Truncated.into());
+        }
+        let tcp_flags = match headers.protocol {
+            IP_PROTO_TCP => l4[13] & 0x3f,
+            _ => 0,
+        };
+        Ok(Transport::Flow {
+            port: l4[port_offset..].u16(),
+            tcp_flags,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Side {
+    Src,
+    Dst,
+}
+
+/// Offset of the port on `side` of a transport header, the identifier for ICMP echoes.
+fn port_offset(protocol: u8, side: Side) -> usize {
+    match (protocol, side) {
+        (IP_PROTO_ICMP, _) | (IP_PROTO_ICMPV6, _) => 4,
+        (_, Side::Src) => 0,
+        (_, Side::Dst) => 2,
+    }
+}
+
+fn quoted_port(packet: &[u8], quoted: &Headers, side: Side) -> io::Result<u16> {
+    match quoted.protocol {
+        IP_PROTO_TCP | IP_PROTO_UDP | IP_PROTO_ICMP | IP_PROTO_ICMPV6 => {
+            Ok(packet[quoted.start + port_offset(quoted.protocol, side)..].u16())
+        }
+        _ => Err(untranslatable("untranslatable protocol")),
+    }
+}
+
+/// Replaces the address on `side` of the IP header described by `headers`.
+fn rewrite_addr(packet: &mut [u8], headers: &Headers, side: Side, to: IpAddr) {
+    let ip = &mut packet[headers.ip..];
+    match (side, to) {
+        (side, IpAddr::V4(to)) => {
+            let (offset, from) = match (side, headers.src, headers.dst) {
+                (Side::Src, IpAddr::V4(from), _) => (12, from),
+                (Side::Dst, _, IpAddr::V4(from)) => (16, from),
+                _ => unreachable!("nat mixes address families"),
+            };
+            ip[offset..offset + 4].copy_from_slice(&to.octets());
+            let checksum = ip[10..].u16().update_ipv4(from, to);
+            ip.write_be(10, checksum).unwrap();
+        }
+        (Side::Src, IpAddr::V6(to)) => ip[8..24].copy_from_slice(&to.octets()),
+        (Side::Dst, IpAddr::V6(to)) => ip[24..40].copy_from_slice(&to.octets()),
+    }
+}
+
+/// Replaces the address and port on `side` of the packet described by `headers`, updating
+/// the IPv4 header and transport checksums.
+fn rewrite(packet: &mut [u8], headers: &Headers, side: Side, to: SocketAddr) {
+    let from = match side {
+        Side::Src => headers.src,
+        Side::Dst => headers.dst,
+    };
+    rewrite_addr(packet, headers, side, to.ip());
+
+    let l4 = &mut packet[headers.start..headers.end];
+    let offset = port_offset(headers.protocol, side);
+    let port = l4[offset..].u16();
+    l4.write_be(offset, to.port()).unwrap();
+    let checksum_offset = match headers.protocol {
+        IP_PROTO_TCP => 16,
+        IP_PROTO_UDP => 6,
+        _ => 2,
+    };
+    // Quoted packets may be cut before the checksum.
+    let mut checksum = match l4.read_be::<u16>(checksum_offset) {
+        Some(checksum) => checksum,
+        None => return,
+    };
+    // A zero UDP checksum over IPv4 means there is none.
+    if headers.protocol == IP_PROTO_UDP && checksum == 0 && from.is_ipv4() {
+        return;
+    }
+    checksum = checksum.update_u16(port, to.port());
+    // Only ICMPv4 leaves the addresses out of its checksum.
+    checksum = match (from, to.ip()) {
+        (IpAddr::V4(from), IpAddr::V4(to)) if headers.protocol != IP_PROTO_ICMP => {
+            checksum.update_ipv4(from, to)
+        }
+        (IpAddr::V6(from), IpAddr::V6(to)) => checksum.update_ipv6(from, to),
+        _ => checksum,
+    };
+    if headers.protocol == IP_PROTO_UDP && checksum == 0 {
+        checksum = 0xffff;
+    }
+    l4.write_be(checksum_offset, checksum).unwrap();
+}
+
+/// Recomputes the checksum of an ICMP error after its quoted packet was rewritten.
+fn fill_icmp_checksum(packet: &mut [u8], headers: &Headers) {
+    let addrs = match packet[0] >> 4 {
+        6 => {
+            let ip = Ipv6Packet::new_unchecked(&packet[..]);
+            Some((ip.src_addr(), ip.dst_addr()))
+        }
+        _ => None,
+    };
+    let mut icmp = IcmpPacket::new_unchecked(&mut packet[headers.start..headers.end]);
+    match addrs {
+        Some((src, dst)) => icmp.fill_checksum_v6(src, dst),
+        None => icmp.fill_checksum_v4(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        icmpv4_error, icmpv6_error, Icmpv4Message, Icmpv6Message, Ipv4Builder, Ipv6Builder,
+        TcpBuilder, TcpPacket, UdpBuilder, UdpPacket, ICMPV4_PORT_UNREACHABLE, TCP_ACK, TCP_SYN,
+    };
+    use std::thread;
+
+    const PUBLIC: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn udp_v4(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+        let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => (s, d),
+            _ => unreachable!(),
+        };
+        let udp = UdpBuilder::new(src.port(), dst.port())
+            .emit_v4(src_ip, dst_ip, payload)
+            .unwrap();
+        Ipv4Builder::new(src_ip, dst_ip, IP_PROTO_UDP)
+            .emit(&udp)
+            .unwrap()
+            .to_vec()
+    }
+
+    fn tcp_v4(src: SocketAddr, dst: SocketAddr, flags: u8) -> Vec<u8> {
+        let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => (s, d),
+            _ => unreachable!(),
+        };
+        let tcp = TcpBuilder::new(src.port(), dst.port())
+            .flags(flags)
+            .emit_v4(src_ip, dst_ip, b"");
+        Ipv4Builder::new(src_ip, dst_ip, IP_PROTO_TCP)
+            .emit(&tcp)
+            .unwrap()
+            .to_vec()
+    }
+
+    fn addrs_v4(packet: &[u8]) -> (SocketAddr, SocketAddr) {
+        let ip = Ipv4Packet::new(packet).unwrap();
+        assert!(ip.verify_checksum());
+        let (src, dst) = (ip.src_addr(), ip.dst_addr());
+        let ports = match ip.protocol() {
+            IP_PROTO_UDP => {
+                let udp = UdpPacket::new(ip.payload()).unwrap();
+                assert!(udp.verify_checksum_v4(src, dst));
+                (udp.src_port(), udp.dst_port())
+            }
+            _ => {
+                let tcp = TcpPacket::new(ip.payload()).unwrap();
+                assert!(tcp.verify_checksum_v4(src, dst));
+                (tcp.src_port(), tcp.dst_port())
+            }
+        };
+        (
+            SocketAddr::new(src.into(), ports.0),
+            SocketAddr::new(dst.into(), ports.1),
+        )
+    }
+
+    #[test]
+    fn test_nat_udp() {
+        let mut nat = Nat::new().ipv4(PUBLIC);
+        let server: SocketAddr = (REMOTE, 53).into();
+        let a: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.3:5000".parse().unwrap();
+
+        let mut packet = udp_v4(a, server, b"query");
+        nat.egress(&mut packet).unwrap();
+        let a_external = SocketAddr::new(PUBLIC.into(), 5000);
+        assert_eq!(addrs_v4(&packet), (a_external, server));
+
+        // The port is taken, so the second host gets the next one.
+        let mut packet = udp_v4(b, server, b"query");
+        nat.egress(&mut packet).unwrap();
+        let b_external = SocketAddr::new(PUBLIC.into(), 5001);
+        assert_eq!(addrs_v4(&packet), (b_external, server));
+
+        // Mappings are endpoint independent.
+        let mut packet = udp_v4(a, (REMOTE, 123).into(), b"ntp");
+        nat.egress(&mut packet).unwrap();
+        assert_eq!(addrs_v4(&packet).0, a_external);
+
+        let mut packet = udp_v4(server, b_external, b"answer");
+        nat.ingress(&mut packet).unwrap();
+        assert_eq!(addrs_v4(&packet), (server, b));
+        assert_eq!(UdpPacket::new(&packet[20..]).unwrap().payload(), b"answer");
+
+        // No checksum stays no checksum.
+        let mut packet = udp_v4(server, a_external, b"answer");
+        packet[26..28].copy_from_slice(&[0, 0]);
+        nat.ingress(&mut packet).unwrap();
+        assert_eq!(addrs_v4(&packet), (server, a));
+        assert_eq!(UdpPacket::new(&packet[20..]).unwrap().checksum(), 0);
+
+        for dst in [
+            SocketAddr::new(PUBLIC.into(), 5002),
+            SocketAddr::new(REMOTE.into(), 5000),
+        ] {
+            let mut packet = udp_v4(server, dst, b"answer");
+            assert_eq!(
+                nat.ingress(&mut packet).unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+        }
+
+        let mut mappings: Vec<_> = nat.mappings().map(|m| (m.internal, m.external)).collect();
+        mappings.sort();
+        assert_eq!(mappings, vec![(a, a_external), (b, b_external)]);
+        assert!(nat.mappings().all(|m| m.protocol == IP_PROTO_UDP));
+        assert_eq!(nat.len(), 2);
+        nat.clear();
+        assert!(nat.is_empty());
+    }
+
+    #[test]
+    fn test_nat_tcp_timeouts() {
+        let mut nat = Nat::new()
+            .ipv4(PUBLIC)
+            .tcp_timeout(Duration::from_secs(3600))
+            .tcp_transitory_timeout(Duration::from_millis(100));
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let server: SocketAddr = (REMOTE, 443).into();
+        let external = SocketAddr::new(PUBLIC.into(), 40000);
+        let expires = |nat: &Nat| nat.mappings().next().unwrap().expires - Instant::now();
+
+        let mut packet = tcp_v4(client, server, TCP_SYN);
+        nat.egress(&mut packet).unwrap();
+        assert_eq!(addrs_v4(&packet), (external, server));
+        assert!(expires(&nat) <= Duration::from_millis(100));
+
+        let mut packet = tcp_v4(server, external, TCP_SYN | TCP_ACK);
+        nat.ingress(&mut packet).unwrap();
+        assert_eq!(addrs_v4(&packet), (server, client));
+        assert!(expires(&nat) > Duration::from_secs(3000));
+
+        let mut packet = tcp_v4(client, server, TCP_FIN | TCP_ACK);
+        nat.egress(&mut packet).unwrap();
+        assert!(expires(&nat) <= Duration::from_millis(100));
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(nat.mappings().count(), 0);
+        let mut packet = tcp_v4(server, external, TCP_ACK);
+        assert_eq!(
+            nat.ingress(&mut packet).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(nat.len(), 1);
+        assert_eq!(nat.expire(), 1);
+        assert!(nat.is_empty());
+    }
+
+    #[test]
+    fn test_nat_port_range() {
+        let mut nat = Nat::new()
+            .ipv4(PUBLIC)
+            .port_range(2000..=2001)
+            .udp_timeout(Duration::from_millis(50));
+        let server: SocketAddr = (REMOTE, 53).into();
+        for (i, port) in [(2, 2000), (3, 2001)] {
+            let mut packet = udp_v4((Ipv4Addr::new(10, 0, 0, i), 5000).into(), server, b"");
+            nat.egress(&mut packet).unwrap();
+            assert_eq!(addrs_v4(&packet).0.port(), port);
+        }
+        let c: SocketAddr = "10.0.0.4:2001".parse().unwrap();
+        let mut packet = udp_v4(c, server, b"");
+        assert_eq!(
+            nat.egress(&mut packet).unwrap_err().kind(),
+            io::ErrorKind::AddrNotAvailable
+        );
+
+        // Expired mappings are reclaimed, and a free internal port is kept.
+        thread::sleep(Duration::from_millis(100));
+        nat.egress(&mut packet).unwrap();
+        assert_eq!(addrs_v4(&packet).0.port(), 2001);
+        assert_eq!(nat.mappings().count(), 1);
+    }
+
+    #[test]
+    fn test_nat_icmp() {
+        let public_v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut nat = Nat::new().ipv4(PUBLIC).ipv6(public_v6);
+
+        let request = Icmpv4Message::EchoRequest {
+            identifier: 7,
+            sequence: 1,
+        }
+        .emit(b"ping");
+        let mut packet = Ipv4Builder::new(CLIENT, REMOTE, IP_PROTO_ICMP)
+            .emit(&request)
+            .unwrap();
+        nat.egress(&mut packet).unwrap();
+        let ip = Ipv4Packet::new(&packet[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!(ip.src_addr(), PUBLIC);
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum_v4());
+        // Identifiers below the port range are allocated from its start.
+        let identifier = 1024;
+        assert_eq!(
+            icmp.icmpv4_message(),
+            Icmpv4Message::EchoRequest {
+                identifier,
+                sequence: 1
+            }
+        );
+
+        let reply = Icmpv4Message::EchoReply {
+            identifier,
+            sequence: 1,
+        }
+        .emit(b"ping");
+        let mut packet = Ipv4Builder::new(REMOTE, PUBLIC, IP_PROTO_ICMP)
+            .emit(&reply)
+            .unwrap();
+        nat.ingress(&mut packet).unwrap();
+        let ip = Ipv4Packet::new(&packet[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!(ip.dst_addr(), CLIENT);
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum_v4());
+        assert_eq!(
+            icmp.icmpv4_message(),
+            Icmpv4Message::EchoReply {
+                identifier: 7,
+                sequence: 1
+            }
+        );
+
+        let (client, remote): (Ipv6Addr, Ipv6Addr) =
+            ("fd00::2".parse().unwrap(), "2001:db8:1::7".parse().unwrap());
+        let request = Icmpv6Message::EchoRequest {
+            identifier: 4000,
+            sequence: 2,
+        }
+        .emit(client, remote, b"ping");
+        let mut packet = Ipv6Builder::new(client, remote, IP_PROTO_ICMPV6)
+            .emit(&request)
+            .unwrap();
+        nat.egress(&mut packet).unwrap();
+        let ip = Ipv6Packet::new(&packet[..]).unwrap();
+        assert_eq!(ip.src_addr(), public_v6);
+        let icmp = IcmpPacket::new(ip.upper_layer_payload()).unwrap();
+        assert!(icmp.verify_checksum_v6(public_v6, remote));
+        assert_eq!(
+            icmp.icmpv6_message(),
+            Icmpv6Message::EchoRequest {
+                identifier: 4000,
+                sequence: 2
+            }
+        );
+
+        // Only echo requests open mappings.
+        let unsolicited = Icmpv4Message::EchoReply {
+            identifier: 1,
+            sequence: 1,
+        }
+        .emit(b"");
+        let mut packet = Ipv4Builder::new(CLIENT, REMOTE, IP_PROTO_ICMP)
+            .emit(&unsolicited)
+            .unwrap();
+        assert_eq!(
+            nat.egress(&mut packet).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_nat_icmp_error() {
+        let mut nat = Nat::new().ipv4(PUBLIC).port_range(6000..=6999);
+        let client: SocketAddr = (CLIENT, 5000).into();
+        let server: SocketAddr = (REMOTE, 53).into();
+        let mut packet = udp_v4(client, server, b"query");
+        nat.egress(&mut packet).unwrap();
+        let external = addrs_v4(&packet).0;
+        assert_eq!(external.port(), 6000);
+
+        // A router on the path reports the translated packet back to the public address.
+        let router = Ipv4Addr::new(192, 0, 2, 254);
+        let mut error = icmpv4_error(
+            router,
+            &packet,
+            Icmpv4Message::DestUnreachable {
+                code: ICMPV4_PORT_UNREACHABLE,
+                next_hop_mtu: 0,
+            },
+        )
+        .unwrap();
+        nat.ingress(&mut error).unwrap();
+        let ip = Ipv4Packet::new(&error[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!(ip.src_addr(), router);
+        assert_eq!(ip.dst_addr(), CLIENT);
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum_v4());
+        assert_eq!(addrs_v4(icmp.payload()), (client, server));
+
+        // The client reports an inbound packet the other way.
+        let mut inbound = udp_v4(server, external, b"late");
+        nat.ingress(&mut inbound).unwrap();
+        let mut error = icmpv4_error(
+            CLIENT,
+            &inbound,
+            Icmpv4Message::DestUnreachable {
+                code: ICMPV4_PORT_UNREACHABLE,
+                next_hop_mtu: 0,
+            },
+        )
+        .unwrap();
+        nat.egress(&mut error).unwrap();
+        let ip = Ipv4Packet::new(&error[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!(ip.src_addr(), PUBLIC);
+        assert_eq!(ip.dst_addr(), REMOTE);
+        let icmp = IcmpPacket::new(ip.payload()).unwrap();
+        assert!(icmp.verify_checksum_v4());
+        assert_eq!(addrs_v4(icmp.payload()), (server, external));
+
+        let public_v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut nat = Nat::new().ipv6(public_v6);
+        let (client, remote): (Ipv6Addr, Ipv6Addr) =
+            ("fd00::2".parse().unwrap(), "2001:db8:1::7".parse().unwrap());
+        let udp = UdpBuilder::new(5000, 53)
+            .emit_v6(client, remote, &[0; 1400])
+            .unwrap();
+        let mut packet = Ipv6Builder::new(client, remote, IP_PROTO_UDP)
+            .emit(&udp)
+            .unwrap();
+        nat.egress(&mut packet).unwrap();
+        let router: Ipv6Addr = "2001:db8:2::1".parse().unwrap();
+        let mut error =
+            icmpv6_error(router, &packet, Icmpv6Message::PacketTooBig { mtu: 1280 }).unwrap();
+        nat.ingress(&mut error).unwrap();
+        let ip = Ipv6Packet::new(&error[..]).unwrap();
+        assert_eq!(ip.dst_addr(), client);
+        let icmp = IcmpPacket::new(ip.upper_layer_payload()).unwrap();
+        assert!(icmp.verify_checksum_v6(router, client));
+        assert_eq!(
+            icmp.icmpv6_message(),
+            Icmpv6Message::PacketTooBig { mtu: 1280 }
+        );
+        // The quote is cut short, so only the header fields are checked.
+        let quoted = Ipv6Packet::new_unchecked(icmp.payload());
+        assert_eq!(quoted.src_addr(), client);
+        assert_eq!(
+            UdpPacket::new_unchecked(&icmp.payload()[40..]).src_port(),
+            5000
+        );
+    }
+
+    #[test]
+    fn test_nat_untranslatable() {
+        let mut nat = Nat::new().ipv4(PUBLIC);
+        let server: SocketAddr = (REMOTE, 53).into();
+        let udp = UdpBuilder::new(5000, 53)
+            .emit_v4(CLIENT, REMOTE, b"query")
+            .unwrap();
+        let mut packet = Ipv4Builder::new(CLIENT, REMOTE, IP_PROTO_UDP)
+            .more_fragments(true)
+            .emit(&udp)
+            .unwrap();
+        assert_eq!(
+            nat.egress(&mut packet).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let mut packet = Ipv4Builder::new(CLIENT, REMOTE, 47).emit(&[0; 8]).unwrap();
+        assert_eq!(
+            nat.egress(&mut packet).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let (client, remote): (Ipv6Addr, Ipv6Addr) =
+            ("fd00::2".parse().unwrap(), "2001:db8:1::7".parse().unwrap());
+        let udp = UdpBuilder::new(5000, 53)
+            .emit_v6(client, remote, b"")
+            .unwrap();
+        let mut packet = Ipv6Builder::new(client, remote, IP_PROTO_UDP)
+            .emit(&udp)
+            .unwrap();
+        assert_eq!(
+            nat.egress(&mut packet).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let mut packet = udp_v4((CLIENT, 5000).into(), server, b"query");
+        assert_eq!(
+            nat.egress(&mut packet[..30]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(nat.is_empty());
+    }
+}
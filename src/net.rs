@@ -1,9 +1,17 @@
+use crate::Resolver;
 use async_std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs, UdpSocket};
+use async_std::prelude::FutureExt;
 use async_trait::async_trait;
-use socket2::{Domain, Protocol, Socket, Type};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::fmt;
+use std::future::Future;
 use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::option::Option::Some;
 use std::prelude::v1::Result::Ok;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
 
 pub const STREAM_BUF_SIZE: usize = 32 * 1024;
 
@@ -11,6 +19,9 @@ pub const DATAGRAM_BUF_SIZE: usize = 64 * 1024;
 
 pub const FRAME_LENGTH_SIZE: usize = 2;
 
+/// Connection Attempt Delay recommended by RFC 8305 before IPv4 is tried alongside IPv6.
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
 const LOCAL_LISTEN_ADDR: &str = "127.19.89.64:0";
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -23,33 +34,406 @@ pub enum BindType {
     IPv6Icmp,
 }
 
-pub fn bind(r#type: BindType, addr: &str) -> io::Result<Socket> {
-    let sock = match r#type {
-        BindType::IPv4Tcp => Socket::new(Domain::ipv4(), Type::stream(), None)?,
-        BindType::IPv4Udp => Socket::new(Domain::ipv4(), Type::dgram(), None)?,
-        BindType::IPv4Icmp => Socket::new(Domain::ipv4(), Type::raw(), Some(Protocol::icmpv4()))?,
-        BindType::IPv6Tcp => Socket::new(Domain::ipv6(), Type::stream(), None)?,
-        BindType::IPv6Udp => Socket::new(Domain::ipv6(), Type::dgram(), None)?,
-        BindType::IPv6Icmp => Socket::new(Domain::ipv6(), Type::raw(), Some(Protocol::icmpv6()))?,
+impl BindType {
+    fn is_ipv6(self) -> bool {
+        matches!(
+            self,
+            BindType::IPv6Tcp | BindType::IPv6Udp | BindType::IPv6Icmp
+        )
+    }
+}
+
+pub(crate) fn invalid_addr(addr: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid socket address {:?}", addr),
+    )
+}
+
+#[cfg(unix)]
+fn set_int_opt(sock: &Socket, level: libc::c_int, name: libc::c_int, value: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let value = value as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
     };
-    match r#type {
-        BindType::IPv6Tcp | BindType::IPv6Udp => {
-            sock.set_only_v6(true)?;
-            sock.bind(&addr.parse::<std::net::SocketAddrV6>().unwrap().into())?;
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Linux policy-routing options shared by [`BindOptions`] and [`DialOptions`].
+#[derive(Debug, Clone, Default)]
+struct RoutingOptions {
+    mark: Option<u32>,
+    device: Option<String>,
+    transparent: bool,
+    freebind: bool,
+}
+
+impl RoutingOptions {
+    fn is_empty(&self) -> bool {
+        self.mark.is_none() && self.device.is_none() && !self.transparent && !self.freebind
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn apply(&self, sock: &Socket, ipv6: bool) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(mark) = self.mark {
+                sock.set_mark(mark)?;
+            }
+            if let Some(device) = &self.device {
+                let device = std::ffi::CString::new(device.as_str()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid device name {:?}", device),
+                    )
+                })?;
+                sock.bind_device(Some(&device))?;
+            }
+            if self.transparent {
+                if ipv6 {
+                    set_int_opt(sock, libc::IPPROTO_IPV6, libc::IPV6_TRANSPARENT, 1)?;
+                } else {
+                    set_int_opt(sock, libc::IPPROTO_IP, libc::IP_TRANSPARENT, 1)?;
+                }
+            }
+            if self.freebind {
+                if ipv6 {
+                    set_int_opt(sock, libc::IPPROTO_IPV6, libc::IPV6_FREEBIND, 1)?;
+                } else {
+                    set_int_opt(sock, libc::IPPROTO_IP, libc::IP_FREEBIND, 1)?;
+                }
+            }
         }
-        _ => {
-            sock.bind(&addr.parse::<std::net::SocketAddrV4>().unwrap().into())?;
+        Ok(())
+    }
+}
+
+macro_rules! routing_setters {
+    () => {
+        /// Sets SO_MARK so policy routing rules can match the socket's traffic.
+        #[cfg(target_os = "linux")]
+        pub fn mark(mut self, mark: u32) -> Self {
+            self.routing.mark = Some(mark);
+            self
         }
-    };
-    match r#type {
-        BindType::IPv4Tcp | BindType::IPv6Tcp => {
-            sock.listen(16)?;
+
+        /// Sets SO_BINDTODEVICE so traffic always leaves through the named interface.
+        #[cfg(target_os = "linux")]
+        pub fn bind_device(mut self, device: &str) -> Self {
+            self.routing.device = Some(device.to_string());
+            self
         }
-        _ => {}
     };
-    Ok(sock)
 }
 
+/// Socket options applied by [`BindOptions::bind`].
+///
+/// The defaults match [`bind`]: a listen backlog of 16 and v6-only IPv6 sockets.
+#[derive(Debug, Clone)]
+pub struct BindOptions {
+    backlog: i32,
+    reuse_address: bool,
+    reuse_port: bool,
+    only_v6: bool,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    tos: Option<u32>,
+    ttl: Option<u32>,
+    nonblocking: bool,
+    routing: RoutingOptions,
+}
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        BindOptions {
+            backlog: 16,
+            reuse_address: false,
+            reuse_port: false,
+            only_v6: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            tos: None,
+            ttl: None,
+            nonblocking: false,
+            routing: RoutingOptions::default(),
+        }
+    }
+}
+
+impl BindOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Listen backlog for TCP sockets.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.reuse_address = reuse;
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// Whether IPv6 TCP and UDP sockets refuse IPv4-mapped traffic. Turn off for dual-stack.
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = only_v6;
+        self
+    }
+
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// IP_TOS for IPv4 sockets, IPV6_TCLASS for IPv6 sockets.
+    #[cfg(unix)]
+    pub fn tos(mut self, tos: u32) -> Self {
+        self.tos = Some(tos);
+        self
+    }
+
+    /// TTL for IPv4 sockets, unicast hop limit for IPv6 sockets.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    routing_setters!();
+
+    /// Sets IP_TRANSPARENT so a transparent-proxy listener accepts traffic for foreign
+    /// addresses. Needs CAP_NET_ADMIN.
+    #[cfg(target_os = "linux")]
+    pub fn transparent(mut self, transparent: bool) -> Self {
+        self.routing.transparent = transparent;
+        self
+    }
+
+    /// Sets IP_FREEBIND so the socket can bind to an address that is not configured yet.
+    #[cfg(target_os = "linux")]
+    pub fn freebind(mut self, freebind: bool) -> Self {
+        self.routing.freebind = freebind;
+        self
+    }
+
+    /// Creates a socket of `type`, applies the options, binds it to `addr` and starts
+    /// listening for TCP types.
+    pub fn bind(&self, r#type: BindType, addr: &str) -> io::Result<Socket> {
+        let addr: SockAddr = if r#type.is_ipv6() {
+            addr.parse::<std::net::SocketAddrV6>()
+                .map_err(|_| invalid_addr(addr))?
+                .into()
+        } else {
+            addr.parse::<std::net::SocketAddrV4>()
+                .map_err(|_| invalid_addr(addr))?
+                .into()
+        };
+        let sock = match r#type {
+            BindType::IPv4Tcp => Socket::new(Domain::ipv4(), Type::stream(), None)?,
+            BindType::IPv4Udp => Socket::new(Domain::ipv4(), Type::dgram(), None)?,
+            BindType::IPv4Icmp => {
+                Socket::new(Domain::ipv4(), Type::raw(), Some(Protocol::icmpv4()))?
+            }
+            BindType::IPv6Tcp => Socket::new(Domain::ipv6(), Type::stream(), None)?,
+            BindType::IPv6Udp => Socket::new(Domain::ipv6(), Type::dgram(), None)?,
+            BindType::IPv6Icmp => {
+                Socket::new(Domain::ipv6(), Type::raw(), Some(Protocol::icmpv6()))?
+            }
+        };
+        if let BindType::IPv6Tcp | BindType::IPv6Udp = r#type {
+            sock.set_only_v6(self.only_v6)?;
+        }
+        if self.reuse_address {
+            sock.set_reuse_address(true)?;
+        }
+        #[cfg(unix)]
+        {
+            if self.reuse_port {
+                sock.set_reuse_port(true)?;
+            }
+            if let Some(tos) = self.tos {
+                if r#type.is_ipv6() {
+                    set_int_opt(&sock, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+                } else {
+                    set_int_opt(&sock, libc::IPPROTO_IP, libc::IP_TOS, tos)?;
+                }
+            }
+        }
+        if let Some(size) = self.send_buffer_size {
+            sock.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            sock.set_recv_buffer_size(size)?;
+        }
+        if let Some(ttl) = self.ttl {
+            if r#type.is_ipv6() {
+                sock.set_unicast_hops_v6(ttl)?;
+            } else {
+                sock.set_ttl(ttl)?;
+            }
+        }
+        self.routing.apply(&sock, r#type.is_ipv6())?;
+        sock.set_nonblocking(self.nonblocking)?;
+        sock.bind(&addr)?;
+        if let BindType::IPv4Tcp | BindType::IPv6Tcp = r#type {
+            sock.listen(self.backlog)?;
+        }
+        Ok(sock)
+    }
+}
+
+/// Binds a socket with the default [`BindOptions`].
+pub fn bind(r#type: BindType, addr: &str) -> io::Result<Socket> {
+    BindOptions::new().bind(r#type, addr)
+}
+
+/// Socket options applied by the `_with` dialing methods of [`SocketAddrExt`] and [`DualAddr`].
+#[derive(Clone, Default)]
+pub struct DialOptions {
+    routing: RoutingOptions,
+    resolver: Option<Arc<dyn Resolver>>,
+}
+
+impl fmt::Debug for DialOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DialOptions")
+            .field("routing", &self.routing)
+            .field("resolver", &self.resolver.is_some())
+            .finish()
+    }
+}
+
+impl DialOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    routing_setters!();
+
+    /// Resolver for the names passed to [`DualAddr::resolve_with`], e.g. a [`DnsClient`]
+    /// reaching its server through the tunnel; the system resolver is used without one.
+    ///
+    /// [`DnsClient`]: crate::DnsClient
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    fn socket(
+        &self,
+        addr: &SocketAddr,
+        r#type: Type,
+        protocol: Option<Protocol>,
+    ) -> io::Result<Socket> {
+        let domain = match addr {
+            SocketAddr::V4(_) => Domain::ipv4(),
+            SocketAddr::V6(_) => Domain::ipv6(),
+        };
+        let sock = Socket::new(domain, r#type, protocol)?;
+        self.routing.apply(&sock, addr.is_ipv6())?;
+        Ok(sock)
+    }
+
+    pub(crate) async fn connect_tcp(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        if self.routing.is_empty() {
+            return TcpStream::connect(addr).await;
+        }
+        let sock = self.socket(&addr, Type::stream(), None)?;
+        Ok(TcpStream::from(connect_nonblocking(sock, addr).await?))
+    }
+
+    pub(crate) async fn connect_udp(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let sock = self.socket(&addr, Type::dgram(), None)?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        sock.bind(&local.into())?;
+        let sock = UdpSocket::from(sock.into_udp_socket());
+        sock.connect(addr).await?;
+        Ok(sock)
+    }
+
+    pub(crate) async fn connect_icmp(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let (any, protocol) = match addr {
+            SocketAddr::V4(_) => (
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                Protocol::icmpv4(),
+            ),
+            SocketAddr::V6(_) => (
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                Protocol::icmpv6(),
+            ),
+        };
+        let sock = self.socket(&any, Type::raw(), Some(protocol))?;
+        let sock = UdpSocket::from(sock.into_udp_socket());
+        sock.connect(addr).await?;
+        Ok(sock)
+    }
+}
+
+fn unresolved() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "could not resolve to any addresses",
+    )
+}
+
+/// Connects `sock` without tying up a thread; dropping the future closes the socket.
+#[cfg(target_os = "linux")]
+async fn connect_nonblocking(sock: Socket, addr: SocketAddr) -> io::Result<std::net::TcpStream> {
+    sock.set_nonblocking(true)?;
+    match sock.connect(&addr.into()) {
+        Ok(()) => {}
+        Err(err)
+            if err.raw_os_error() == Some(libc::EINPROGRESS)
+                || err.kind() == io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err),
+    }
+    let stream = async_io::Async::new(sock.into_tcp_stream())?;
+    stream.writable().await?;
+    if let Some(err) = stream.get_ref().take_error()? {
+        return Err(err);
+    }
+    stream.into_inner()
+}
+
+// Routing options, the only reason to dial through socket2, are Linux only.
+#[cfg(not(target_os = "linux"))]
+async fn connect_nonblocking(_sock: Socket, _addr: SocketAddr) -> io::Result<std::net::TcpStream> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[deprecated(
+    note = "use `Ipv4Packet`, which reports malformed packets instead of passing them through, and `Ipv4Reassembler` for fragments"
+)]
 pub fn strip_ipv4_header(b: &[u8]) -> &[u8] {
     if b.len() < 20 {
         return b;
@@ -61,7 +445,7 @@ pub fn strip_ipv4_header(b: &[u8]) -> &[u8] {
     if 20 > l || l > b.len() {
         return b;
     }
-    return &b[l..];
+    &b[l..]
 }
 
 #[async_trait(?Send)]
@@ -90,58 +474,411 @@ pub trait SocketAddrExt: ToSocketAddrs {
         sock.connect(self).await?;
         Ok(sock)
     }
+
+    async fn dial_tcp_with(&self, opts: &DialOptions) -> io::Result<TcpStream> {
+        if opts.routing.is_empty() {
+            return self.dial_tcp().await;
+        }
+        let mut last_err = None;
+        for addr in self.to_socket_addrs().await? {
+            match opts.connect_tcp(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(unresolved))
+    }
+
+    async fn dial_udp_with(&self, opts: &DialOptions) -> io::Result<UdpSocket> {
+        let mut last_err = None;
+        for addr in self.to_socket_addrs().await? {
+            match opts.connect_udp(addr).await {
+                Ok(sock) => return Ok(sock),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(unresolved))
+    }
+
+    async fn dial_icmpv4_with(&self, opts: &DialOptions) -> io::Result<UdpSocket> {
+        let any = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let sock = opts.socket(&any, Type::raw(), Some(Protocol::icmpv4()))?;
+        let sock = UdpSocket::from(sock.into_udp_socket());
+        sock.connect(self).await?;
+        Ok(sock)
+    }
+
+    async fn dial_icmpv6_with(&self, opts: &DialOptions) -> io::Result<UdpSocket> {
+        let any = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0));
+        let sock = opts.socket(&any, Type::raw(), Some(Protocol::icmpv6()))?;
+        let sock = UdpSocket::from(sock.into_udp_socket());
+        sock.connect(self).await?;
+        Ok(sock)
+    }
 }
 
 #[async_trait(?Send)]
 impl<T: ToSocketAddrs> SocketAddrExt for T {}
 
-fn any_success<T>(res1: io::Result<T>, res2: io::Result<T>) -> io::Result<(Option<T>, Option<T>)> {
-    match res1 {
-        Ok(sock1) => Ok(match res2 {
-            Ok(sock2) => (Some(sock1), Some(sock2)),
-            _ => (Some(sock1), None),
+/// Errors from dialing a [`DualAddr`]; each family that was attempted and failed has its error.
+#[derive(Debug)]
+pub struct DualError {
+    pub v4: Option<io::Error>,
+    pub v6: Option<io::Error>,
+}
+
+impl DualError {
+    fn v4(err: io::Error) -> Self {
+        DualError {
+            v4: Some(err),
+            v6: None,
+        }
+    }
+
+    fn v6(err: io::Error) -> Self {
+        DualError {
+            v4: None,
+            v6: Some(err),
+        }
+    }
+
+    /// Kind of the IPv4 error if there is one, otherwise of the IPv6 error.
+    pub fn kind(&self) -> io::ErrorKind {
+        self.v4
+            .as_ref()
+            .or(self.v6.as_ref())
+            .map_or(io::ErrorKind::Other, io::Error::kind)
+    }
+}
+
+impl fmt::Display for DualError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.v4, &self.v6) {
+            (Some(v4), Some(v6)) => write!(f, "IPv4: {}; IPv6: {}", v4, v6),
+            (Some(v4), None) => write!(f, "IPv4: {}", v4),
+            (None, Some(v6)) => write!(f, "IPv6: {}", v6),
+            (None, None) => write!(f, "no address to dial"),
+        }
+    }
+}
+
+impl std::error::Error for DualError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        let err = self.v4.as_ref().or(self.v6.as_ref())?;
+        Some(err)
+    }
+}
+
+impl From<DualError> for io::Error {
+    /// A single failure converts back to itself; two are wrapped so both stay reachable
+    /// through [`io::Error::get_ref`].
+    fn from(err: DualError) -> Self {
+        match err {
+            DualError {
+                v4: Some(err),
+                v6: None,
+            }
+            | DualError {
+                v4: None,
+                v6: Some(err),
+            } => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+fn any_success<T>(
+    v4: io::Result<T>,
+    v6: io::Result<T>,
+) -> Result<(Option<T>, Option<T>), DualError> {
+    match (v4, v6) {
+        (Err(v4), Err(v6)) => Err(DualError {
+            v4: Some(v4),
+            v6: Some(v6),
         }),
-        Err(err1) => match res2 {
-            Ok(err2) => Ok((None, Some(err2))),
-            _ => Err(err1),
-        },
+        (v4, v6) => Ok((v4.ok(), v6.ok())),
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Polls both futures and returns the first success, or both errors once each has failed.
+async fn first_ok<T, A, B>(a: A, b: B) -> Result<T, (io::Error, io::Error)>
+where
+    A: Future<Output = io::Result<T>>,
+    B: Future<Output = io::Result<T>>,
+{
+    let (mut a, mut b) = (Box::pin(a), Box::pin(b));
+    let (mut err_a, mut err_b) = (None, None);
+    std::future::poll_fn(|cx| {
+        if err_a.is_none() {
+            if let Poll::Ready(res) = a.as_mut().poll(cx) {
+                match res {
+                    Ok(v) => return Poll::Ready(Ok(v)),
+                    Err(err) => err_a = Some(err),
+                }
+            }
+        }
+        if err_b.is_none() {
+            if let Poll::Ready(res) = b.as_mut().poll(cx) {
+                match res {
+                    Ok(v) => return Poll::Ready(Ok(v)),
+                    Err(err) => err_b = Some(err),
+                }
+            }
+        }
+        match (err_a.take(), err_b.take()) {
+            (Some(a), Some(b)) => Poll::Ready(Err((a, b))),
+            (a, b) => {
+                err_a = a;
+                err_b = b;
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+/// Which families [`DualAddr::resolve_prefer`] keeps from the resolved addresses.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum FamilyPreference {
+    /// Keep one address of each family that resolved.
+    #[default]
+    Both,
+    /// Keep an IPv4 address if there is one, otherwise IPv6.
+    PreferV4,
+    /// Keep an IPv6 address if there is one, otherwise IPv4.
+    PreferV6,
+    V4Only,
+    V6Only,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum DualAddr {
     V4(SocketAddrV4),
     V6(SocketAddrV6),
     Both(SocketAddrV4, SocketAddrV6),
 }
 
+impl From<SocketAddrV4> for DualAddr {
+    fn from(addr: SocketAddrV4) -> Self {
+        DualAddr::V4(addr)
+    }
+}
+
+impl From<SocketAddrV6> for DualAddr {
+    fn from(addr: SocketAddrV6) -> Self {
+        DualAddr::V6(addr)
+    }
+}
+
+impl From<SocketAddr> for DualAddr {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => DualAddr::V4(addr),
+            SocketAddr::V6(addr) => DualAddr::V6(addr),
+        }
+    }
+}
+
+/// Formats as `1.2.3.4:80`, `[::1]:80`, or `1.2.3.4:80,[::1]:80` for both families.
+impl fmt::Display for DualAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DualAddr::V4(addr) => write!(f, "{}", addr),
+            DualAddr::V6(addr) => write!(f, "{}", addr),
+            DualAddr::Both(v4, v6) => write!(f, "{},{}", v4, v6),
+        }
+    }
+}
+
+/// Parses the [`Display`](fmt::Display) format; names are not resolved, see
+/// [`DualAddr::resolve`] for that.
+impl std::str::FromStr for DualAddr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let parse = |s: &str| s.trim().parse::<SocketAddr>().map_err(|_| invalid_addr(s));
+        match s.split_once(',') {
+            None => parse(s).map(DualAddr::from),
+            Some((a, b)) => match (parse(a)?, parse(b)?) {
+                (SocketAddr::V4(v4), SocketAddr::V6(v6))
+                | (SocketAddr::V6(v6), SocketAddr::V4(v4)) => Ok(DualAddr::Both(v4, v6)),
+                _ => Err(invalid_addr(s)),
+            },
+        }
+    }
+}
+
 impl DualAddr {
-    pub async fn dial_tcp(self) -> io::Result<(Option<TcpStream>, Option<TcpStream>)> {
+    /// Resolves a `host:port` string, keeping the first address of each family.
+    pub async fn resolve(addr: &str) -> io::Result<DualAddr> {
+        Self::resolve_prefer(addr, FamilyPreference::Both).await
+    }
+
+    /// Resolves a `host:port` string, keeping the families selected by `preference`.
+    pub async fn resolve_prefer(addr: &str, preference: FamilyPreference) -> io::Result<DualAddr> {
+        Self::from_addrs(addr.to_socket_addrs().await?, preference).ok_or_else(unresolved)
+    }
+
+    /// Resolves a `host:port` string through the resolver of `opts`, keeping the families
+    /// selected by `preference`.
+    pub async fn resolve_with(
+        addr: &str,
+        preference: FamilyPreference,
+        opts: &DialOptions,
+    ) -> io::Result<DualAddr> {
+        match &opts.resolver {
+            Some(resolver) => resolver.resolve(addr, preference).await,
+            None => Self::resolve_prefer(addr, preference).await,
+        }
+    }
+
+    /// Groups `addrs` by family, keeping the first address of each family selected by
+    /// `preference`.
+    pub fn from_addrs<I>(addrs: I, preference: FamilyPreference) -> Option<DualAddr>
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let (mut v4, mut v6) = (None, None);
+        for addr in addrs {
+            match addr {
+                SocketAddr::V4(addr) => {
+                    v4.get_or_insert(addr);
+                }
+                SocketAddr::V6(addr) => {
+                    v6.get_or_insert(addr);
+                }
+            }
+        }
+        match (preference, v4, v6) {
+            (FamilyPreference::Both, Some(v4), Some(v6)) => Some(DualAddr::Both(v4, v6)),
+            (FamilyPreference::V6Only, _, None) | (FamilyPreference::V4Only, None, _) => None,
+            (FamilyPreference::V6Only, _, Some(v6)) | (FamilyPreference::PreferV6, _, Some(v6)) => {
+                Some(DualAddr::V6(v6))
+            }
+            (_, Some(v4), _) => Some(DualAddr::V4(v4)),
+            (_, None, Some(v6)) => Some(DualAddr::V6(v6)),
+            (_, None, None) => None,
+        }
+    }
+
+    pub async fn dial_tcp(self) -> Result<(Option<TcpStream>, Option<TcpStream>), DualError> {
+        self.dial_tcp_with(&DialOptions::default()).await
+    }
+
+    /// Connects to whichever family answers first using Happy Eyeballs, see
+    /// [`happy_eyeballs_with`](DualAddr::happy_eyeballs_with).
+    pub async fn happy_eyeballs(self) -> io::Result<TcpStream> {
+        self.happy_eyeballs_with(&DialOptions::default(), HAPPY_EYEBALLS_DELAY)
+            .await
+    }
+
+    /// Connects IPv6 first and starts IPv4 after `delay`, or as soon as IPv6 fails, returning
+    /// whichever stream is established first. The losing attempt is cancelled and its socket
+    /// closed.
+    pub async fn happy_eyeballs_with(
+        self,
+        opts: &DialOptions,
+        delay: Duration,
+    ) -> io::Result<TcpStream> {
+        let (addr_v4, addr_v6) = match self {
+            DualAddr::V4(addr) => return opts.connect_tcp(addr.into()).await,
+            DualAddr::V6(addr) => return opts.connect_tcp(addr.into()).await,
+            DualAddr::Both(addr_v4, addr_v6) => (addr_v4, addr_v6),
+        };
+        let (failed_tx, failed_rx) = async_std::channel::bounded::<()>(1);
+        let v6 = async move {
+            let res = opts.connect_tcp(addr_v6.into()).await;
+            drop(failed_tx);
+            res
+        };
+        let v4 = async move {
+            // Closing the channel on IPv6 completion cuts the delay short.
+            let _ = async_std::future::timeout(delay, failed_rx.recv()).await;
+            opts.connect_tcp(addr_v4.into()).await
+        };
+        first_ok(v6, v4).await.map_err(|(v6, v4)| {
+            DualError {
+                v4: Some(v4),
+                v6: Some(v6),
+            }
+            .into()
+        })
+    }
+
+    pub async fn dial_udp(&self) -> Result<(Option<UdpSocket>, Option<UdpSocket>), DualError> {
+        self.dial_udp_with(&DialOptions::default()).await
+    }
+
+    pub async fn dial_icmp(&self) -> Result<(Option<UdpSocket>, Option<UdpSocket>), DualError> {
+        self.dial_icmp_with(&DialOptions::default()).await
+    }
+
+    /// Dials every family of the address concurrently and keeps each connection that succeeds.
+    pub async fn dial_tcp_with(
+        self,
+        opts: &DialOptions,
+    ) -> Result<(Option<TcpStream>, Option<TcpStream>), DualError> {
         match self {
-            DualAddr::V4(addr) => Ok((Some(addr.dial_tcp().await?), None)),
-            DualAddr::V6(addr) => Ok((None, Some(addr.dial_tcp().await?))),
+            DualAddr::V4(addr) => match opts.connect_tcp(addr.into()).await {
+                Ok(stream) => Ok((Some(stream), None)),
+                Err(err) => Err(DualError::v4(err)),
+            },
+            DualAddr::V6(addr) => match opts.connect_tcp(addr.into()).await {
+                Ok(stream) => Ok((None, Some(stream))),
+                Err(err) => Err(DualError::v6(err)),
+            },
             DualAddr::Both(addr_v4, addr_v6) => {
-                any_success(addr_v4.dial_tcp().await, addr_v6.dial_tcp().await)
+                let (v4, v6) = opts
+                    .connect_tcp(addr_v4.into())
+                    .join(opts.connect_tcp(addr_v6.into()))
+                    .await;
+                any_success(v4, v6)
             }
         }
     }
 
-    pub async fn dial_udp(&self) -> io::Result<(Option<UdpSocket>, Option<UdpSocket>)> {
+    pub async fn dial_udp_with(
+        &self,
+        opts: &DialOptions,
+    ) -> Result<(Option<UdpSocket>, Option<UdpSocket>), DualError> {
         match self {
-            DualAddr::V4(addr) => Ok((Some(addr.dial_udp().await?), None)),
-            DualAddr::V6(addr) => Ok((None, Some(addr.dial_udp().await?))),
+            DualAddr::V4(addr) => match opts.connect_udp((*addr).into()).await {
+                Ok(sock) => Ok((Some(sock), None)),
+                Err(err) => Err(DualError::v4(err)),
+            },
+            DualAddr::V6(addr) => match opts.connect_udp((*addr).into()).await {
+                Ok(sock) => Ok((None, Some(sock))),
+                Err(err) => Err(DualError::v6(err)),
+            },
             DualAddr::Both(addr_v4, addr_v6) => {
-                any_success(addr_v4.dial_udp().await, addr_v6.dial_udp().await)
+                let (v4, v6) = opts
+                    .connect_udp((*addr_v4).into())
+                    .join(opts.connect_udp((*addr_v6).into()))
+                    .await;
+                any_success(v4, v6)
             }
         }
     }
 
-    pub async fn dial_icmp(&self) -> io::Result<(Option<UdpSocket>, Option<UdpSocket>)> {
+    pub async fn dial_icmp_with(
+        &self,
+        opts: &DialOptions,
+    ) -> Result<(Option<UdpSocket>, Option<UdpSocket>), DualError> {
         match self {
-            DualAddr::V4(addr) => Ok((Some(addr.dial_icmpv4().await?), None)),
-            DualAddr::V6(addr) => Ok((None, Some(addr.dial_icmpv6().await?))),
+            DualAddr::V4(addr) => match opts.connect_icmp((*addr).into()).await {
+                Ok(sock) => Ok((Some(sock), None)),
+                Err(err) => Err(DualError::v4(err)),
+            },
+            DualAddr::V6(addr) => match opts.connect_icmp((*addr).into()).await {
+                Ok(sock) => Ok((None, Some(sock))),
+                Err(err) => Err(DualError::v6(err)),
+            },
             DualAddr::Both(addr_v4, addr_v6) => {
-                any_success(addr_v4.dial_icmpv4().await, addr_v6.dial_icmpv6().await)
+                let (v4, v6) = opts
+                    .connect_icmp((*addr_v4).into())
+                    .join(opts.connect_icmp((*addr_v6).into()))
+                    .await;
+                any_success(v4, v6)
             }
         }
     }
@@ -150,8 +887,8 @@ impl DualAddr {
 pub fn new_udp_pair() -> io::Result<(UdpSocket, UdpSocket)> {
     let zero = bind(BindType::IPv4Udp, LOCAL_LISTEN_ADDR).map(|sock| sock.into_udp_socket())?;
     let one = bind(BindType::IPv4Udp, LOCAL_LISTEN_ADDR).map(|sock| sock.into_udp_socket())?;
-    zero.connect(&one.local_addr()?)?;
-    one.connect(&zero.local_addr()?)?;
+    zero.connect(one.local_addr()?)?;
+    one.connect(zero.local_addr()?)?;
     Ok((zero.into(), one.into()))
 }
 
@@ -168,13 +905,349 @@ mod tests {
         assert_eq!(a.peer_addr().unwrap(), b.local_addr().unwrap());
     }
 
+    #[cfg(unix)]
+    fn get_int_opt(
+        sock: &impl std::os::unix::io::AsRawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+    ) -> u32 {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        value as u32
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_options() {
+        let sock = bind(BindType::IPv4Tcp, "127.0.0.1:0").unwrap();
+        assert!(!sock.reuse_address().unwrap());
+
+        let opts = BindOptions::new()
+            .backlog(128)
+            .reuse_address(true)
+            .reuse_port(true)
+            .recv_buffer_size(1 << 18)
+            .send_buffer_size(1 << 18)
+            .tos(0xb8)
+            .ttl(7)
+            .nonblocking(true);
+        let a = opts.bind(BindType::IPv4Udp, "127.0.0.1:0").unwrap();
+        let addr = a.local_addr().unwrap();
+        let b = opts
+            .bind(BindType::IPv4Udp, &addr.as_std().unwrap().to_string())
+            .unwrap();
+        assert!(b.reuse_address().unwrap());
+        assert!(b.reuse_port().unwrap());
+        assert!(b.recv_buffer_size().unwrap() >= 1 << 18);
+        assert!(b.send_buffer_size().unwrap() >= 1 << 18);
+        assert_eq!(b.ttl().unwrap(), 7);
+        assert_eq!(get_int_opt(&b, libc::IPPROTO_IP, libc::IP_TOS), 0xb8);
+        assert_eq!(
+            b.recv(&mut [0u8; 1]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let v6 = BindOptions::new()
+            .only_v6(false)
+            .tos(0x20)
+            .ttl(9)
+            .bind(BindType::IPv6Tcp, "[::]:0")
+            .unwrap();
+        assert!(!v6.only_v6().unwrap());
+        assert_eq!(v6.unicast_hops_v6().unwrap(), 9);
+        assert_eq!(
+            get_int_opt(&v6, libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+            0x20
+        );
+        assert!(bind(BindType::IPv6Udp, "[::1]:0")
+            .unwrap()
+            .only_v6()
+            .unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn skip_unprivileged<T>(res: io::Result<T>) -> Option<T> {
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => None,
+            res => Some(res.unwrap()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_routing_options() {
+        let free = BindOptions::new()
+            .freebind(true)
+            .bind(BindType::IPv4Udp, "192.0.2.1:0");
+        if let Some(sock) = skip_unprivileged(free) {
+            assert_eq!(get_int_opt(&sock, libc::IPPROTO_IP, libc::IP_FREEBIND), 1);
+        }
+        let transparent = BindOptions::new()
+            .transparent(true)
+            .bind(BindType::IPv6Tcp, "[::1]:0");
+        if let Some(sock) = skip_unprivileged(transparent) {
+            assert_eq!(
+                get_int_opt(&sock, libc::IPPROTO_IPV6, libc::IPV6_TRANSPARENT),
+                1
+            );
+        }
+
+        async_std::task::block_on(async {
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let opts = DialOptions::new().bind_device("lo");
+            let stream = addr.dial_tcp_with(&opts).await.unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), addr);
+            let udp = addr.dial_udp_with(&opts).await.unwrap();
+            assert_eq!(udp.peer_addr().unwrap(), addr);
+
+            let marked = addr.dial_tcp_with(&DialOptions::new().mark(0x2a)).await;
+            if let Some(stream) = skip_unprivileged(marked) {
+                assert_eq!(get_int_opt(&stream, libc::SOL_SOCKET, libc::SO_MARK), 0x2a);
+            }
+
+            let invalid = DialOptions::new().bind_device("no\0such");
+            assert_eq!(
+                addr.dial_udp_with(&invalid).await.unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+
+            // The nonblocking connect reports failures once the socket turns writable.
+            drop(listener);
+            assert_eq!(
+                addr.dial_tcp_with(&opts).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionRefused
+            );
+        });
+    }
+
+    async fn local_listeners() -> (
+        async_std::net::TcpListener,
+        async_std::net::TcpListener,
+        DualAddr,
+    ) {
+        let v4 = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let v6 = async_std::net::TcpListener::bind("[::1]:0").await.unwrap();
+        let addr = match (v4.local_addr().unwrap(), v6.local_addr().unwrap()) {
+            (SocketAddr::V4(a), SocketAddr::V6(b)) => DualAddr::Both(a, b),
+            _ => unreachable!(),
+        };
+        (v4, v6, addr)
+    }
+
+    #[test]
+    fn test_happy_eyeballs() {
+        use async_std::prelude::FutureExt;
+        use std::time::Instant;
+
+        async_std::task::block_on(async {
+            // IPv6 wins when it connects within the delay; IPv4 is never attempted.
+            let (v4, _v6, addr) = local_listeners().await;
+            let stream = addr
+                .happy_eyeballs_with(&DialOptions::new(), Duration::from_millis(100))
+                .await
+                .unwrap();
+            assert!(stream.peer_addr().unwrap().is_ipv6());
+            assert!(v4
+                .accept()
+                .timeout(Duration::from_millis(300))
+                .await
+                .is_err());
+
+            // A refused IPv6 attempt starts IPv4 right away instead of waiting for the delay.
+            let (_v4, v6, addr) = local_listeners().await;
+            drop(v6);
+            let start = Instant::now();
+            let stream = addr
+                .happy_eyeballs_with(&DialOptions::new(), Duration::from_secs(10))
+                .await
+                .unwrap();
+            assert!(stream.peer_addr().unwrap().is_ipv4());
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            let (v4, v6, addr) = local_listeners().await;
+            drop((v4, v6));
+            let err = addr.happy_eyeballs().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(err.to_string().contains("IPv4"));
+        });
+    }
+
+    #[test]
+    fn test_happy_eyeballs_cancels_loser() {
+        use async_std::prelude::FutureExt;
+
+        async_std::task::block_on(async {
+            // A full accept queue drops SYNs, so IPv6 hangs until its SYN is retransmitted.
+            let (v4, _, _) = local_listeners().await;
+            let sock = Socket::new(Domain::ipv6(), Type::stream(), None).unwrap();
+            sock.bind(&"[::1]:0".parse::<SocketAddr>().unwrap().into())
+                .unwrap();
+            sock.listen(0).unwrap();
+            let v6 = async_std::net::TcpListener::from(sock.into_tcp_listener());
+            let addr_v6 = match v6.local_addr().unwrap() {
+                SocketAddr::V6(addr) => addr,
+                _ => unreachable!(),
+            };
+            let _queued = std::net::TcpStream::connect(addr_v6).unwrap();
+            let addr_v4 = match v4.local_addr().unwrap() {
+                SocketAddr::V4(addr) => addr,
+                _ => unreachable!(),
+            };
+
+            let opts = DialOptions::new().bind_device("lo");
+            let stream = DualAddr::Both(addr_v4, addr_v6)
+                .happy_eyeballs_with(&opts, Duration::from_millis(50))
+                .await
+                .unwrap();
+            assert!(stream.peer_addr().unwrap().is_ipv4());
+            v4.accept().await.unwrap();
+
+            // Once there is room, only the cancelled attempt's retransmitted SYN could connect.
+            v6.accept().await.unwrap();
+            assert!(v6
+                .accept()
+                .timeout(Duration::from_millis(2500))
+                .await
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn test_dual_dial() {
+        async_std::task::block_on(async {
+            let (_v4, _v6, addr) = local_listeners().await;
+            let (v4, v6) = addr.dial_tcp().await.unwrap();
+            assert!(v4.unwrap().peer_addr().unwrap().is_ipv4());
+            assert!(v6.unwrap().peer_addr().unwrap().is_ipv6());
+
+            let (_v4, v6, addr) = local_listeners().await;
+            drop(v6);
+            let (v4, v6) = addr.dial_tcp().await.unwrap();
+            assert!(v4.is_some() && v6.is_none());
+
+            let (v4, v6, addr) = local_listeners().await;
+            drop((v4, v6));
+            let err = addr.dial_tcp().await.unwrap_err();
+            assert_eq!(err.v4.unwrap().kind(), io::ErrorKind::ConnectionRefused);
+            assert_eq!(err.v6.unwrap().kind(), io::ErrorKind::ConnectionRefused);
+
+            // Both failures survive the conversion to io::Error.
+            let err = io::Error::from(addr.dial_tcp().await.unwrap_err());
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let dual = err.get_ref().unwrap().downcast_ref::<DualError>().unwrap();
+            assert!(dual.v4.is_some() && dual.v6.is_some());
+
+            let v4 = match addr {
+                DualAddr::Both(v4, _) => DualAddr::V4(v4),
+                _ => unreachable!(),
+            };
+            let err = io::Error::from(v4.dial_tcp().await.unwrap_err());
+            assert!(err.get_ref().is_none());
+        });
+    }
+
+    #[test]
+    fn test_dual_addr_parse() {
+        let v4: SocketAddrV4 = "1.2.3.4:80".parse().unwrap();
+        let v6: SocketAddrV6 = "[2001:db8::1]:443".parse().unwrap();
+        for addr in &[DualAddr::V4(v4), DualAddr::V6(v6), DualAddr::Both(v4, v6)] {
+            assert_eq!(addr.to_string().parse::<DualAddr>().unwrap(), *addr);
+        }
+        assert_eq!(
+            "[2001:db8::1]:443, 1.2.3.4:80".parse::<DualAddr>().unwrap(),
+            DualAddr::Both(v4, v6)
+        );
+        assert_eq!(DualAddr::from(SocketAddr::V6(v6)), DualAddr::V6(v6));
+        for s in &["1.2.3.4:80,5.6.7.8:80", "localhost:80", "1.2.3.4", ""] {
+            assert_eq!(
+                s.parse::<DualAddr>().unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn test_dual_addr_resolve() {
+        let v4: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let v4b: SocketAddr = "10.0.0.2:53".parse().unwrap();
+        let v6: SocketAddr = "[fd00::1]:53".parse().unwrap();
+        let both = DualAddr::from_addrs(vec![v6, v4, v4b], FamilyPreference::Both).unwrap();
+        assert_eq!(both.to_string(), "10.0.0.1:53,[fd00::1]:53");
+        let addrs = || vec![v4, v6];
+        assert_eq!(
+            DualAddr::from_addrs(addrs(), FamilyPreference::PreferV6),
+            Some(DualAddr::from(v6))
+        );
+        assert_eq!(
+            DualAddr::from_addrs(addrs(), FamilyPreference::PreferV4),
+            Some(DualAddr::from(v4))
+        );
+        assert_eq!(
+            DualAddr::from_addrs(vec![v4], FamilyPreference::PreferV6),
+            Some(DualAddr::from(v4))
+        );
+        assert_eq!(
+            DualAddr::from_addrs(vec![v4], FamilyPreference::Both),
+            Some(DualAddr::from(v4))
+        );
+        assert_eq!(
+            DualAddr::from_addrs(vec![v4], FamilyPreference::V6Only),
+            None
+        );
+        assert_eq!(DualAddr::from_addrs(vec![], FamilyPreference::Both), None);
+
+        async_std::task::block_on(async {
+            assert_eq!(
+                DualAddr::resolve("127.0.0.1:80").await.unwrap(),
+                DualAddr::from(SocketAddr::from(([127, 0, 0, 1], 80)))
+            );
+            let addr = DualAddr::resolve_prefer("[::1]:80", FamilyPreference::V4Only).await;
+            assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            assert!(DualAddr::resolve("localhost:80").await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_bind_invalid_addr() {
+        for (r#type, addr) in &[
+            (BindType::IPv4Udp, "[::1]:0"),
+            (BindType::IPv4Tcp, "localhost:80"),
+            (BindType::IPv6Udp, "127.0.0.1:0"),
+            (BindType::IPv6Tcp, ""),
+        ] {
+            assert_eq!(
+                bind(*r#type, addr).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
     #[test]
     fn test_dial() {
         let server = bind(BindType::IPv4Udp, "0.0.0.0:8964").unwrap();
-        let client = SocketAddr::from(([127, 0, 0, 1], 8964)).dial_udp().unwrap();
         let data = &[1, 2, 3, 4u8];
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(client.send(data)).unwrap();
+        async_std::task::block_on(async {
+            let client = SocketAddr::from(([127, 0, 0, 1], 8964))
+                .dial_udp()
+                .await
+                .unwrap();
+            client.send(data).await.unwrap();
+        });
         let buf = &mut [0u8; 1 << 16];
         let n = server.recv(buf).unwrap();
         assert_eq!(n, data.len());
@@ -0,0 +1,49 @@
+use std::fmt;
+use std::io;
+
+pub const IP_PROTO_HOPOPT: u8 = 0;
+pub const IP_PROTO_ICMP: u8 = 1;
+pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+pub const IP_PROTO_IPV6_ROUTE: u8 = 43;
+pub const IP_PROTO_IPV6_FRAG: u8 = 44;
+pub const IP_PROTO_ICMPV6: u8 = 58;
+pub const IP_PROTO_IPV6_NONXT: u8 = 59;
+pub const IP_PROTO_IPV6_OPTS: u8 = 60;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PacketError {
+    /// The buffer is shorter than the header or length fields require.
+    Truncated,
+    /// The version nibble does not match the parser.
+    Version(u8),
+    /// The header length field is out of range.
+    HeaderLen(usize),
+    /// The total or payload length field is out of range.
+    TotalLen(usize),
+    /// The options do not fit the header.
+    Options(usize),
+    /// The packet would exceed the maximum size of its length field.
+    TooLarge(usize),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Truncated => write!(f, "packet truncated"),
+            PacketError::Version(v) => write!(f, "unexpected ip version {}", v),
+            PacketError::HeaderLen(l) => write!(f, "invalid header length {}", l),
+            PacketError::TotalLen(l) => write!(f, "invalid total length {}", l),
+            PacketError::Options(l) => write!(f, "invalid options length {}", l),
+            PacketError::TooLarge(l) => write!(f, "packet too large ({} bytes)", l),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl From<PacketError> for io::Error {
+    fn from(err: PacketError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
@@ -0,0 +1,289 @@
+use crate::ext::random_u16;
+use crate::{
+    Buffer, DatagramExt, IcmpPacket, Icmpv4Message, Icmpv6Message, Ipv4Packet, SocketAddrExt,
+};
+use async_std::net::{SocketAddr, UdpSocket};
+use async_std::prelude::FutureExt;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Payload size of the echo requests sent by [`Pinger`], as with `ping(8)`.
+pub const PING_PAYLOAD_SIZE: usize = 56;
+
+/// Kind of socket a [`Pinger`] sends through.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum PingSocket {
+    /// Raw ICMP socket, needs CAP_NET_RAW.
+    Raw,
+    /// Unprivileged `SOCK_DGRAM` ICMP socket, allowed by `net.ipv4.ping_group_range`. The
+    /// kernel picks the identifier and fills in the checksum.
+    Datagram,
+}
+
+/// Echo reply received by [`Pinger::ping`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PingReply {
+    pub sequence: u16,
+    pub rtt: Duration,
+    /// ICMP message size, header included.
+    pub size: usize,
+    /// TTL of the reply, when the socket exposes the IP header.
+    pub ttl: Option<u8>,
+}
+
+/// Sends ICMP echo requests to one host and matches the replies.
+#[derive(Debug)]
+pub struct Pinger {
+    sock: UdpSocket,
+    target: IpAddr,
+    kind: PingSocket,
+    identifier: u16,
+    sequence: u16,
+    payload: Vec<u8>,
+    buf: Buffer,
+}
+
+impl Pinger {
+    /// Opens a raw socket to `target`, falling back to a datagram socket without the
+    /// privileges for it.
+    pub async fn new(target: IpAddr) -> io::Result<Self> {
+        match Self::raw(target).await {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                Self::datagram(target).await
+            }
+            res => res,
+        }
+    }
+
+    pub async fn raw(target: IpAddr) -> io::Result<Self> {
+        let addr = SocketAddr::new(target, 0);
+        let sock = match target {
+            IpAddr::V4(_) => addr.dial_icmpv4().await?,
+            IpAddr::V6(_) => addr.dial_icmpv6().await?,
+        };
+        let identifier = random_u16();
+        Ok(Self::with_socket(sock, target, PingSocket::Raw, identifier))
+    }
+
+    pub async fn datagram(target: IpAddr) -> io::Result<Self> {
+        let (domain, protocol) = match target {
+            IpAddr::V4(_) => (Domain::ipv4(), Protocol::icmpv4()),
+            IpAddr::V6(_) => (Domain::ipv6(), Protocol::icmpv6()),
+        };
+        let sock = Socket::new(domain, Type::dgram(), Some(protocol))?;
+        let sock = UdpSocket::from(sock.into_udp_socket());
+        sock.connect(SocketAddr::new(target, 0)).await?;
+        // The kernel replaces the identifier with the socket's local "port".
+        let identifier = sock.local_addr()?.port();
+        Ok(Self::with_socket(
+            sock,
+            target,
+            PingSocket::Datagram,
+            identifier,
+        ))
+    }
+
+    fn with_socket(sock: UdpSocket, target: IpAddr, kind: PingSocket, identifier: u16) -> Self {
+        Pinger {
+            sock,
+            target,
+            kind,
+            identifier,
+            sequence: 0,
+            payload: (0..PING_PAYLOAD_SIZE).map(|i| i as u8).collect(),
+            buf: Buffer::new(),
+        }
+    }
+
+    /// Sets the payload carried by the following echo requests.
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    pub fn target(&self) -> IpAddr {
+        self.target
+    }
+
+    pub fn kind(&self) -> PingSocket {
+        self.kind
+    }
+
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    /// Sequence number of the last echo request sent.
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Sends the next echo request and waits up to `timeout` for its reply.
+    ///
+    /// Replies to earlier requests that arrive late are skipped.
+    pub async fn ping(&mut self, timeout: Duration) -> io::Result<PingReply> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let (identifier, sequence) = (self.identifier, self.sequence);
+        // The kernel fills in ICMPv6 checksums, which need the source address.
+        let request = match self.target {
+            IpAddr::V4(_) => Icmpv4Message::EchoRequest {
+                identifier,
+                sequence,
+            }
+            .emit(&self.payload),
+            IpAddr::V6(_) => Icmpv6Message::EchoRequest {
+                identifier,
+                sequence,
+            }
+            .emit_unchecked(&self.payload),
+        };
+        let start = Instant::now();
+        self.sock.send_all(&request).await?;
+        match self.recv_reply(start).timeout(timeout).await {
+            Ok(reply) => reply,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no echo reply from {}", self.target),
+            )),
+        }
+    }
+
+    async fn recv_reply(&mut self, start: Instant) -> io::Result<PingReply> {
+        loop {
+            self.sock.recv_buf(&mut self.buf).await?;
+            let rtt = start.elapsed();
+            // Raw IPv4 sockets see the IP header, every other kind gets the bare message.
+            let (message, ttl) = match (self.kind, self.target) {
+                (PingSocket::Raw, IpAddr::V4(_)) => match Ipv4Packet::new(&self.buf[..]) {
+                    Ok(packet) => {
+                        let start = packet.header_len();
+                        let end = packet.total_len() as usize;
+                        (&self.buf[start..end], Some(packet.ttl()))
+                    }
+                    Err(_) => continue,
+                },
+                _ => (&self.buf[..], None),
+            };
+            let (identifier, sequence) = match echo_reply(message, self.target.is_ipv6()) {
+                Some(echo) => echo,
+                None => continue,
+            };
+            // Datagram sockets only deliver replies carrying their own identifier.
+            if self.kind == PingSocket::Raw && identifier != self.identifier {
+                continue;
+            }
+            if sequence != self.sequence {
+                continue;
+            }
+            return Ok(PingReply {
+                sequence,
+                rtt,
+                size: message.len(),
+                ttl,
+            });
+        }
+    }
+}
+
+/// Returns the identifier and sequence of an echo reply.
+fn echo_reply(message: &[u8], ipv6: bool) -> Option<(u16, u16)> {
+    let packet = IcmpPacket::new(message).ok()?;
+    if ipv6 {
+        // The kernel has already verified ICMPv6 checksums, which need the pseudo-header.
+        match packet.icmpv6_message() {
+            Icmpv6Message::EchoReply {
+                identifier,
+                sequence,
+            } if packet.code() == 0 => Some((identifier, sequence)),
+            _ => None,
+        }
+    } else {
+        match packet.icmpv4_message() {
+            Icmpv4Message::EchoReply {
+                identifier,
+                sequence,
+            } if packet.code() == 0 && packet.verify_checksum_v4() => Some((identifier, sequence)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn test_echo_reply() {
+        let reply = Icmpv4Message::EchoReply {
+            identifier: 0x1234,
+            sequence: 7,
+        };
+        let mut b = reply.emit(b"abcd");
+        assert_eq!(echo_reply(&b, false), Some((0x1234, 7)));
+        assert_eq!(echo_reply(&b, true), None);
+        b[9] ^= 1;
+        assert_eq!(echo_reply(&b, false), None);
+        assert_eq!(echo_reply(&b[..7], false), None);
+        let request = Icmpv4Message::EchoRequest {
+            identifier: 0x1234,
+            sequence: 7,
+        };
+        assert_eq!(echo_reply(&request.emit(&[]), false), None);
+
+        let v6 = Icmpv6Message::EchoReply {
+            identifier: 1,
+            sequence: 2,
+        }
+        .emit_unchecked(&[]);
+        assert_eq!(echo_reply(&v6, true), Some((1, 2)));
+    }
+
+    fn skip_unprivileged(res: io::Result<Pinger>) -> Option<Pinger> {
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => None,
+            res => Some(res.unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_ping_loopback() {
+        task::block_on(async {
+            for target in &["127.0.0.1", "::1"] {
+                let target: IpAddr = target.parse().unwrap();
+                let pinger = match skip_unprivileged(Pinger::new(target).await) {
+                    Some(pinger) => pinger,
+                    None => continue,
+                };
+                let mut pinger = pinger.payload(&[0x5a; 100]);
+                for sequence in 1..=3 {
+                    let reply = pinger.ping(Duration::from_secs(2)).await.unwrap();
+                    assert_eq!(reply.sequence, sequence);
+                    assert_eq!(reply.size, crate::ICMP_HEADER_LEN + 100);
+                    assert!(reply.rtt < Duration::from_secs(2));
+                    if pinger.kind() == PingSocket::Raw && target.is_ipv4() {
+                        assert!(reply.ttl.is_some());
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_ping_datagram() {
+        task::block_on(async {
+            // Unprivileged ping sockets depend on net.ipv4.ping_group_range.
+            let pinger = match skip_unprivileged(Pinger::datagram([127, 0, 0, 1].into()).await) {
+                Some(pinger) => pinger,
+                None => return,
+            };
+            let mut pinger = pinger;
+            assert_eq!(pinger.kind(), PingSocket::Datagram);
+            let reply = pinger.ping(Duration::from_secs(2)).await.unwrap();
+            assert_eq!(reply.sequence, 1);
+            assert_eq!(reply.ttl, None);
+        });
+    }
+}
@@ -0,0 +1,188 @@
+use crate::STREAM_BUF_SIZE;
+use async_std::future;
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std::net::TcpStream;
+use async_std::prelude::FutureExt;
+use async_std::task;
+use std::io;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Streams whose write half can be shut down while the read half keeps working.
+pub trait HalfClose {
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// Bytes copied by [`relay`] in each direction.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct RelayStats {
+    /// Bytes read from the first stream and written to the second.
+    pub a_to_b: u64,
+    /// Bytes read from the second stream and written to the first.
+    pub b_to_a: u64,
+    /// Whether the relay ended because of the idle timeout.
+    pub timed_out: bool,
+}
+
+struct Activity {
+    start: Instant,
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        let now = self.start.elapsed().as_nanos() as u64;
+        self.last.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.start.elapsed() - Duration::from_nanos(self.last.load(Ordering::Relaxed))
+    }
+}
+
+async fn copy_half<R, W>(
+    mut src: R,
+    mut dst: W,
+    copied: &AtomicU64,
+    activity: &Activity,
+) -> io::Result<()>
+where
+    R: Read + Unpin,
+    W: Write + HalfClose + Unpin,
+{
+    let mut buf = vec![0u8; STREAM_BUF_SIZE];
+    loop {
+        let n = src.read(&mut buf).await?;
+        if n == 0 {
+            dst.flush().await?;
+            return dst.shutdown_write();
+        }
+        dst.write_all(&buf[..n]).await?;
+        copied.fetch_add(n as u64, Ordering::Relaxed);
+        activity.touch();
+    }
+}
+
+async fn watchdog(activity: &Activity, idle_timeout: Option<Duration>) {
+    let idle_timeout = match idle_timeout {
+        Some(idle_timeout) => idle_timeout,
+        None => return future::pending().await,
+    };
+    loop {
+        let idle = activity.idle();
+        if idle >= idle_timeout {
+            return;
+        }
+        task::sleep(idle_timeout - idle).await;
+    }
+}
+
+/// Copies data between `a` and `b` in both directions until both sides have finished.
+///
+/// End of stream on one side shuts down the write half of the other, so half-closed
+/// connections keep flowing in the open direction. With `idle_timeout` set, the relay stops
+/// once no data moved in either direction for that long and reports it in
+/// [`RelayStats::timed_out`].
+pub async fn relay<A, B>(a: A, b: B, idle_timeout: Option<Duration>) -> io::Result<RelayStats>
+where
+    A: Read + Write + HalfClose + Clone + Unpin,
+    B: Read + Write + HalfClose + Clone + Unpin,
+{
+    let (a_to_b, b_to_a) = (AtomicU64::new(0), AtomicU64::new(0));
+    let activity = Activity {
+        start: Instant::now(),
+        last: AtomicU64::new(0),
+    };
+    let transfer = async {
+        copy_half(a.clone(), b.clone(), &a_to_b, &activity)
+            .try_join(copy_half(b, a, &b_to_a, &activity))
+            .await
+            .map(|_| false)
+    };
+    let timed_out = transfer
+        .race(async {
+            watchdog(&activity, idle_timeout).await;
+            Ok(true)
+        })
+        .await?;
+    Ok(RelayStats {
+        a_to_b: a_to_b.load(Ordering::Relaxed),
+        b_to_a: b_to_a.load(Ordering::Relaxed),
+        timed_out,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_relay_half_close() {
+        task::block_on(async {
+            let (mut client, a) = pair().await;
+            let (b, mut upstream) = pair().await;
+            let relayed = task::spawn(relay(a, b, None));
+
+            client.write_all(&[1u8; 100_000]).await.unwrap();
+            client.shutdown_write().unwrap();
+            let mut received = Vec::new();
+            upstream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, vec![1u8; 100_000]);
+
+            // The other direction still works after the client's half-close.
+            upstream.write_all(b"response").await.unwrap();
+            upstream.shutdown_write().unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"response");
+
+            assert_eq!(
+                relayed.await.unwrap(),
+                RelayStats {
+                    a_to_b: 100_000,
+                    b_to_a: 8,
+                    timed_out: false
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_relay_idle_timeout() {
+        task::block_on(async {
+            let (mut client, a) = pair().await;
+            let (b, mut upstream) = pair().await;
+            let relayed = task::spawn(relay(a, b, Some(Duration::from_millis(200))));
+
+            for _ in 0..3 {
+                client.write_all(b"ping").await.unwrap();
+                let mut buf = [0u8; 4];
+                upstream.read_exact(&mut buf).await.unwrap();
+                upstream.write_all(b"pong").await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+                task::sleep(Duration::from_millis(100)).await;
+            }
+            let stats = relayed.await.unwrap();
+            assert_eq!(stats.a_to_b, 12);
+            assert_eq!(stats.b_to_a, 12);
+            assert!(stats.timed_out);
+        });
+    }
+}
@@ -0,0 +1,2093 @@
+use crate::{
+    fragment_ipv4, Buffer, HalfClose, Ipv4Builder, Ipv4Packet, Ipv4Reassembler, Ipv6Builder,
+    Ipv6Packet, PacketDevice, TcpBuilder, TcpOption, TcpPacket, UdpBuilder, UdpPacket,
+    IPV4_HEADER_LEN, IPV6_HEADER_LEN, IP_PROTO_TCP, IP_PROTO_UDP, TCP_ACK, TCP_FIN, TCP_HEADER_LEN,
+    TCP_PSH, TCP_RST, TCP_SYN,
+};
+use async_std::channel::{self, Receiver, Sender};
+use async_std::io::{Read, Write};
+use async_std::net::SocketAddr;
+use async_std::prelude::{FutureExt, StreamExt};
+use async_std::stream::{self, Stream};
+use async_std::task;
+use bytes::{Bytes, BytesMut};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+// Granularity of the retransmission, TIME-WAIT and UDP expiry timers.
+const STACK_TICK: Duration = Duration::from_millis(10);
+
+// RFC 6298 retransmission timeout bounds.
+const TCP_RTO_INITIAL: Duration = Duration::from_secs(1);
+const TCP_RTO_MIN: Duration = Duration::from_millis(200);
+const TCP_RTO_MAX: Duration = Duration::from_secs(60);
+const TCP_SYN_RETRIES: u32 = 5;
+const TCP_MAX_RETRIES: u32 = 12;
+const TCP_DUP_ACK_THRESHOLD: u32 = 3;
+const TCP_TIME_WAIT: Duration = Duration::from_secs(60);
+// How long a connection closed by the application waits for the peer's FIN.
+const TCP_FIN_TIMEOUT: Duration = Duration::from_secs(60);
+const TCP_MAX_WINDOW_SHIFT: u8 = 14;
+// MSS assumed when the SYN carries none, RFC 9293 section 3.7.1.
+const TCP_DEFAULT_MSS_V4: usize = 536;
+const TCP_DEFAULT_MSS_V6: usize = 1220;
+
+// Datagrams queued on a UDP flow before new ones are dropped.
+const UDP_FLOW_QUEUE_LEN: usize = 64;
+// Datagrams queued towards the device before UDP sends wait; TCP is bounded by its windows.
+const UDP_OUT_QUEUE_LEN: usize = 256;
+
+/// Options for a [`Stack`], see [`StackOptions::start`].
+#[derive(Debug, Clone)]
+pub struct StackOptions {
+    mtu: usize,
+    send_buffer_size: usize,
+    recv_buffer_size: usize,
+    backlog: usize,
+    udp_timeout: Duration,
+}
+
+impl Default for StackOptions {
+    fn default() -> Self {
+        StackOptions {
+            mtu: 1500,
+            send_buffer_size: 256 * 1024,
+            recv_buffer_size: 256 * 1024,
+            backlog: 128,
+            udp_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl StackOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// MTU of the device, which bounds the MSS announced to peers. Clamped to the largest
+    /// IP packet.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu.min(u16::MAX as usize);
+        self
+    }
+
+    /// Bytes a TCP stream buffers before writes wait for the peer to acknowledge.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = size;
+        self
+    }
+
+    /// Bytes a TCP stream buffers before its receive window closes.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// Established connections and new UDP flows waiting to be accepted; more are refused.
+    /// Also bounds the connections still in their handshake, beyond which SYNs are dropped.
+    pub fn backlog(mut self, backlog: usize) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Idle time after which a UDP flow expires.
+    pub fn udp_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_timeout = timeout;
+        self
+    }
+
+    /// Starts a stack on `device` in a background task.
+    pub fn start<D: PacketDevice + 'static>(self, device: D) -> Stack {
+        let (tcp_tx, tcp_rx) = channel::bounded(self.backlog.max(1));
+        let (udp_tx, udp_rx) = channel::bounded(self.backlog.max(1));
+        let (out_tx, out_rx) = channel::unbounded();
+        let (udp_out_tx, udp_out_rx) = channel::bounded(UDP_OUT_QUEUE_LEN);
+        let (stop_tx, stop_rx) = channel::bounded(1);
+        let shared = Arc::new(Mutex::new(State {
+            options: self,
+            out: out_tx,
+            udp_out: udp_out_tx,
+            tcp_accept: tcp_tx,
+            udp_accept: udp_tx,
+            tcp: HashMap::new(),
+            tcp_index: HashMap::new(),
+            udp: HashMap::new(),
+            udp_index: HashMap::new(),
+            fragments: Ipv4Reassembler::new(),
+            next_id: 0,
+            next_ip_id: 0,
+            isn_key: RandomState::new(),
+            start: Instant::now(),
+            stopped: None,
+        }));
+        task::spawn(run(
+            shared.clone(),
+            device,
+            out_rx.merge(udp_out_rx),
+            stop_rx,
+        ));
+        Stack {
+            shared,
+            tcp_accept: tcp_rx,
+            udp_accept: udp_rx,
+            _stop: stop_tx,
+        }
+    }
+}
+
+/// Userspace TCP/IP stack terminating the TCP connections and UDP flows that arrive on a
+/// [`PacketDevice`], whatever their destination, as in "tun2socks".
+///
+/// Each flow is accepted as a [`StackTcpStream`] or [`StackUdpSocket`] whose local address
+/// is the destination the peer asked for, ready to be relayed to a socket dialed there.
+/// Dropping the stack stops it; open streams and sockets then fail with `NotConnected`.
+pub struct Stack {
+    shared: Shared,
+    tcp_accept: Receiver<StackTcpStream>,
+    udp_accept: Receiver<StackUdpSocket>,
+    _stop: Sender<()>,
+}
+
+impl Stack {
+    pub fn new<D: PacketDevice + 'static>(device: D) -> Self {
+        StackOptions::new().start(device)
+    }
+
+    /// Waits for the next TCP connection to complete its handshake.
+    pub async fn accept_tcp(&self) -> io::Result<StackTcpStream> {
+        self.tcp_accept.recv().await.map_err(|_| self.stopped())
+    }
+
+    /// Waits for the first datagram of a new UDP flow.
+    pub async fn accept_udp(&self) -> io::Result<StackUdpSocket> {
+        self.udp_accept.recv().await.map_err(|_| self.stopped())
+    }
+
+    fn stopped(&self) -> io::Error {
+        match &self.shared.lock().unwrap().stopped {
+            Some((kind, message)) => io::Error::new(*kind, message.clone()),
+            None => not_connected(),
+        }
+    }
+}
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stack").finish_non_exhaustive()
+    }
+}
+
+type Shared = Arc<Mutex<State>>;
+
+async fn run<D: PacketDevice>(
+    shared: Shared,
+    device: D,
+    out: impl Stream<Item = Bytes> + Unpin,
+    stop: Receiver<()>,
+) {
+    let reader = async {
+        let mut buf = Buffer::new();
+        loop {
+            device.recv(&mut buf).await?;
+            let refused = shared.lock().unwrap().input(&shared, &buf, Instant::now());
+            // Dropping a refused handle takes the lock again.
+            drop(refused);
+        }
+    };
+    let writer = async {
+        let mut out = out;
+        while let Some(packet) = out.next().await {
+            device.send(&packet).await?;
+        }
+        Ok(())
+    };
+    let timer = async {
+        let mut ticks = stream::interval(STACK_TICK);
+        while ticks.next().await.is_some() {
+            shared.lock().unwrap().poll(Instant::now());
+        }
+        Ok(())
+    };
+    let stopped = async {
+        let _ = stop.recv().await;
+        Err(io::Error::new(io::ErrorKind::NotConnected, "stack stopped"))
+    };
+    let err = match reader.race(writer).race(timer).race(stopped).await {
+        Ok(()) => not_connected(),
+        Err(err) => err,
+    };
+    let senders = shared.lock().unwrap().stop(err);
+    // Queued handles lock the state when dropped with the channels.
+    drop(senders);
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "stack stopped")
+}
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// Both ends of a flow: `local` is the destination the peer addressed, `remote` the peer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+struct FlowKey {
+    local: SocketAddr,
+    remote: SocketAddr,
+}
+
+fn emit_tcp(key: &FlowKey, tcp: TcpBuilder, payload: &[u8]) -> Bytes {
+    let packet = match (key.local.ip(), key.remote.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => Ipv4Builder::new(src, dst, IP_PROTO_TCP)
+            .dont_fragment(true)
+            .emit(&tcp.emit_v4(src, dst, payload)),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            Ipv6Builder::new(src, dst, IP_PROTO_TCP).emit(&tcp.emit_v6(src, dst, payload))
+        }
+        _ => unreachable!("flow mixes address families"),
+    };
+    packet.expect("segment fits the mss").freeze()
+}
+
+/// Packets carrying a datagram, IPv4 fragmented to `mtu`; IPv6 is not fragmented.
+fn emit_udp(
+    key: &FlowKey,
+    payload: &[u8],
+    identification: u16,
+    mtu: usize,
+) -> io::Result<Vec<Bytes>> {
+    let udp = UdpBuilder::new(key.local.port(), key.remote.port());
+    match (key.local.ip(), key.remote.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let packet = Ipv4Builder::new(src, dst, IP_PROTO_UDP)
+                .identification(identification)
+                .emit(&udp.emit_v4(src, dst, payload)?)?;
+            let fragments = fragment_ipv4(&packet, mtu)?;
+            Ok(fragments.into_iter().map(BytesMut::freeze).collect())
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let packet =
+                Ipv6Builder::new(src, dst, IP_PROTO_UDP).emit(&udp.emit_v6(src, dst, payload)?)?;
+            if packet.len() > mtu {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("packet of {} bytes exceeds mtu {}", packet.len(), mtu),
+                ));
+            }
+            Ok(vec![packet.freeze()])
+        }
+        _ => unreachable!("flow mixes address families"),
+    }
+}
+
+struct State {
+    options: StackOptions,
+    out: Sender<Bytes>,
+    udp_out: Sender<Bytes>,
+    tcp_accept: Sender<StackTcpStream>,
+    udp_accept: Sender<StackUdpSocket>,
+    // Connections and flows stay in the maps while a handle refers to them, and in the
+    // indexes while packets may still arrive for them.
+    tcp: HashMap<u64, Tcb>,
+    tcp_index: HashMap<FlowKey, u64>,
+    udp: HashMap<u64, UdpFlow>,
+    udp_index: HashMap<FlowKey, u64>,
+    fragments: Ipv4Reassembler,
+    next_id: u64,
+    // Identification of the next IPv4 datagram sent on a UDP flow.
+    next_ip_id: u16,
+    isn_key: RandomState,
+    start: Instant,
+    stopped: Option<(io::ErrorKind, String)>,
+}
+
+/// A handle the accept queue refused; it must be dropped after the state lock is released.
+type Refused = Option<Box<dyn Send>>;
+
+impl State {
+    fn input(&mut self, shared: &Shared, packet: &[u8], now: Instant) -> Refused {
+        if self.stopped.is_some() {
+            return None;
+        }
+        let (src, dst, protocol, payload): (IpAddr, IpAddr, _, _) = match packet.first()? >> 4 {
+            4 => {
+                let ip = Ipv4Packet::new(packet).ok()?;
+                if !ip.verify_checksum() {
+                    return None;
+                }
+                if ip.more_fragments() || ip.fragment_offset() != 0 {
+                    let whole = self.fragments.push(packet).ok()??;
+                    return self.input(shared, &whole, now);
+                }
+                let (src, dst, protocol) = (ip.src_addr(), ip.dst_addr(), ip.protocol());
+                (
+                    src.into(),
+                    dst.into(),
+                    protocol,
+                    &packet[ip.header_len()..ip.total_len() as usize],
+                )
+            }
+            6 => {
+                let ip = Ipv6Packet::new(packet).ok()?;
+                // IPv6 fragments are not reassembled.
+                if ip.fragment().is_some() {
+                    return None;
+                }
+                let start = ip.upper_layer_offset();
+                let end = IPV6_HEADER_LEN + ip.payload_len() as usize;
+                (
+                    ip.src_addr().into(),
+                    ip.dst_addr().into(),
+                    ip.protocol(),
+                    &packet[start..end],
+                )
+            }
+            _ => return None,
+        };
+        match protocol {
+            IP_PROTO_TCP => {
+                let tcp = TcpPacket::new(payload).ok()?;
+                let valid = match (src, dst) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => tcp.verify_checksum_v4(src, dst),
+                    (IpAddr::V6(src), IpAddr::V6(dst)) => tcp.verify_checksum_v6(src, dst),
+                    _ => false,
+                };
+                if !valid {
+                    return None;
+                }
+                let key = FlowKey {
+                    local: SocketAddr::new(dst, tcp.dst_port()),
+                    remote: SocketAddr::new(src, tcp.src_port()),
+                };
+                self.tcp_input(shared, key, &tcp, now)
+            }
+            IP_PROTO_UDP => {
+                let udp = UdpPacket::new(payload).ok()?;
+                let valid = match (src, dst) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => udp.verify_checksum_v4(src, dst),
+                    (IpAddr::V6(src), IpAddr::V6(dst)) => udp.verify_checksum_v6(src, dst),
+                    _ => false,
+                };
+                if !valid {
+                    return None;
+                }
+                let key = FlowKey {
+                    local: SocketAddr::new(dst, udp.dst_port()),
+                    remote: SocketAddr::new(src, udp.src_port()),
+                };
+                self.udp_input(shared, key, udp.payload(), now)
+            }
+            _ => None,
+        }
+    }
+
+    fn tcp_input(
+        &mut self,
+        shared: &Shared,
+        key: FlowKey,
+        seg: &TcpPacket<&[u8]>,
+        now: Instant,
+    ) -> Refused {
+        if let Some(&id) = self.tcp_index.get(&key) {
+            let tcb = self.tcp.get_mut(&id).unwrap();
+            let mut refused = None;
+            if tcb.input(seg, now) {
+                refused = self.tcp_accept(shared, id);
+            }
+            if self.tcp[&id].state == TcpState::Closed {
+                self.sweep();
+            }
+            return refused;
+        }
+        if seg.has_flags(TCP_RST) {
+            return None;
+        }
+        if seg.flags() & (TCP_SYN | TCP_ACK) != TCP_SYN {
+            let _ = self.out.try_send(reset_reply(&key, seg));
+            return None;
+        }
+        let half_open = self
+            .tcp
+            .values()
+            .filter(|tcb| tcb.state == TcpState::SynReceived)
+            .count();
+        if half_open >= self.options.backlog.max(1) {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let isn = self.isn(&key);
+        let tcb = Tcb::new(key, self.out.clone(), &self.options, isn, seg, now);
+        self.tcp.insert(id, tcb);
+        self.tcp_index.insert(key, id);
+        None
+    }
+
+    fn tcp_accept(&mut self, shared: &Shared, id: u64) -> Refused {
+        let tcb = self.tcp.get_mut(&id).unwrap();
+        if self.tcp_accept.is_full() || self.tcp_accept.is_closed() {
+            tcb.reset(io::ErrorKind::ConnectionRefused);
+            return None;
+        }
+        tcb.has_handle = true;
+        let stream = StackTcpStream {
+            handle: Arc::new(TcpHandle {
+                shared: shared.clone(),
+                id,
+                key: tcb.key,
+            }),
+        };
+        match self.tcp_accept.try_send(stream) {
+            Ok(()) => None,
+            Err(err) => Some(Box::new(err.into_inner())),
+        }
+    }
+
+    // RFC 6528: a keyed hash of the flow plus a clock ticking every 4 microseconds.
+    fn isn(&self, key: &FlowKey) -> u32 {
+        let clock = (self.start.elapsed().as_micros() / 4) as u32;
+        (self.isn_key.hash_one(key) as u32).wrapping_add(clock)
+    }
+
+    fn udp_input(
+        &mut self,
+        shared: &Shared,
+        key: FlowKey,
+        payload: &[u8],
+        now: Instant,
+    ) -> Refused {
+        if let Some(&id) = self.udp_index.get(&key) {
+            let flow = self.udp.get_mut(&id).unwrap();
+            flow.last_active = now;
+            if flow.queue.len() < UDP_FLOW_QUEUE_LEN {
+                flow.queue.push_back(Bytes::copy_from_slice(payload));
+                if let Some(waker) = flow.waker.take() {
+                    waker.wake();
+                }
+            }
+            return None;
+        }
+        if self.udp_accept.is_full() || self.udp_accept.is_closed() {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut queue = VecDeque::new();
+        queue.push_back(Bytes::copy_from_slice(payload));
+        self.udp.insert(
+            id,
+            UdpFlow {
+                queue,
+                waker: None,
+                last_active: now,
+                expired: false,
+                has_handle: true,
+            },
+        );
+        self.udp_index.insert(key, id);
+        let socket = StackUdpSocket {
+            handle: Arc::new(UdpHandle {
+                shared: shared.clone(),
+                id,
+                key,
+            }),
+        };
+        match self.udp_accept.try_send(socket) {
+            Ok(()) => None,
+            Err(err) => Some(Box::new(err.into_inner())),
+        }
+    }
+
+    fn poll(&mut self, now: Instant) {
+        for tcb in self.tcp.values_mut() {
+            tcb.poll(now);
+        }
+        let udp_timeout = self.options.udp_timeout;
+        for flow in self.udp.values_mut() {
+            if !flow.expired && now.saturating_duration_since(flow.last_active) >= udp_timeout {
+                flow.expire();
+            }
+        }
+        self.fragments.expire();
+        self.sweep();
+    }
+
+    fn sweep(&mut self) {
+        let tcp = &self.tcp;
+        self.tcp_index
+            .retain(|_, id| tcp.get(id).is_some_and(|tcb| tcb.state != TcpState::Closed));
+        self.tcp
+            .retain(|_, tcb| tcb.state != TcpState::Closed || tcb.has_handle);
+        let udp = &self.udp;
+        self.udp_index
+            .retain(|_, id| udp.get(id).is_some_and(|flow| !flow.expired));
+        self.udp.retain(|_, flow| !flow.expired || flow.has_handle);
+    }
+
+    fn tcp_release(&mut self, id: u64, now: Instant) {
+        if let Some(tcb) = self.tcp.get_mut(&id) {
+            tcb.has_handle = false;
+            tcb.read_waker = None;
+            tcb.write_waker = None;
+            if tcb.state != TcpState::Closed {
+                if tcb.recv_buf.is_empty() {
+                    tcb.shutdown(now);
+                    if tcb.state == TcpState::FinWait2 {
+                        tcb.close_at = Some(now + TCP_FIN_TIMEOUT);
+                    }
+                } else {
+                    // Like close(2), unread data aborts the connection.
+                    tcb.reset(io::ErrorKind::ConnectionAborted);
+                }
+            }
+            self.sweep();
+        }
+    }
+
+    fn udp_release(&mut self, id: u64) {
+        if let Some(flow) = self.udp.get_mut(&id) {
+            flow.has_handle = false;
+            flow.expired = true;
+            self.sweep();
+        }
+    }
+
+    fn stop(&mut self, err: io::Error) -> (Sender<StackTcpStream>, Sender<StackUdpSocket>) {
+        self.stopped = Some((err.kind(), err.to_string()));
+        for tcb in self.tcp.values_mut() {
+            if tcb.state != TcpState::Closed {
+                tcb.abort(io::ErrorKind::NotConnected);
+            }
+        }
+        for flow in self.udp.values_mut() {
+            flow.expire();
+        }
+        self.sweep();
+        (
+            std::mem::replace(&mut self.tcp_accept, channel::bounded(1).0),
+            std::mem::replace(&mut self.udp_accept, channel::bounded(1).0),
+        )
+    }
+}
+
+/// RST answering a segment that belongs to no connection, RFC 9293 section 3.10.7.1.
+fn reset_reply(key: &FlowKey, seg: &TcpPacket<&[u8]>) -> Bytes {
+    let tcp = TcpBuilder::new(key.local.port(), key.remote.port());
+    let tcp = if seg.has_flags(TCP_ACK) {
+        tcp.seq(seg.ack()).flags(TCP_RST)
+    } else {
+        tcp.ack(seg.seq().wrapping_add(seg.segment_len()))
+            .flags(TCP_RST)
+    };
+    emit_tcp(key, tcp, &[])
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum TcpState {
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+    Closed,
+}
+
+/// Transmission control block of one connection, RFC 9293 section 3.3.1.
+struct Tcb {
+    key: FlowKey,
+    out: Sender<Bytes>,
+    state: TcpState,
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_wnd: u32,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    snd_shift: u8,
+    // Bytes from `snd_una` on, sent or not.
+    send_buf: VecDeque<u8>,
+    send_cap: usize,
+    // The application shut down writing; a FIN follows the buffered data.
+    fin_queued: bool,
+    fin_sent: bool,
+    rcv_nxt: u32,
+    rcv_shift: u8,
+    // Right edge of the last advertised window.
+    rcv_adv: u32,
+    recv_buf: VecDeque<u8>,
+    recv_cap: usize,
+    fin_received: bool,
+    ack_pending: bool,
+    mss: usize,
+    our_mss: usize,
+    window_scaling: bool,
+    rto: Duration,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rtt_sample: Option<(u32, Instant)>,
+    retransmit_at: Option<Instant>,
+    retries: u32,
+    dup_acks: u32,
+    close_at: Option<Instant>,
+    error: Option<io::ErrorKind>,
+    has_handle: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Tcb {
+    fn new(
+        key: FlowKey,
+        out: Sender<Bytes>,
+        options: &StackOptions,
+        iss: u32,
+        syn: &TcpPacket<&[u8]>,
+        now: Instant,
+    ) -> Self {
+        let (ip_header_len, default_mss) = match key.local {
+            SocketAddr::V4(_) => (IPV4_HEADER_LEN, TCP_DEFAULT_MSS_V4),
+            SocketAddr::V6(_) => (IPV6_HEADER_LEN, TCP_DEFAULT_MSS_V6),
+        };
+        let our_mss = options
+            .mtu
+            .saturating_sub(ip_header_len + TCP_HEADER_LEN)
+            .max(1);
+        let mut peer_mss = default_mss;
+        let mut peer_shift = None;
+        for option in syn.options() {
+            match option {
+                TcpOption::Mss(mss) => peer_mss = mss as usize,
+                TcpOption::WindowScale(shift) => peer_shift = Some(shift),
+                _ => {}
+            }
+        }
+        let recv_cap = options.recv_buffer_size.max(1);
+        // RFC 7323: scaling applies only when both ends offer it.
+        let (window_scaling, snd_shift, rcv_shift, recv_cap) = match peer_shift {
+            Some(shift) => {
+                let mut rcv_shift = 0;
+                while recv_cap >> rcv_shift > 0xffff && rcv_shift < TCP_MAX_WINDOW_SHIFT {
+                    rcv_shift += 1;
+                }
+                (true, shift.min(TCP_MAX_WINDOW_SHIFT), rcv_shift, recv_cap)
+            }
+            None => (false, 0, 0, recv_cap.min(0xffff)),
+        };
+        let rcv_nxt = syn.seq().wrapping_add(1);
+        let mut tcb = Tcb {
+            key,
+            out,
+            state: TcpState::SynReceived,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: syn.window() as u32,
+            snd_wl1: syn.seq(),
+            snd_wl2: iss,
+            snd_shift,
+            send_buf: VecDeque::new(),
+            send_cap: options.send_buffer_size.max(1),
+            fin_queued: false,
+            fin_sent: false,
+            rcv_nxt,
+            rcv_shift,
+            rcv_adv: rcv_nxt,
+            recv_buf: VecDeque::new(),
+            recv_cap,
+            fin_received: false,
+            ack_pending: false,
+            mss: peer_mss.clamp(1, our_mss),
+            our_mss,
+            window_scaling,
+            rto: TCP_RTO_INITIAL,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rtt_sample: Some((iss.wrapping_add(1), now)),
+            retransmit_at: Some(now + TCP_RTO_INITIAL),
+            retries: 0,
+            dup_acks: 0,
+            close_at: None,
+            error: None,
+            has_handle: false,
+            read_waker: None,
+            write_waker: None,
+        };
+        tcb.send_syn_ack();
+        tcb
+    }
+
+    fn orphaned(&self) -> bool {
+        !self.has_handle && self.state != TcpState::SynReceived
+    }
+
+    /// Window to advertise, recording its right edge.
+    fn advertise(&mut self) -> u16 {
+        let free = self.recv_cap - self.recv_buf.len();
+        let window = (free >> self.rcv_shift).min(0xffff);
+        self.rcv_adv = self.rcv_nxt.wrapping_add((window << self.rcv_shift) as u32);
+        window as u16
+    }
+
+    fn send(&mut self, seq: u32, flags: u8, payload: &[u8]) {
+        let window = self.advertise();
+        let tcp = TcpBuilder::new(self.key.local.port(), self.key.remote.port())
+            .seq(seq)
+            .ack(self.rcv_nxt)
+            .flags(flags)
+            .window(window);
+        self.ack_pending = false;
+        let _ = self.out.try_send(emit_tcp(&self.key, tcp, payload));
+    }
+
+    fn send_ack(&mut self) {
+        self.send(self.snd_nxt, TCP_ACK, &[]);
+    }
+
+    fn send_syn_ack(&mut self) {
+        // Windows in SYN segments are never scaled.
+        let window = (self.recv_cap - self.recv_buf.len()).min(0xffff);
+        self.rcv_adv = self.rcv_nxt.wrapping_add(window as u32);
+        let mut tcp = TcpBuilder::new(self.key.local.port(), self.key.remote.port())
+            .seq(self.iss)
+            .ack(self.rcv_nxt)
+            .flags(TCP_SYN)
+            .window(window as u16)
+            .mss(self.our_mss.min(0xffff) as u16);
+        if self.window_scaling {
+            tcp = tcp.window_scale(self.rcv_shift);
+        }
+        let _ = self.out.try_send(emit_tcp(&self.key, tcp, &[]));
+    }
+
+    /// Sends `len` bytes of the send buffer starting `offset` bytes after `snd_una`.
+    fn send_data(&mut self, offset: usize, len: usize, fin: bool) {
+        let seq = self.snd_una.wrapping_add(offset as u32);
+        let payload = self.send_buf.make_contiguous()[offset..offset + len].to_vec();
+        let mut flags = TCP_ACK;
+        if len > 0 {
+            flags |= TCP_PSH;
+        }
+        if fin {
+            flags |= TCP_FIN;
+        }
+        self.send(seq, flags, &payload);
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn abort(&mut self, kind: io::ErrorKind) {
+        self.state = TcpState::Closed;
+        self.error.get_or_insert(kind);
+        self.send_buf.clear();
+        self.retransmit_at = None;
+        self.close_at = None;
+        self.wake_reader();
+        self.wake_writer();
+    }
+
+    fn reset(&mut self, kind: io::ErrorKind) {
+        self.send(self.snd_nxt, TCP_RST | TCP_ACK, &[]);
+        self.abort(kind);
+    }
+
+    /// Processes a segment of this connection, returning whether it completed the handshake.
+    fn input(&mut self, seg: &TcpPacket<&[u8]>, now: Instant) -> bool {
+        let (seq, flags) = (seg.seq(), seg.flags());
+        if flags & TCP_RST != 0 {
+            let window = self.rcv_adv.wrapping_sub(self.rcv_nxt).max(1);
+            if seq.wrapping_sub(self.rcv_nxt) < window {
+                self.abort(io::ErrorKind::ConnectionReset);
+            }
+            return false;
+        }
+        let mut established = false;
+        match self.state {
+            TcpState::Closed => return false,
+            TcpState::SynReceived => {
+                if flags & TCP_SYN != 0 {
+                    // The peer retransmitted its SYN.
+                    if seq.wrapping_add(1) == self.rcv_nxt {
+                        self.send_syn_ack();
+                    }
+                    return false;
+                }
+                if flags & TCP_ACK == 0 {
+                    return false;
+                }
+                if seg.ack() != self.snd_nxt {
+                    let _ = self.out.try_send(reset_reply(&self.key, seg));
+                    return false;
+                }
+                self.state = TcpState::Established;
+                self.snd_una = self.snd_nxt;
+                self.snd_wnd = (seg.window() as u32) << self.snd_shift;
+                self.snd_wl1 = seq;
+                self.snd_wl2 = seg.ack();
+                self.retransmit_at = None;
+                self.retries = 0;
+                self.sample_rtt(seg.ack(), now);
+                established = true;
+            }
+            _ if flags & TCP_SYN != 0 => {
+                // RFC 5961 challenge ACK.
+                self.send_ack();
+                return false;
+            }
+            _ => {}
+        }
+        if flags & TCP_ACK == 0 {
+            return established;
+        }
+        self.on_ack(seg, now);
+        if self.state != TcpState::Closed {
+            self.on_data(seg, now);
+        }
+        if self.state != TcpState::Closed {
+            self.output(now);
+        }
+        established
+    }
+
+    fn sample_rtt(&mut self, ack: u32, now: Instant) {
+        let sent = match self.rtt_sample {
+            Some((seq, sent)) if seq_le(seq, ack) => sent,
+            _ => return,
+        };
+        self.rtt_sample = None;
+        let rtt = now - sent;
+        // RFC 6298 section 2.
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + (self.rttvar * 4).max(STACK_TICK)).clamp(TCP_RTO_MIN, TCP_RTO_MAX);
+    }
+
+    fn on_ack(&mut self, seg: &TcpPacket<&[u8]>, now: Instant) {
+        let (seq, ack) = (seg.seq(), seg.ack());
+        if seq_lt(self.snd_nxt, ack) {
+            // Acknowledges something never sent.
+            self.send_ack();
+            return;
+        }
+        let window = (seg.window() as u32) << self.snd_shift;
+        if seq_lt(self.snd_una, ack) {
+            let fin_acked = self.fin_sent && ack == self.snd_nxt;
+            let acked = ack.wrapping_sub(self.snd_una) - fin_acked as u32;
+            self.send_buf.drain(..acked as usize);
+            self.snd_una = ack;
+            self.retries = 0;
+            self.dup_acks = 0;
+            self.sample_rtt(ack, now);
+            self.retransmit_at = if self.snd_una == self.snd_nxt {
+                None
+            } else {
+                Some(now + self.rto)
+            };
+            self.wake_writer();
+            if fin_acked {
+                match self.state {
+                    TcpState::FinWait1 => {
+                        self.state = TcpState::FinWait2;
+                        if self.orphaned() {
+                            self.close_at = Some(now + TCP_FIN_TIMEOUT);
+                        }
+                    }
+                    TcpState::Closing => {
+                        self.state = TcpState::TimeWait;
+                        self.close_at = Some(now + TCP_TIME_WAIT);
+                    }
+                    TcpState::LastAck => {
+                        self.state = TcpState::Closed;
+                        self.wake_reader();
+                        self.wake_writer();
+                    }
+                    _ => {}
+                }
+            }
+        } else if ack == self.snd_una
+            && self.snd_nxt != self.snd_una
+            && seg.segment_len() == 0
+            && window == self.snd_wnd
+        {
+            if self.snd_wnd == 0 {
+                // The peer answers window probes, keep probing.
+                self.retries = 0;
+            } else {
+                self.dup_acks += 1;
+                if self.dup_acks == TCP_DUP_ACK_THRESHOLD {
+                    self.retransmit();
+                }
+            }
+        }
+        if seq_lt(self.snd_wl1, seq) || (self.snd_wl1 == seq && seq_le(self.snd_wl2, ack)) {
+            self.snd_wnd = window;
+            self.snd_wl1 = seq;
+            self.snd_wl2 = ack;
+        }
+    }
+
+    fn on_data(&mut self, seg: &TcpPacket<&[u8]>, now: Instant) {
+        if seg.segment_len() == 0 {
+            return;
+        }
+        if !matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        ) {
+            // The peer's FIN was already received: this is a retransmission.
+            if self.state == TcpState::TimeWait {
+                self.close_at = Some(now + TCP_TIME_WAIT);
+            }
+            self.ack_pending = true;
+            return;
+        }
+        let mut data = seg.payload();
+        let fin = seg.has_flags(TCP_FIN);
+        let mut seq = seg.seq();
+        self.ack_pending = true;
+        if seq_lt(seq, self.rcv_nxt) {
+            let skip = self.rcv_nxt.wrapping_sub(seq) as usize;
+            if skip >= data.len() + fin as usize {
+                return;
+            }
+            data = &data[skip.min(data.len())..];
+            seq = self.rcv_nxt;
+        }
+        if seq != self.rcv_nxt {
+            // Out of order segments are dropped and the duplicate ACK asks for a retransmission.
+            return;
+        }
+        if self.orphaned() && !data.is_empty() {
+            self.reset(io::ErrorKind::ConnectionAborted);
+            return;
+        }
+        let accepted = data.len().min(self.recv_cap - self.recv_buf.len());
+        if accepted > 0 {
+            self.recv_buf.extend(&data[..accepted]);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+            self.wake_reader();
+        }
+        if fin && accepted == data.len() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.wake_reader();
+            self.state = match self.state {
+                TcpState::Established => TcpState::CloseWait,
+                TcpState::FinWait1 => TcpState::Closing,
+                _ => {
+                    self.close_at = Some(now + TCP_TIME_WAIT);
+                    TcpState::TimeWait
+                }
+            };
+        }
+    }
+
+    /// Sends whatever the peer's window allows, then any pending ACK.
+    fn output(&mut self, now: Instant) {
+        if matches!(self.state, TcpState::Established | TcpState::CloseWait) {
+            while !self.fin_sent {
+                let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                let unsent = self.send_buf.len() - sent;
+                let window = (self.snd_wnd as usize).saturating_sub(sent);
+                let len = unsent.min(window).min(self.mss);
+                let fin = self.fin_queued && len == unsent;
+                if len == 0 && !fin {
+                    break;
+                }
+                self.send_data(sent, len, fin);
+                if self.rtt_sample.is_none() && self.retries == 0 {
+                    self.rtt_sample = Some((self.snd_nxt.wrapping_add(len as u32), now));
+                }
+                self.snd_nxt = self.snd_nxt.wrapping_add(len as u32 + fin as u32);
+                self.retransmit_at.get_or_insert(now + self.rto);
+                if fin {
+                    self.fin_sent = true;
+                    self.state = match self.state {
+                        TcpState::Established => TcpState::FinWait1,
+                        _ => TcpState::LastAck,
+                    };
+                }
+            }
+            // A closed window with data waiting arms the persist timer.
+            if self.snd_wnd == 0 && !self.send_buf.is_empty() && self.snd_nxt == self.snd_una {
+                self.retransmit_at.get_or_insert(now + self.rto);
+            }
+        }
+        if self.ack_pending {
+            self.send_ack();
+        }
+    }
+
+    /// Resends the first unacknowledged segment.
+    fn retransmit(&mut self) {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize - self.fin_sent as usize;
+        let len = in_flight.min(self.mss);
+        self.send_data(0, len, self.fin_sent && len == in_flight);
+    }
+
+    fn poll(&mut self, now: Instant) {
+        if self.retransmit_at.is_some_and(|at| at <= now) {
+            self.on_timeout(now);
+        }
+        if self.close_at.is_some_and(|at| at <= now) {
+            self.close_at = None;
+            self.state = TcpState::Closed;
+            self.wake_reader();
+            self.wake_writer();
+        }
+    }
+
+    fn on_timeout(&mut self, now: Instant) {
+        if self.state == TcpState::SynReceived {
+            if self.retries >= TCP_SYN_RETRIES {
+                self.abort(io::ErrorKind::TimedOut);
+                return;
+            }
+            self.send_syn_ack();
+        } else if self.snd_nxt == self.snd_una {
+            if self.snd_wnd != 0 || self.send_buf.is_empty() {
+                self.retransmit_at = None;
+                return;
+            }
+            // Zero window probe, RFC 9293 section 3.8.6.1.
+            self.send_data(0, 1, false);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        } else {
+            if self.retries >= TCP_MAX_RETRIES {
+                self.reset(io::ErrorKind::TimedOut);
+                return;
+            }
+            self.retransmit();
+        }
+        // Karn's algorithm: no RTT samples from retransmitted segments.
+        self.rtt_sample = None;
+        self.retries += 1;
+        self.rto = (self.rto * 2).min(TCP_RTO_MAX);
+        self.retransmit_at = Some(now + self.rto);
+    }
+
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if !self.recv_buf.is_empty() {
+            let n = buf.len().min(self.recv_buf.len());
+            for (dst, src) in buf.iter_mut().zip(self.recv_buf.drain(..n)) {
+                *dst = src;
+            }
+            self.window_update();
+            return Poll::Ready(Ok(n));
+        }
+        if self.fin_received {
+            return Poll::Ready(Ok(0));
+        }
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Announces the space freed by a read once it is worth a segment (RFC 9293 3.8.6.2.2).
+    fn window_update(&mut self) {
+        if !matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        ) {
+            return;
+        }
+        let free = (self.recv_cap - self.recv_buf.len()).min(0xffff << self.rcv_shift);
+        let edge = self.rcv_nxt.wrapping_add(free as u32);
+        let threshold = (self.recv_cap / 2).min(2 * self.mss) as i32;
+        if edge.wrapping_sub(self.rcv_adv) as i32 >= threshold {
+            self.send_ack();
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.fin_queued || !matches!(self.state, TcpState::Established | TcpState::CloseWait) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let space = self.send_cap - self.send_buf.len();
+        if space == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(space);
+        self.send_buf.extend(&buf[..n]);
+        self.output(Instant::now());
+        Poll::Ready(Ok(n))
+    }
+
+    fn shutdown(&mut self, now: Instant) {
+        if self.error.is_none()
+            && !self.fin_queued
+            && matches!(self.state, TcpState::Established | TcpState::CloseWait)
+        {
+            self.fin_queued = true;
+            self.output(now);
+        }
+    }
+}
+
+struct TcpHandle {
+    shared: Shared,
+    id: u64,
+    key: FlowKey,
+}
+
+impl TcpHandle {
+    fn with<R>(&self, f: impl FnOnce(&mut Tcb) -> io::Result<R>) -> io::Result<R> {
+        match self.shared.lock().unwrap().tcp.get_mut(&self.id) {
+            Some(tcb) => f(tcb),
+            None => Err(not_connected()),
+        }
+    }
+
+    fn poll<R>(&self, f: impl FnOnce(&mut Tcb) -> Poll<io::Result<R>>) -> Poll<io::Result<R>> {
+        match self.shared.lock().unwrap().tcp.get_mut(&self.id) {
+            Some(tcb) => f(tcb),
+            None => Poll::Ready(Err(not_connected())),
+        }
+    }
+}
+
+impl Drop for TcpHandle {
+    fn drop(&mut self) {
+        self.shared
+            .lock()
+            .unwrap()
+            .tcp_release(self.id, Instant::now());
+    }
+}
+
+/// TCP connection terminated by a [`Stack`]; clones share the connection, which is closed
+/// when the last one is dropped.
+#[derive(Clone)]
+pub struct StackTcpStream {
+    handle: Arc<TcpHandle>,
+}
+
+impl StackTcpStream {
+    /// The address the peer connected to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.key.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.handle.key.remote
+    }
+}
+
+impl fmt::Debug for StackTcpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StackTcpStream")
+            .field("local_addr", &self.local_addr())
+            .field("peer_addr", &self.peer_addr())
+            .finish()
+    }
+}
+
+impl HalfClose for StackTcpStream {
+    /// Sends a FIN once the buffered data has been sent.
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.handle.with(|tcb| {
+            tcb.shutdown(Instant::now());
+            Ok(())
+        })
+    }
+}
+
+impl Read for &StackTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.handle.poll(|tcb| tcb.poll_read(cx, buf))
+    }
+}
+
+impl Write for &StackTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.handle.poll(|tcb| tcb.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.shutdown_write())
+    }
+}
+
+impl Read for StackTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self).poll_read(cx, buf)
+    }
+}
+
+impl Write for StackTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self).poll_close(cx)
+    }
+}
+
+struct UdpFlow {
+    queue: VecDeque<Bytes>,
+    waker: Option<Waker>,
+    last_active: Instant,
+    expired: bool,
+    has_handle: bool,
+}
+
+impl UdpFlow {
+    fn expire(&mut self) {
+        self.expired = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn flow_ended(stopped: bool) -> io::Error {
+    if stopped {
+        not_connected()
+    } else {
+        io::Error::new(io::ErrorKind::TimedOut, "udp flow expired")
+    }
+}
+
+struct UdpHandle {
+    shared: Shared,
+    id: u64,
+    key: FlowKey,
+}
+
+impl Drop for UdpHandle {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().udp_release(self.id);
+    }
+}
+
+/// UDP flow between one peer and one destination, terminated by a [`Stack`].
+///
+/// The flow expires after the stack's UDP timeout without traffic in either direction, or
+/// when the last clone is dropped; later datagrams from the peer start a new flow.
+#[derive(Clone)]
+pub struct StackUdpSocket {
+    handle: Arc<UdpHandle>,
+}
+
+impl StackUdpSocket {
+    /// The address the peer sent to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.key.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.handle.key.remote
+    }
+
+    /// Receives one datagram from the peer into `buf`, replacing its contents.
+    ///
+    /// Fails with `TimedOut` once the flow has expired, or `NotConnected` once the stack
+    /// has stopped.
+    pub async fn recv_buf(&self, buf: &mut Buffer) -> io::Result<usize> {
+        std::future::poll_fn(|cx| {
+            let mut state = self.handle.shared.lock().unwrap();
+            let stopped = state.stopped.is_some();
+            let flow = match state.udp.get_mut(&self.handle.id) {
+                Some(flow) => flow,
+                None => return Poll::Ready(Err(not_connected())),
+            };
+            if let Some(datagram) = flow.queue.pop_front() {
+                buf.storage_mut()[..datagram.len()].copy_from_slice(&datagram);
+                buf.set_len(datagram.len());
+                return Poll::Ready(Ok(datagram.len()));
+            }
+            if flow.expired {
+                return Poll::Ready(Err(flow_ended(stopped)));
+            }
+            flow.waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Sends `buf` as one datagram to the peer, waiting while the device is behind.
+    ///
+    /// IPv4 datagrams larger than the stack's MTU are fragmented, IPv6 ones fail with
+    /// `InvalidInput`.
+    pub async fn send_all(&self, buf: &[u8]) -> io::Result<()> {
+        let (out, identification, mtu) = {
+            let mut state = self.handle.shared.lock().unwrap();
+            let stopped = state.stopped.is_some();
+            let flow = match state.udp.get_mut(&self.handle.id) {
+                Some(flow) if !flow.expired => flow,
+                _ => return Err(flow_ended(stopped)),
+            };
+            flow.last_active = Instant::now();
+            let identification = state.next_ip_id;
+            state.next_ip_id = identification.wrapping_add(1);
+            (state.udp_out.clone(), identification, state.options.mtu)
+        };
+        for packet in emit_udp(&self.handle.key, buf, identification, mtu)? {
+            out.send(packet).await.map_err(|_| not_connected())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for StackUdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StackUdpSocket")
+            .field("local_addr", &self.local_addr())
+            .field("peer_addr", &self.peer_addr())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{new_device_pair, relay, MemoryDevice, SocketAddrExt};
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(3);
+    const CLIENT_ISN: u32 = 0xffff_fff0;
+
+    /// Plays the peer of a stack by writing and reading raw packets.
+    struct Client {
+        device: MemoryDevice,
+        key: FlowKey,
+        seq: u32,
+        ack: u32,
+        window: u16,
+    }
+
+    impl Client {
+        fn new(device: MemoryDevice, addr: &str, server: &str) -> Self {
+            Client {
+                device,
+                key: FlowKey {
+                    local: addr.parse().unwrap(),
+                    remote: server.parse().unwrap(),
+                },
+                seq: CLIENT_ISN,
+                ack: 0,
+                window: 65535,
+            }
+        }
+
+        fn builder(&self) -> TcpBuilder {
+            TcpBuilder::new(self.key.local.port(), self.key.remote.port())
+                .seq(self.seq)
+                .window(self.window)
+        }
+
+        async fn send(&self, tcp: TcpBuilder, payload: &[u8]) {
+            let packet = emit_tcp(&self.key, tcp, payload);
+            self.device.send(&packet).await.unwrap();
+        }
+
+        async fn send_ack(&self) {
+            self.send(self.builder().ack(self.ack), &[]).await;
+        }
+
+        /// A UDP datagram from the client to the server.
+        fn udp_packet(&self, payload: &[u8]) -> BytesMut {
+            let udp = UdpBuilder::new(self.key.local.port(), self.key.remote.port());
+            let packet = match (self.key.local.ip(), self.key.remote.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => Ipv4Builder::new(src, dst, IP_PROTO_UDP)
+                    .emit(&udp.emit_v4(src, dst, payload).unwrap()),
+                (IpAddr::V6(src), IpAddr::V6(dst)) => Ipv6Builder::new(src, dst, IP_PROTO_UDP)
+                    .emit(&udp.emit_v6(src, dst, payload).unwrap()),
+                _ => unreachable!(),
+            };
+            packet.unwrap()
+        }
+
+        async fn send_udp(&self, payload: &[u8]) {
+            self.device.send(&self.udp_packet(payload)).await.unwrap();
+        }
+
+        async fn send_data(&mut self, payload: &[u8]) {
+            self.send(self.builder().ack(self.ack).flags(TCP_PSH), payload)
+                .await;
+            self.seq = self.seq.wrapping_add(payload.len() as u32);
+        }
+
+        async fn send_fin(&mut self) {
+            self.send(self.builder().ack(self.ack).flags(TCP_FIN), &[])
+                .await;
+            self.seq = self.seq.wrapping_add(1);
+        }
+
+        async fn recv_packet(&self, timeout: Duration) -> Option<Bytes> {
+            let mut buf = Buffer::new();
+            self.device
+                .recv(&mut buf)
+                .timeout(timeout)
+                .await
+                .ok()?
+                .unwrap();
+            Some(Bytes::copy_from_slice(&buf))
+        }
+
+        /// Receives the next segment, checking its addresses and checksum.
+        async fn recv(&self) -> TcpPacket<Bytes> {
+            self.try_recv(TIMEOUT).await.expect("no segment")
+        }
+
+        async fn try_recv(&self, timeout: Duration) -> Option<TcpPacket<Bytes>> {
+            let packet = self.recv_packet(timeout).await?;
+            let (src, dst, segment) = match self.key.local {
+                SocketAddr::V4(_) => {
+                    let ip = Ipv4Packet::new(&packet[..]).unwrap();
+                    assert!(ip.verify_checksum());
+                    assert_eq!(ip.protocol(), IP_PROTO_TCP);
+                    let payload = packet.slice(ip.header_len()..ip.total_len() as usize);
+                    (ip.src_addr().into(), ip.dst_addr().into(), payload)
+                }
+                SocketAddr::V6(_) => {
+                    let ip = Ipv6Packet::new(&packet[..]).unwrap();
+                    assert_eq!(ip.protocol(), IP_PROTO_TCP);
+                    let payload = packet.slice(ip.upper_layer_offset()..);
+                    (ip.src_addr().into(), ip.dst_addr().into(), payload)
+                }
+            };
+            assert_eq!((src, dst), (self.key.remote.ip(), self.key.local.ip()));
+            let tcp = TcpPacket::new(segment).unwrap();
+            let valid = match (src, dst) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => tcp.verify_checksum_v4(src, dst),
+                (IpAddr::V6(src), IpAddr::V6(dst)) => tcp.verify_checksum_v6(src, dst),
+                _ => false,
+            };
+            assert!(valid);
+            assert_eq!(tcp.src_port(), self.key.remote.port());
+            assert_eq!(tcp.dst_port(), self.key.local.port());
+            Some(tcp)
+        }
+
+        /// Receives `len` bytes of data segments, acknowledging each.
+        async fn recv_data(&mut self, len: usize, mss: usize) -> Vec<u8> {
+            let mut data = Vec::new();
+            while data.len() < len {
+                let tcp = self.recv().await;
+                assert_eq!(tcp.seq(), self.ack);
+                assert!(tcp.payload().len() <= mss);
+                data.extend_from_slice(tcp.payload());
+                self.ack = self.ack.wrapping_add(tcp.payload().len() as u32);
+                self.send_ack().await;
+            }
+            assert_eq!(data.len(), len);
+            data
+        }
+
+        async fn connect(&mut self, mss: u16, window_scale: Option<u8>) -> TcpPacket<Bytes> {
+            let mut syn = self.builder().flags(TCP_SYN).mss(mss);
+            if let Some(shift) = window_scale {
+                syn = syn.window_scale(shift);
+            }
+            self.send(syn, &[]).await;
+            let syn_ack = self.recv().await;
+            assert_eq!(syn_ack.flags(), TCP_SYN | TCP_ACK);
+            assert_eq!(syn_ack.ack(), CLIENT_ISN.wrapping_add(1));
+            self.seq = self.seq.wrapping_add(1);
+            self.ack = syn_ack.seq().wrapping_add(1);
+            self.send_ack().await;
+            syn_ack
+        }
+    }
+
+    fn setup(options: StackOptions, addr: &str, server: &str) -> (Stack, Client) {
+        let (a, b) = new_device_pair();
+        (options.start(b), Client::new(a, addr, server))
+    }
+
+    async fn accept(stack: &Stack) -> StackTcpStream {
+        stack.accept_tcp().timeout(TIMEOUT).await.unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_stack_tcp() {
+        task::block_on(async {
+            let (stack, mut client) = setup(StackOptions::new(), "10.0.0.2:40000", "1.2.3.4:80");
+            let syn_ack = client.connect(1000, Some(2)).await;
+            assert!(syn_ack
+                .options()
+                .any(|option| option == TcpOption::Mss(1460)));
+            assert!(syn_ack
+                .options()
+                .any(|option| matches!(option, TcpOption::WindowScale(_))));
+            let mut stream = accept(&stack).await;
+            assert_eq!(stream.local_addr(), "1.2.3.4:80".parse().unwrap());
+            assert_eq!(stream.peer_addr(), "10.0.0.2:40000".parse().unwrap());
+
+            // Data from the client, sequence numbers wrapping around zero.
+            client.send_data(b"hello world, ").await;
+            client.send_data(b"again").await;
+            let mut buf = [0u8; 18];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello world, again");
+            let ack = client.recv().await;
+            assert_eq!(ack.payload(), b"");
+            let ack = match client.try_recv(Duration::from_millis(100)).await {
+                Some(ack) => ack.ack(),
+                None => ack.ack(),
+            };
+            assert_eq!(ack, client.seq);
+
+            // Data to the client, split at its MSS.
+            let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+            stream.write_all(&data).await.unwrap();
+            assert_eq!(client.recv_data(3000, 1000).await, data);
+
+            // The client half-closes, the stream still writes.
+            client.send_fin().await;
+            assert_eq!(client.recv().await.ack(), client.seq);
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            stream.write_all(b"bye").await.unwrap();
+            assert_eq!(client.recv_data(3, 1000).await, b"bye");
+
+            stream.shutdown_write().unwrap();
+            let fin = client.recv().await;
+            assert!(fin.has_flags(TCP_FIN | TCP_ACK));
+            assert_eq!(fin.seq(), client.ack);
+            client.ack = client.ack.wrapping_add(1);
+            client.send_ack().await;
+            assert_eq!(
+                stream.write(b"late").await.unwrap_err().kind(),
+                io::ErrorKind::BrokenPipe
+            );
+
+            // The connection is gone: further segments are reset.
+            task::sleep(Duration::from_millis(50)).await;
+            client.send_data(b"stale").await;
+            let rst = client.recv().await;
+            assert!(rst.has_flags(TCP_RST));
+            assert_eq!(rst.seq(), client.ack);
+        });
+    }
+
+    #[test]
+    fn test_stack_tcp_ipv6() {
+        task::block_on(async {
+            let (stack, mut client) =
+                setup(StackOptions::new(), "[fd00::2]:40000", "[2001:db8::1]:443");
+            // Without an MSS option the peer is assumed to take 1220 bytes.
+            client.send(client.builder().flags(TCP_SYN), &[]).await;
+            let syn_ack = client.recv().await;
+            assert_eq!(
+                syn_ack.options().collect::<Vec<_>>(),
+                [TcpOption::Mss(1440)]
+            );
+            client.seq = client.seq.wrapping_add(1);
+            client.ack = syn_ack.seq().wrapping_add(1);
+            client.send_ack().await;
+            let stream = accept(&stack).await;
+            assert_eq!(stream.local_addr(), "[2001:db8::1]:443".parse().unwrap());
+            (&stream).write_all(&[7u8; 2000]).await.unwrap();
+            assert_eq!(client.recv_data(2000, 1220).await, [7u8; 2000]);
+
+            // Dropping the stream closes the connection.
+            drop(stream);
+            let fin = client.recv().await;
+            assert!(fin.has_flags(TCP_FIN));
+        });
+    }
+
+    #[test]
+    fn test_stack_tcp_jumbo_mtu() {
+        task::block_on(async {
+            let options = StackOptions::new().mtu(100_000);
+            let (stack, mut client) = setup(options, "10.0.0.2:40000", "1.2.3.4:80");
+            let syn_ack = client.connect(u16::MAX, Some(2)).await;
+            assert!(syn_ack
+                .options()
+                .any(|option| option == TcpOption::Mss(65495)));
+            let stream = accept(&stack).await;
+            (&stream).write_all(&[1u8; 100_000]).await.unwrap();
+            assert_eq!(client.recv_data(100_000, 65495).await, vec![1u8; 100_000]);
+        });
+    }
+
+    #[test]
+    fn test_stack_tcp_retransmit() {
+        task::block_on(async {
+            let (stack, mut client) = setup(StackOptions::new(), "10.0.0.2:40000", "1.2.3.4:80");
+
+            // A lost SYN-ACK is sent again, the retransmitted SYN answered at once.
+            client.send(client.builder().flags(TCP_SYN), &[]).await;
+            let syn_ack = client.recv().await;
+            client.send(client.builder().flags(TCP_SYN), &[]).await;
+            assert_eq!(client.recv().await.seq(), syn_ack.seq());
+            let again = client.recv().await;
+            assert_eq!(again.flags(), TCP_SYN | TCP_ACK);
+            assert_eq!(again.seq(), syn_ack.seq());
+            client.seq = client.seq.wrapping_add(1);
+            client.ack = syn_ack.seq().wrapping_add(1);
+            client.send_ack().await;
+            let mut stream = accept(&stack).await;
+
+            // Unacknowledged data is sent again after the RTO.
+            stream.write_all(b"data").await.unwrap();
+            let first = client.recv().await;
+            assert_eq!(first.payload(), b"data");
+            let start = Instant::now();
+            let second = client.recv().await;
+            assert_eq!(second.seq(), first.seq());
+            assert_eq!(second.payload(), b"data");
+            assert!(start.elapsed() >= TCP_RTO_MIN / 2);
+            client.ack = client.ack.wrapping_add(4);
+            client.send_ack().await;
+            assert!(client.try_recv(Duration::from_millis(500)).await.is_none());
+
+            // Three duplicate ACKs trigger a fast retransmit.
+            stream.write_all(b"0123456789").await.unwrap();
+            stream.write_all(b"abcdefghij").await.unwrap();
+            let first = client.recv().await;
+            let _ = client.recv().await;
+            for _ in 0..3 {
+                client.send_ack().await;
+            }
+            let resent = client.recv().await;
+            // Retransmissions resend up to an MSS from the first unacknowledged byte.
+            assert_eq!(resent.seq(), first.seq());
+            assert_eq!(resent.payload(), b"0123456789abcdefghij");
+            client.ack = client.ack.wrapping_add(20);
+            client.send_ack().await;
+
+            // Duplicate and out of order data are acknowledged but delivered once, in order.
+            let seq = client.seq;
+            client.seq = seq.wrapping_add(3);
+            client.send_data(b"def").await;
+            assert_eq!(client.recv().await.ack(), seq);
+            client.seq = seq;
+            client.send_data(b"abc").await;
+            client.seq = seq;
+            client.send_data(b"abcdef").await;
+            client.send_data(b"ghi").await;
+            let mut buf = [0u8; 9];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"abcdefghi");
+        });
+    }
+
+    #[test]
+    fn test_stack_tcp_window() {
+        task::block_on(async {
+            let options = StackOptions::new().recv_buffer_size(2000);
+            let (stack, mut client) = setup(options, "10.0.0.2:40000", "1.2.3.4:80");
+            let syn_ack = client.connect(1000, None).await;
+            assert_eq!(syn_ack.window(), 2000);
+            let mut stream = accept(&stack).await;
+
+            // The stack never sends past the client's window.
+            client.window = 100;
+            client.send_ack().await;
+            task::sleep(Duration::from_millis(50)).await;
+            stream.write_all(&[1u8; 1000]).await.unwrap();
+            let tcp = client.recv().await;
+            assert_eq!(tcp.payload().len(), 100);
+            assert!(client.try_recv(Duration::from_millis(100)).await.is_none());
+
+            // A closed window is probed until it opens again.
+            client.ack = client.ack.wrapping_add(100);
+            client.window = 0;
+            client.send_ack().await;
+            let probe = client.recv().await;
+            assert_eq!(probe.seq(), client.ack);
+            assert_eq!(probe.payload().len(), 1);
+            client.window = 2000;
+            client.ack = client.ack.wrapping_add(1);
+            client.send_ack().await;
+            assert_eq!(client.recv_data(899, 1000).await, [1u8; 899]);
+
+            // The stack's own window closes while nothing reads, and reopens after a read.
+            client.send_data(&[2u8; 1000]).await;
+            assert_eq!(client.recv().await.window(), 1000);
+            client.send_data(&[3u8; 1000]).await;
+            assert_eq!(client.recv().await.window(), 0);
+            let mut buf = vec![0u8; 2000];
+            stream.read_exact(&mut buf).await.unwrap();
+            let update = client.recv().await;
+            assert_eq!(update.ack(), client.seq);
+            assert_eq!(update.window(), 2000);
+        });
+    }
+
+    #[test]
+    fn test_stack_tcp_reset() {
+        task::block_on(async {
+            let (stack, mut client) = setup(StackOptions::new(), "10.0.0.2:40000", "1.2.3.4:80");
+            client.connect(1000, None).await;
+            let mut stream = accept(&stack).await;
+            client.send(client.builder().flags(TCP_RST), &[]).await;
+            let mut buf = [0u8; 1];
+            assert_eq!(
+                stream.read(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionReset
+            );
+            assert_eq!(
+                stream.write(b"x").await.unwrap_err().kind(),
+                io::ErrorKind::ConnectionReset
+            );
+
+            // Segments for unknown connections are reset, RSTs are not answered.
+            let mut other = Client::new(client.device.clone(), "10.0.0.3:1", "1.2.3.4:80");
+            other.ack = 1234;
+            other.send_ack().await;
+            let rst = other.recv().await;
+            assert_eq!(rst.flags(), TCP_RST);
+            assert_eq!(rst.seq(), 1234);
+            other.send_data(b"abc").await;
+            other.send(other.builder().flags(TCP_FIN), &[]).await;
+            let _ = other.recv().await;
+            let rst = other.recv().await;
+            assert_eq!(rst.flags(), TCP_RST | TCP_ACK);
+            assert_eq!(rst.ack(), other.seq.wrapping_add(1));
+            other.send(other.builder().flags(TCP_RST), &[]).await;
+            assert!(other.try_recv(Duration::from_millis(100)).await.is_none());
+
+            // Closing with unread data resets the connection.
+            let mut client = Client::new(client.device.clone(), "10.0.0.4:1", "1.2.3.4:80");
+            client.connect(1000, None).await;
+            let stream = accept(&stack).await;
+            client.send_data(b"unread").await;
+            let _ = client.recv().await;
+            drop(stream);
+            assert!(client.recv().await.has_flags(TCP_RST));
+        });
+    }
+
+    async fn recv_udp(client: &Client) -> (SocketAddr, SocketAddr, Vec<u8>) {
+        let packet = client.recv_packet(TIMEOUT).await.unwrap();
+        let (src, dst, payload): (IpAddr, IpAddr, _) = match packet[0] >> 4 {
+            4 => {
+                let ip = Ipv4Packet::new(&packet[..]).unwrap();
+                (
+                    ip.src_addr().into(),
+                    ip.dst_addr().into(),
+                    ip.payload().to_vec(),
+                )
+            }
+            _ => {
+                let ip = Ipv6Packet::new(&packet[..]).unwrap();
+                (
+                    ip.src_addr().into(),
+                    ip.dst_addr().into(),
+                    ip.upper_layer_payload().to_vec(),
+                )
+            }
+        };
+        let udp = UdpPacket::new(&payload[..]).unwrap();
+        let valid = match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => udp.verify_checksum_v4(src, dst),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => udp.verify_checksum_v6(src, dst),
+            _ => false,
+        };
+        assert!(valid);
+        (
+            SocketAddr::new(src, udp.src_port()),
+            SocketAddr::new(dst, udp.dst_port()),
+            udp.payload().to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_stack_tcp_syn_backlog() {
+        task::block_on(async {
+            let options = StackOptions::new().backlog(2);
+            let (stack, client) = setup(options, "10.0.0.2:40000", "1.2.3.4:80");
+            let mut clients: Vec<_> = (1..=3)
+                .map(|i| {
+                    Client::new(
+                        client.device.clone(),
+                        &format!("10.0.0.2:{}", i),
+                        "1.2.3.4:80",
+                    )
+                })
+                .collect();
+            for client in &clients {
+                client.send(client.builder().flags(TCP_SYN), &[]).await;
+            }
+            // Only the first two half-open connections are kept, the third SYN is dropped.
+            let syn_ack = clients[0].recv().await;
+            assert_eq!(clients[1].recv().await.flags(), TCP_SYN | TCP_ACK);
+            assert!(clients[2]
+                .try_recv(Duration::from_millis(200))
+                .await
+                .is_none());
+
+            // Completing a handshake makes room for another.
+            let first = &mut clients[0];
+            first.seq = first.seq.wrapping_add(1);
+            first.ack = syn_ack.seq().wrapping_add(1);
+            first.send_ack().await;
+            let _stream = accept(&stack).await;
+            let third = &clients[2];
+            third.send(third.builder().flags(TCP_SYN), &[]).await;
+            let syn_ack = third.recv().await;
+            assert_eq!(syn_ack.flags(), TCP_SYN | TCP_ACK);
+        });
+    }
+
+    #[test]
+    fn test_stack_udp() {
+        task::block_on(async {
+            let options = StackOptions::new().udp_timeout(Duration::from_millis(200));
+            let (stack, client) = setup(options, "10.0.0.2:5353", "8.8.8.8:53");
+            let v6 = Client::new(
+                client.device.clone(),
+                "[fd00::2]:5353",
+                "[2001:4860:4860::8888]:53",
+            );
+
+            client.send_udp(b"query 1").await;
+            v6.send_udp(b"query 2").await;
+            client.send_udp(b"query 3").await;
+            let first = stack.accept_udp().timeout(TIMEOUT).await.unwrap().unwrap();
+            let second = stack.accept_udp().timeout(TIMEOUT).await.unwrap().unwrap();
+            assert_eq!(first.local_addr(), client.key.remote);
+            assert_eq!(first.peer_addr(), client.key.local);
+            assert_eq!(second.local_addr(), v6.key.remote);
+            assert_eq!(second.peer_addr(), v6.key.local);
+
+            let mut buf = Buffer::new();
+            first.recv_buf(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], b"query 1");
+            first.recv_buf(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], b"query 3");
+            second.recv_buf(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], b"query 2");
+
+            first.send_all(b"answer 1").await.unwrap();
+            assert_eq!(
+                recv_udp(&client).await,
+                (client.key.remote, client.key.local, b"answer 1".to_vec())
+            );
+            second.send_all(b"answer 2").await.unwrap();
+            assert_eq!(
+                recv_udp(&client).await,
+                (v6.key.remote, v6.key.local, b"answer 2".to_vec())
+            );
+
+            // Idle flows expire, a new datagram starts a new flow.
+            assert_eq!(
+                first
+                    .recv_buf(&mut buf)
+                    .timeout(TIMEOUT)
+                    .await
+                    .unwrap()
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::TimedOut
+            );
+            assert_eq!(
+                first.send_all(b"late").await.unwrap_err().kind(),
+                io::ErrorKind::TimedOut
+            );
+            client.send_udp(b"query 4").await;
+            let third = stack.accept_udp().timeout(TIMEOUT).await.unwrap().unwrap();
+            third.recv_buf(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], b"query 4");
+
+            // Dropping the stack ends its flows.
+            drop(stack);
+            assert_eq!(
+                third
+                    .recv_buf(&mut buf)
+                    .timeout(TIMEOUT)
+                    .await
+                    .unwrap()
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::NotConnected
+            );
+            assert_eq!(
+                third.send_all(b"late").await.unwrap_err().kind(),
+                io::ErrorKind::NotConnected
+            );
+        });
+    }
+
+    #[test]
+    fn test_stack_udp_backpressure() {
+        task::block_on(async {
+            let (stack, client) = setup(StackOptions::new(), "10.0.0.2:5353", "10.0.0.1:53");
+            client.send_udp(b"open").await;
+            let socket = stack.accept_udp().timeout(TIMEOUT).await.unwrap().unwrap();
+
+            // With the device not read, sends wait once the queues towards it are full.
+            let mut sent = 0;
+            while socket
+                .send_all(b"flood")
+                .timeout(Duration::from_millis(50))
+                .await
+                .is_ok()
+            {
+                sent += 1;
+                assert!(sent <= 2 * UDP_OUT_QUEUE_LEN + 1);
+            }
+            assert!(sent >= UDP_OUT_QUEUE_LEN);
+            for _ in 0..sent {
+                assert_eq!(recv_udp(&client).await.2, b"flood");
+            }
+            socket.send_all(b"drained").await.unwrap();
+            assert_eq!(recv_udp(&client).await.2, b"drained");
+        });
+    }
+
+    #[test]
+    fn test_stack_udp_fragments() {
+        task::block_on(async {
+            let (stack, client) = setup(StackOptions::new(), "10.0.0.2:5353", "10.0.0.1:53");
+            let payload: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+            let fragments = fragment_ipv4(&client.udp_packet(&payload), 1500).unwrap();
+            assert_eq!(fragments.len(), 3);
+            for fragment in fragments.iter().rev() {
+                client.device.send(fragment).await.unwrap();
+            }
+
+            let socket = stack.accept_udp().timeout(TIMEOUT).await.unwrap().unwrap();
+            let mut buf = Buffer::new();
+            socket.recv_buf(&mut buf).await.unwrap();
+            assert_eq!(&buf[..], &payload[..]);
+
+            // Datagrams to the peer are fragmented to the MTU.
+            socket.send_all(&payload).await.unwrap();
+            let mut reassembler = Ipv4Reassembler::new();
+            for i in 0..3 {
+                let fragment = client.recv_packet(TIMEOUT).await.unwrap();
+                assert!(fragment.len() <= 1500);
+                let whole = reassembler.push(&fragment).unwrap();
+                assert_eq!(whole.is_some(), i == 2);
+                if let Some(whole) = whole {
+                    let ip = Ipv4Packet::new(&whole[..]).unwrap();
+                    let udp = UdpPacket::new(ip.payload()).unwrap();
+                    assert!(udp.verify_checksum_v4(ip.src_addr(), ip.dst_addr()));
+                    assert_eq!(udp.payload(), &payload[..]);
+                }
+            }
+
+            // IPv6 is not fragmented.
+            let v6 = Client::new(client.device.clone(), "[fd00::2]:5353", "[2001:db8::1]:53");
+            v6.send_udp(b"open").await;
+            let socket = stack.accept_udp().timeout(TIMEOUT).await.unwrap().unwrap();
+            assert_eq!(
+                socket.send_all(&payload).await.unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+            socket.send_all(&payload[..1400]).await.unwrap();
+            assert_eq!(recv_udp(&v6).await.2, &payload[..1400]);
+        });
+    }
+
+    #[test]
+    fn test_stack_relay() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let upstream = listener.local_addr().unwrap();
+            task::spawn(async move {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                conn.read_to_end(&mut request).await.unwrap();
+                request.reverse();
+                conn.write_all(&request).await.unwrap();
+            });
+
+            let (stack, mut client) =
+                setup(StackOptions::new(), "10.0.0.2:40000", &upstream.to_string());
+            client.connect(1400, Some(7)).await;
+            let stream = accept(&stack).await;
+            let conn = stream.local_addr().dial_tcp().await.unwrap();
+            let relayed = task::spawn(relay(stream, conn, None));
+
+            let request: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+            for chunk in request.chunks(1400) {
+                client.send_data(chunk).await;
+            }
+            client.send_fin().await;
+            let mut response = Vec::new();
+            loop {
+                let tcp = client.recv().await;
+                if !tcp.payload().is_empty() {
+                    assert_eq!(tcp.seq(), client.ack);
+                    response.extend_from_slice(tcp.payload());
+                    client.ack = client.ack.wrapping_add(tcp.payload().len() as u32);
+                    client.send_ack().await;
+                }
+                if tcp.has_flags(TCP_FIN) {
+                    client.ack = client.ack.wrapping_add(1);
+                    client.send_ack().await;
+                    break;
+                }
+            }
+            let mut expected = request.clone();
+            expected.reverse();
+            assert_eq!(response, expected);
+            let stats = relayed.await.unwrap();
+            assert_eq!((stats.a_to_b, stats.b_to_a), (10_000, 10_000));
+        });
+    }
+}
@@ -0,0 +1,408 @@
+use crate::{BytesExt, BytesMutExt, PacketError, IP_PROTO_TCP};
+use bytes::{BufMut, BytesMut};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TCP_HEADER_LEN: usize = 20;
+
+pub const TCP_MAX_HEADER_LEN: usize = 60;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+const TCP_OPT_WINDOW_SCALE: u8 = 3;
+const TCP_OPT_SACK_PERMITTED: u8 = 4;
+const TCP_OPT_TIMESTAMPS: u8 = 8;
+
+/// Zero-copy view over a TCP segment.
+#[derive(Debug, Clone)]
+pub struct TcpPacket<T: AsRef<[u8]>> {
+    buf: T,
+}
+
+impl<T: AsRef<[u8]>> TcpPacket<T> {
+    /// Wraps `buf` without looking at it. Accessors may panic on a malformed buffer.
+    pub fn new_unchecked(buf: T) -> Self {
+        TcpPacket { buf }
+    }
+
+    /// Wraps `buf` after checking the data offset against it.
+    pub fn new(buf: T) -> Result<Self, PacketError> {
+        let packet = TcpPacket { buf };
+        let len = packet.buf.as_ref().len();
+        if len < TCP_HEADER_LEN {
+            return Err(PacketError::Truncated);
+        }
+        let header_len = packet.header_len();
+        if header_len < TCP_HEADER_LEN {
+            return Err(PacketError::HeaderLen(header_len));
+        }
+        if header_len > len {
+            return Err(PacketError::Truncated);
+        }
+        Ok(packet)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buf
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buf.as_ref()
+    }
+
+    pub fn src_port(&self) -> u16 {
+        self.buf.as_ref()[0..].u16()
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        self.buf.as_ref()[2..].u16()
+    }
+
+    pub fn seq(&self) -> u32 {
+        self.buf.as_ref()[4..].u32()
+    }
+
+    pub fn ack(&self) -> u32 {
+        self.buf.as_ref()[8..].u32()
+    }
+
+    pub fn header_len(&self) -> usize {
+        ((self.buf.as_ref()[12] >> 4) as usize) << 2
+    }
+
+    /// Control bits, see the `TCP_*` flag constants.
+    pub fn flags(&self) -> u8 {
+        self.buf.as_ref()[13] & 0x3f
+    }
+
+    pub fn has_flags(&self, flags: u8) -> bool {
+        self.flags() & flags == flags
+    }
+
+    pub fn window(&self) -> u16 {
+        self.buf.as_ref()[14..].u16()
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.buf.as_ref()[16..].u16()
+    }
+
+    pub fn urgent_pointer(&self) -> u16 {
+        self.buf.as_ref()[18..].u16()
+    }
+
+    pub fn options(&self) -> TcpOptions<'_> {
+        TcpOptions {
+            buf: &self.buf.as_ref()[TCP_HEADER_LEN..self.header_len()],
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf.as_ref()[self.header_len()..]
+    }
+
+    /// Sequence space taken by the segment: its payload plus one for each of SYN and FIN.
+    pub fn segment_len(&self) -> u32 {
+        let flags = self.flags();
+        self.payload().len() as u32 + (flags & TCP_SYN != 0) as u32 + (flags & TCP_FIN != 0) as u32
+    }
+
+    pub fn verify_checksum_v4(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+        self.buf
+            .as_ref()
+            .verify_checksum_ipv4(src, dst, IP_PROTO_TCP)
+    }
+
+    pub fn verify_checksum_v6(&self, src: Ipv6Addr, dst: Ipv6Addr) -> bool {
+        self.buf
+            .as_ref()
+            .verify_checksum_ipv6(src, dst, IP_PROTO_TCP)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> TcpPacket<T> {
+    pub fn set_src_port(&mut self, port: u16) {
+        self.buf.as_mut().write_be(0, port).unwrap();
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        self.buf.as_mut().write_be(2, port).unwrap();
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.buf.as_mut().write_be(16, checksum).unwrap();
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let header_len = self.header_len();
+        &mut self.buf.as_mut()[header_len..]
+    }
+
+    pub fn fill_checksum_v4(&mut self, src: Ipv4Addr, dst: Ipv4Addr) {
+        self.buf
+            .as_mut()
+            .fill_checksum_ipv4(16, src, dst, IP_PROTO_TCP)
+            .unwrap();
+    }
+
+    pub fn fill_checksum_v6(&mut self, src: Ipv6Addr, dst: Ipv6Addr) {
+        self.buf
+            .as_mut()
+            .fill_checksum_ipv6(16, src, dst, IP_PROTO_TCP)
+            .unwrap();
+    }
+}
+
+/// TCP options understood by [`TcpOptions`]; anything else is reported as `Other`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TcpOption<'a> {
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Timestamps { value: u32, echo_reply: u32 },
+    Other { kind: u8, data: &'a [u8] },
+}
+
+/// Iterator over the options of a [`TcpPacket`], ending at the first malformed option.
+#[derive(Debug, Clone)]
+pub struct TcpOptions<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for TcpOptions<'a> {
+    type Item = TcpOption<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match *self.buf.first()? {
+                TCP_OPT_END => {
+                    self.buf = &[];
+                    return None;
+                }
+                TCP_OPT_NOP => self.buf = &self.buf[1..],
+                kind => {
+                    let len = *self.buf.get(1)? as usize;
+                    if len < 2 || len > self.buf.len() {
+                        self.buf = &[];
+                        return None;
+                    }
+                    let data = &self.buf[2..len];
+                    self.buf = &self.buf[len..];
+                    return Some(match (kind, data.len()) {
+                        (TCP_OPT_MSS, 2) => TcpOption::Mss(data.u16()),
+                        (TCP_OPT_WINDOW_SCALE, 1) => TcpOption::WindowScale(data[0]),
+                        (TCP_OPT_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+                        (TCP_OPT_TIMESTAMPS, 8) => TcpOption::Timestamps {
+                            value: data.u32(),
+                            echo_reply: data[4..].u32(),
+                        },
+                        _ => TcpOption::Other { kind, data },
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Builder for TCP segments, see [`TcpPacket`] for the field meanings.
+#[derive(Debug, Clone)]
+pub struct TcpBuilder {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    window_scale: Option<u8>,
+}
+
+impl TcpBuilder {
+    pub fn new(src_port: u16, dst_port: u16) -> Self {
+        TcpBuilder {
+            src_port,
+            dst_port,
+            seq: 0,
+            ack: 0,
+            flags: 0,
+            window: 0,
+            mss: None,
+            window_scale: None,
+        }
+    }
+
+    pub fn seq(mut self, seq: u32) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    /// Sets the acknowledgment number and the ACK flag.
+    pub fn ack(mut self, ack: u32) -> Self {
+        self.ack = ack;
+        self.flags |= TCP_ACK;
+        self
+    }
+
+    /// Adds `flags` to the control bits.
+    pub fn flags(mut self, flags: u8) -> Self {
+        self.flags |= flags & 0x3f;
+        self
+    }
+
+    pub fn window(mut self, window: u16) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn mss(mut self, mss: u16) -> Self {
+        self.mss = Some(mss);
+        self
+    }
+
+    pub fn window_scale(mut self, shift: u8) -> Self {
+        self.window_scale = Some(shift);
+        self
+    }
+
+    pub fn header_len(&self) -> usize {
+        TCP_HEADER_LEN + self.mss.map_or(0, |_| 4) + self.window_scale.map_or(0, |_| 4)
+    }
+
+    fn emit(&self, payload: &[u8]) -> BytesMut {
+        let header_len = self.header_len();
+        let mut buf = BytesMut::with_capacity(header_len + payload.len());
+        buf.put_u16(self.src_port);
+        buf.put_u16(self.dst_port);
+        buf.put_u32(self.seq);
+        buf.put_u32(self.ack);
+        buf.put_u8((header_len >> 2 << 4) as u8);
+        buf.put_u8(self.flags);
+        buf.put_u16(self.window);
+        buf.put_u32(0);
+        if let Some(mss) = self.mss {
+            buf.put_u8(TCP_OPT_MSS);
+            buf.put_u8(4);
+            buf.put_u16(mss);
+        }
+        if let Some(shift) = self.window_scale {
+            buf.put_u8(TCP_OPT_NOP);
+            buf.put_u8(TCP_OPT_WINDOW_SCALE);
+            buf.put_u8(3);
+            buf.put_u8(shift);
+        }
+        buf.put_slice(payload);
+        buf
+    }
+
+    /// Writes the segment followed by `payload`, filling in the checksum for an IPv4 packet.
+    pub fn emit_v4(&self, src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> BytesMut {
+        let mut buf = self.emit(payload);
+        buf.fill_checksum_ipv4(16, src, dst, IP_PROTO_TCP).unwrap();
+        buf
+    }
+
+    /// Writes the segment followed by `payload`, filling in the checksum for an IPv6 packet.
+    pub fn emit_v6(&self, src: Ipv6Addr, dst: Ipv6Addr, payload: &[u8]) -> BytesMut {
+        let mut buf = self.emit(payload);
+        buf.fill_checksum_ipv6(16, src, dst, IP_PROTO_TCP).unwrap();
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_builder() {
+        let (src, dst) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let b = TcpBuilder::new(40000, 80)
+            .seq(1000)
+            .ack(2000)
+            .flags(TCP_SYN)
+            .window(65535)
+            .mss(1460)
+            .window_scale(7)
+            .emit_v4(src, dst, b"hi");
+        let p = TcpPacket::new(&b[..]).unwrap();
+        assert!(p.verify_checksum_v4(src, dst));
+        assert!(!p.verify_checksum_v4(dst, Ipv4Addr::new(10, 0, 0, 3)));
+        assert_eq!(p.src_port(), 40000);
+        assert_eq!(p.dst_port(), 80);
+        assert_eq!(p.seq(), 1000);
+        assert_eq!(p.ack(), 2000);
+        assert_eq!(p.header_len(), 28);
+        assert_eq!(p.flags(), TCP_SYN | TCP_ACK);
+        assert!(p.has_flags(TCP_SYN | TCP_ACK));
+        assert!(!p.has_flags(TCP_FIN));
+        assert_eq!(p.window(), 65535);
+        assert_eq!(p.payload(), b"hi");
+        assert_eq!(p.segment_len(), 3);
+        assert_eq!(
+            p.options().collect::<Vec<_>>(),
+            [TcpOption::Mss(1460), TcpOption::WindowScale(7)]
+        );
+
+        let (src, dst) = ("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap());
+        let b = TcpBuilder::new(1, 2).flags(TCP_RST).emit_v6(src, dst, &[]);
+        let mut p = TcpPacket::new(b).unwrap();
+        assert!(p.verify_checksum_v6(src, dst));
+        p.set_src_port(3);
+        assert!(!p.verify_checksum_v6(src, dst));
+        p.fill_checksum_v6(src, dst);
+        assert!(p.verify_checksum_v6(src, dst));
+        assert_eq!(p.src_port(), 3);
+    }
+
+    #[test]
+    fn test_tcp_options() {
+        let mut b = TcpBuilder::new(1, 2).emit_v4(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, &[]);
+        // Timestamps, SACK permitted, an unknown option, padding and end of list.
+        b.extend_from_slice(&[
+            8, 10, 0, 0, 0, 1, 0, 0, 0, 2, 4, 2, 254, 3, 9, 1, 0, 0, 0, 0,
+        ]);
+        b[12] = (40 >> 2) << 4;
+        let p = TcpPacket::new(&b[..]).unwrap();
+        assert_eq!(
+            p.options().collect::<Vec<_>>(),
+            [
+                TcpOption::Timestamps {
+                    value: 1,
+                    echo_reply: 2
+                },
+                TcpOption::SackPermitted,
+                TcpOption::Other {
+                    kind: 254,
+                    data: &[9]
+                },
+            ]
+        );
+
+        // A length running past the header ends the list.
+        b[21] = 30;
+        let p = TcpPacket::new(&b[..]).unwrap();
+        assert_eq!(p.options().count(), 0);
+
+        assert_eq!(
+            TcpPacket::new(&b[..19]).unwrap_err(),
+            PacketError::Truncated
+        );
+        b[12] = 4 << 4;
+        assert_eq!(
+            TcpPacket::new(&b[..]).unwrap_err(),
+            PacketError::HeaderLen(16)
+        );
+        b[12] = 15 << 4;
+        assert_eq!(
+            TcpPacket::new(&b[..40]).unwrap_err(),
+            PacketError::Truncated
+        );
+    }
+}
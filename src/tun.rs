@@ -0,0 +1,386 @@
+use crate::Buffer;
+use async_io::Async;
+use socket2::{Domain, Socket, Type};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+fn ioctl<T>(fd: &impl AsRawFd, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), request as _, arg as *mut T) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ || name.as_bytes().contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid interface name {:?}", name),
+        ));
+    }
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(req)
+}
+
+fn sockaddr_v4(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    unsafe { std::mem::transmute(sin) }
+}
+
+fn control_socket(domain: Domain) -> io::Result<Socket> {
+    Socket::new(domain, Type::dgram(), None)
+}
+
+/// Options for creating a TUN device, or attaching another queue to one, with
+/// [`TunOptions::open`].
+#[derive(Debug, Clone, Default)]
+pub struct TunOptions {
+    name: Option<String>,
+    tap: bool,
+    packet_info: bool,
+    multi_queue: bool,
+    mtu: Option<u32>,
+    addrs: Vec<(IpAddr, u8)>,
+    up: bool,
+}
+
+impl TunOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Interface name; a `%d` is replaced by the kernel with the first free number.
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Creates a TAP device, which carries Ethernet frames instead of IP packets.
+    pub fn tap(mut self, tap: bool) -> Self {
+        self.tap = tap;
+        self
+    }
+
+    /// Keeps the 4-byte packet information header in front of every packet; off by default
+    /// (IFF_NO_PI) so reads and writes are bare IP packets.
+    pub fn packet_info(mut self, packet_info: bool) -> Self {
+        self.packet_info = packet_info;
+        self
+    }
+
+    /// Sets IFF_MULTI_QUEUE; opening the same name again attaches another queue.
+    pub fn multi_queue(mut self, multi_queue: bool) -> Self {
+        self.multi_queue = multi_queue;
+        self
+    }
+
+    pub fn mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Assigns `addr` with a `prefix_len` bit network; may be called once per address.
+    pub fn address(mut self, addr: IpAddr, prefix_len: u8) -> Self {
+        self.addrs.push((addr, prefix_len));
+        self
+    }
+
+    /// Brings the interface up.
+    pub fn up(mut self, up: bool) -> Self {
+        self.up = up;
+        self
+    }
+
+    /// Opens the device and applies the options. Needs CAP_NET_ADMIN.
+    pub fn open(&self) -> io::Result<Tun> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(TUN_PATH)?;
+        let mut req = ifreq(self.name.as_deref().unwrap_or(""))?;
+        let mut flags = if self.tap {
+            libc::IFF_TAP
+        } else {
+            libc::IFF_TUN
+        };
+        if !self.packet_info {
+            flags |= libc::IFF_NO_PI;
+        }
+        if self.multi_queue {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+        req.ifr_ifru.ifru_flags = flags as libc::c_short;
+        ioctl(&file, libc::TUNSETIFF as libc::c_ulong, &mut req)?;
+        let name = req
+            .ifr_name
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8 as char)
+            .collect();
+        let tun = Tun {
+            file: Async::new(file)?,
+            name,
+        };
+        if let Some(mtu) = self.mtu {
+            tun.set_mtu(mtu)?;
+        }
+        for (addr, prefix_len) in &self.addrs {
+            tun.add_address(*addr, *prefix_len)?;
+        }
+        if self.up {
+            tun.set_up(true)?;
+        }
+        Ok(tun)
+    }
+}
+
+/// Async handle to one queue of a Linux TUN/TAP device.
+///
+/// Without [`packet_info`](TunOptions::packet_info) every read yields one whole IP packet,
+/// ready for [`Ipv4Packet`](crate::Ipv4Packet) or [`Ipv6Packet`](crate::Ipv6Packet). The
+/// device is removed once its last queue is closed.
+#[derive(Debug)]
+pub struct Tun {
+    file: Async<File>,
+    name: String,
+}
+
+impl Tun {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Receives one packet into `buf`, which should hold at least the MTU.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read_with(|mut file| file.read(buf)).await
+    }
+
+    /// Receives one packet into `buf`, replacing its contents.
+    pub async fn recv_buf(&self, buf: &mut Buffer) -> io::Result<usize> {
+        let n = self.recv(buf.storage_mut()).await?;
+        buf.set_len(n);
+        Ok(n)
+    }
+
+    /// Sends one whole packet.
+    pub async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.file.write_with(|mut file| file.write(packet)).await
+    }
+
+    pub fn mtu(&self) -> io::Result<u32> {
+        let mut req = ifreq(&self.name)?;
+        ioctl(&control_socket(Domain::ipv4())?, libc::SIOCGIFMTU, &mut req)?;
+        Ok(unsafe { req.ifr_ifru.ifru_mtu } as u32)
+    }
+
+    pub fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        let mut req = ifreq(&self.name)?;
+        req.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+        ioctl(&control_socket(Domain::ipv4())?, libc::SIOCSIFMTU, &mut req)
+    }
+
+    pub fn set_up(&self, up: bool) -> io::Result<()> {
+        let sock = control_socket(Domain::ipv4())?;
+        let mut req = ifreq(&self.name)?;
+        ioctl(&sock, libc::SIOCGIFFLAGS, &mut req)?;
+        let up_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        unsafe {
+            if up {
+                req.ifr_ifru.ifru_flags |= up_flags;
+            } else {
+                req.ifr_ifru.ifru_flags &= !up_flags;
+            }
+        }
+        ioctl(&sock, libc::SIOCSIFFLAGS, &mut req)
+    }
+
+    /// Assigns `addr` with a `prefix_len` bit network. An IPv4 device holds a single address,
+    /// which this replaces.
+    pub fn add_address(&self, addr: IpAddr, prefix_len: u8) -> io::Result<()> {
+        match addr {
+            IpAddr::V4(addr) => {
+                if prefix_len > 32 {
+                    return Err(invalid_prefix(prefix_len));
+                }
+                let sock = control_socket(Domain::ipv4())?;
+                let mut req = ifreq(&self.name)?;
+                req.ifr_ifru.ifru_addr = sockaddr_v4(addr);
+                ioctl(&sock, libc::SIOCSIFADDR, &mut req)?;
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                req.ifr_ifru.ifru_netmask = sockaddr_v4(mask.into());
+                ioctl(&sock, libc::SIOCSIFNETMASK, &mut req)
+            }
+            IpAddr::V6(addr) => {
+                if prefix_len > 128 {
+                    return Err(invalid_prefix(prefix_len));
+                }
+                let sock = control_socket(Domain::ipv6())?;
+                let mut req = ifreq(&self.name)?;
+                ioctl(&sock, libc::SIOCGIFINDEX, &mut req)?;
+                let mut req6 = libc::in6_ifreq {
+                    ifr6_addr: libc::in6_addr {
+                        s6_addr: Ipv6Addr::octets(&addr),
+                    },
+                    ifr6_prefixlen: prefix_len as u32,
+                    ifr6_ifindex: unsafe { req.ifr_ifru.ifru_ifindex },
+                };
+                ioctl(&sock, libc::SIOCSIFADDR, &mut req6)
+            }
+        }
+    }
+}
+
+fn invalid_prefix(prefix_len: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid prefix length {}", prefix_len),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{IcmpPacket, Icmpv4Message, Ipv4Builder, Ipv4Packet, IP_PROTO_ICMP, IP_PROTO_UDP};
+    use async_std::net::UdpSocket;
+    use async_std::prelude::FutureExt;
+    use async_std::task;
+    use std::time::Duration;
+
+    /// Opens a device, or returns `None` where TUN is unavailable or CAP_NET_ADMIN is missing.
+    fn open(opts: TunOptions) -> Option<Tun> {
+        match opts.open() {
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound
+                ) =>
+            {
+                None
+            }
+            res => Some(res.unwrap()),
+        }
+    }
+
+    async fn recv_matching(tun: &Tun, mut f: impl FnMut(&[u8]) -> bool) -> Vec<u8> {
+        let mut buf = Buffer::new();
+        async {
+            loop {
+                tun.recv_buf(&mut buf).await.unwrap();
+                if f(&buf) {
+                    return buf.to_vec();
+                }
+            }
+        }
+        .timeout(Duration::from_secs(5))
+        .await
+        .expect("no matching packet")
+    }
+
+    #[test]
+    fn test_tun_options() {
+        assert_eq!(
+            TunOptions::new()
+                .name("a-name-that-is-too-long")
+                .open()
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let tun = match open(TunOptions::new().name("pooh%d").mtu(1400)) {
+            Some(tun) => tun,
+            None => return,
+        };
+        assert!(tun.name().starts_with("pooh"));
+        assert_eq!(tun.mtu().unwrap(), 1400);
+        tun.set_mtu(1300).unwrap();
+        assert_eq!(tun.mtu().unwrap(), 1300);
+        tun.set_up(true).unwrap();
+        tun.add_address("fd89:64::1".parse().unwrap(), 64).unwrap();
+        tun.set_up(false).unwrap();
+        assert_eq!(
+            tun.add_address(IpAddr::from([10, 0, 0, 1]), 33)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let name = "poohmq0";
+        let opts = TunOptions::new().name(name).multi_queue(true);
+        let first = open(opts.clone()).unwrap();
+        let second = opts.open().unwrap();
+        assert_eq!(first.name(), second.name());
+    }
+
+    #[test]
+    fn test_tun_packets() {
+        let local = Ipv4Addr::new(10, 89, 64, 1);
+        let peer = Ipv4Addr::new(10, 89, 64, 2);
+        let tun = match open(
+            TunOptions::new()
+                .name("poohpkt%d")
+                .address(local.into(), 24)
+                .up(true),
+        ) {
+            Some(tun) => tun,
+            None => return,
+        };
+        task::block_on(async {
+            // Traffic routed into the subnet comes out of the device as bare IP packets.
+            let sock = UdpSocket::bind((local, 0)).await.unwrap();
+            sock.send_to(b"hello tun", (peer, 9999)).await.unwrap();
+            let packet = recv_matching(&tun, |b| {
+                Ipv4Packet::new(b)
+                    .map(|p| p.protocol() == IP_PROTO_UDP && p.dst_addr() == peer)
+                    .unwrap_or(false)
+            })
+            .await;
+            let packet = Ipv4Packet::new(&packet[..]).unwrap();
+            assert_eq!(packet.src_addr(), local);
+            assert_eq!(&packet.payload()[8..], b"hello tun");
+
+            // Packets written to the device are delivered to the local stack.
+            let request = Icmpv4Message::EchoRequest {
+                identifier: 0x5a5a,
+                sequence: 1,
+            }
+            .emit(b"ping");
+            let request = Ipv4Builder::new(peer, local, IP_PROTO_ICMP)
+                .emit(&request)
+                .unwrap();
+            assert_eq!(tun.send(&request).await.unwrap(), request.len());
+            let reply = recv_matching(&tun, |b| {
+                Ipv4Packet::new(b)
+                    .map(|p| p.protocol() == IP_PROTO_ICMP && p.dst_addr() == peer)
+                    .unwrap_or(false)
+            })
+            .await;
+            let reply = Ipv4Packet::new(&reply[..]).unwrap();
+            let icmp = IcmpPacket::new(reply.payload()).unwrap();
+            assert_eq!(
+                icmp.icmpv4_message(),
+                Icmpv4Message::EchoReply {
+                    identifier: 0x5a5a,
+                    sequence: 1
+                }
+            );
+            assert_eq!(icmp.payload(), b"ping");
+        });
+    }
+}
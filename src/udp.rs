@@ -0,0 +1,207 @@
+use crate::{BytesExt, BytesMutExt, PacketError, IP_PROTO_UDP};
+use bytes::{BufMut, BytesMut};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const UDP_HEADER_LEN: usize = 8;
+
+/// Zero-copy view over a UDP datagram.
+#[derive(Debug, Clone)]
+pub struct UdpPacket<T: AsRef<[u8]>> {
+    buf: T,
+}
+
+impl<T: AsRef<[u8]>> UdpPacket<T> {
+    /// Wraps `buf` without looking at it. Accessors may panic on a malformed buffer.
+    pub fn new_unchecked(buf: T) -> Self {
+        UdpPacket { buf }
+    }
+
+    /// Wraps `buf` after checking the length field against it.
+    pub fn new(buf: T) -> Result<Self, PacketError> {
+        let packet = UdpPacket { buf };
+        let len = packet.buf.as_ref().len();
+        if len < UDP_HEADER_LEN {
+            return Err(PacketError::Truncated);
+        }
+        let length = packet.length() as usize;
+        if length < UDP_HEADER_LEN {
+            return Err(PacketError::TotalLen(length));
+        }
+        if length > len {
+            return Err(PacketError::Truncated);
+        }
+        Ok(packet)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.buf
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf.as_ref()[..self.length() as usize]
+    }
+
+    pub fn src_port(&self) -> u16 {
+        self.buf.as_ref()[0..].u16()
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        self.buf.as_ref()[2..].u16()
+    }
+
+    /// Length field, header included.
+    pub fn length(&self) -> u16 {
+        self.buf.as_ref()[4..].u16()
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.buf.as_ref()[6..].u16()
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.as_bytes()[UDP_HEADER_LEN..]
+    }
+
+    /// A zero checksum means none was computed, which IPv4 allows.
+    pub fn verify_checksum_v4(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+        self.checksum() == 0 || self.as_bytes().verify_checksum_ipv4(src, dst, IP_PROTO_UDP)
+    }
+
+    pub fn verify_checksum_v6(&self, src: Ipv6Addr, dst: Ipv6Addr) -> bool {
+        self.as_bytes().verify_checksum_ipv6(src, dst, IP_PROTO_UDP)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> UdpPacket<T> {
+    pub fn set_src_port(&mut self, port: u16) {
+        self.buf.as_mut().write_be(0, port).unwrap();
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        self.buf.as_mut().write_be(2, port).unwrap();
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.buf.as_mut().write_be(6, checksum).unwrap();
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let end = self.length() as usize;
+        &mut self.buf.as_mut()[UDP_HEADER_LEN..end]
+    }
+
+    pub fn fill_checksum_v4(&mut self, src: Ipv4Addr, dst: Ipv4Addr) {
+        let end = self.length() as usize;
+        let b = &mut self.buf.as_mut()[..end];
+        b.fill_checksum_ipv4(6, src, dst, IP_PROTO_UDP).unwrap();
+        // A computed zero is sent as all ones, zero meaning no checksum (RFC 768).
+        if b[6..].u16() == 0 {
+            b.write_be(6, 0xffffu16).unwrap();
+        }
+    }
+
+    pub fn fill_checksum_v6(&mut self, src: Ipv6Addr, dst: Ipv6Addr) {
+        let end = self.length() as usize;
+        let b = &mut self.buf.as_mut()[..end];
+        b.fill_checksum_ipv6(6, src, dst, IP_PROTO_UDP).unwrap();
+        if b[6..].u16() == 0 {
+            b.write_be(6, 0xffffu16).unwrap();
+        }
+    }
+}
+
+/// Builder for UDP datagrams.
+#[derive(Debug, Clone)]
+pub struct UdpBuilder {
+    src_port: u16,
+    dst_port: u16,
+}
+
+impl UdpBuilder {
+    pub fn new(src_port: u16, dst_port: u16) -> Self {
+        UdpBuilder { src_port, dst_port }
+    }
+
+    fn emit(&self, payload: &[u8]) -> Result<BytesMut, PacketError> {
+        let len = UDP_HEADER_LEN + payload.len();
+        if len > u16::MAX as usize {
+            return Err(PacketError::TooLarge(len));
+        }
+        let mut buf = BytesMut::with_capacity(len);
+        buf.put_u16(self.src_port);
+        buf.put_u16(self.dst_port);
+        buf.put_u16(len as u16);
+        buf.put_u16(0);
+        buf.put_slice(payload);
+        Ok(buf)
+    }
+
+    /// Writes the datagram, filling in the checksum for an IPv4 packet.
+    pub fn emit_v4(
+        &self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        payload: &[u8],
+    ) -> Result<BytesMut, PacketError> {
+        let mut packet = UdpPacket::new_unchecked(self.emit(payload)?);
+        packet.fill_checksum_v4(src, dst);
+        Ok(packet.into_inner())
+    }
+
+    /// Writes the datagram, filling in the checksum for an IPv6 packet.
+    pub fn emit_v6(
+        &self,
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+        payload: &[u8],
+    ) -> Result<BytesMut, PacketError> {
+        let mut packet = UdpPacket::new_unchecked(self.emit(payload)?);
+        packet.fill_checksum_v6(src, dst);
+        Ok(packet.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_packet() {
+        let (src, dst) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let mut b = UdpBuilder::new(5353, 53)
+            .emit_v4(src, dst, b"query")
+            .unwrap();
+        let p = UdpPacket::new(&b[..]).unwrap();
+        assert!(p.verify_checksum_v4(src, dst));
+        assert_eq!(p.src_port(), 5353);
+        assert_eq!(p.dst_port(), 53);
+        assert_eq!(p.length(), 13);
+        assert_eq!(p.payload(), b"query");
+
+        // Trailing bytes past the length field are not part of the datagram.
+        b.extend_from_slice(&[0; 3]);
+        let mut p = UdpPacket::new(&mut b[..]).unwrap();
+        assert_eq!(p.payload(), b"query");
+        p.set_dst_port(5300);
+        assert!(!p.verify_checksum_v4(src, dst));
+        p.set_checksum(0);
+        assert!(p.verify_checksum_v4(src, dst));
+        assert!(!p.verify_checksum_v6(Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST));
+        p.fill_checksum_v4(src, dst);
+        assert!(p.verify_checksum_v4(src, dst));
+
+        let (src, dst) = ("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap());
+        let b = UdpBuilder::new(1, 2).emit_v6(src, dst, &[]).unwrap();
+        assert!(UdpPacket::new(&b[..]).unwrap().verify_checksum_v6(src, dst));
+
+        assert_eq!(UdpPacket::new(&b[..7]).unwrap_err(), PacketError::Truncated);
+        let mut b = b.to_vec();
+        b[5] = 4;
+        assert_eq!(
+            UdpPacket::new(&b[..]).unwrap_err(),
+            PacketError::TotalLen(4)
+        );
+        b[5] = 9;
+        assert_eq!(UdpPacket::new(&b[..]).unwrap_err(), PacketError::Truncated);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{
+    framed, DialOptions, DualAddr, FamilyPreference, DATAGRAM_BUF_SIZE, HAPPY_EYEBALLS_DELAY,
+};
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use async_std::prelude::FutureExt;
+use async_std::stream::StreamExt;
+use async_std::task;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const UDP_OVER_TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Datagrams queued for a flow whose TCP connection is not writable yet; more are dropped.
+const FLOW_QUEUE_LEN: usize = 64;
+
+type Flows = Arc<Mutex<HashMap<SocketAddr, Sender<Bytes>>>>;
+
+enum Event<U, D> {
+    Up(U),
+    Down(D),
+}
+
+/// Client half of the UDP-over-TCP relay.
+///
+/// Every UDP peer sending to `local` gets its own TCP connection to `server`. Datagrams are
+/// carried as length-prefixed frames and replies are sent back to the peer they belong to. A
+/// flow is closed after `idle_timeout` without traffic in either direction.
+pub async fn udp_over_tcp_client(
+    local: UdpSocket,
+    server: SocketAddr,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    udp_over_tcp_client_with(
+        local,
+        &server.to_string(),
+        &DialOptions::default(),
+        idle_timeout,
+    )
+    .await
+}
+
+/// Like [`udp_over_tcp_client`], resolving the `host:port` of `server` for each flow with
+/// [`DualAddr::resolve_with`] and dialing it with `opts`.
+pub async fn udp_over_tcp_client_with(
+    local: UdpSocket,
+    server: &str,
+    opts: &DialOptions,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let local = Arc::new(local);
+    let flows = Flows::default();
+    let mut buf = vec![0u8; DATAGRAM_BUF_SIZE];
+    loop {
+        let (n, peer) = local.recv_from(&mut buf).await?;
+        let datagram = Bytes::copy_from_slice(&buf[..n]);
+        let mut guard = flows.lock().unwrap();
+        let datagram = match guard.get(&peer) {
+            Some(tx) => match tx.try_send(datagram) {
+                Ok(()) | Err(TrySendError::Full(_)) => continue,
+                Err(TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+        let (tx, rx) = channel::bounded(FLOW_QUEUE_LEN);
+        let _ = tx.try_send(datagram);
+        guard.insert(peer, tx);
+        drop(guard);
+        let (local, flows) = (local.clone(), flows.clone());
+        let (server, opts) = (server.to_string(), opts.clone());
+        task::spawn(async move {
+            let _ = client_flow(&local, peer, &server, &opts, &rx, idle_timeout).await;
+            rx.close();
+            let mut guard = flows.lock().unwrap();
+            if guard.get(&peer).is_some_and(|tx| tx.is_closed()) {
+                guard.remove(&peer);
+            }
+        });
+    }
+}
+
+async fn client_flow(
+    local: &UdpSocket,
+    peer: SocketAddr,
+    server: &str,
+    opts: &DialOptions,
+    rx: &Receiver<Bytes>,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let stream = DualAddr::resolve_with(server, FamilyPreference::Both, opts)
+        .await?
+        .happy_eyeballs_with(opts, HAPPY_EYEBALLS_DELAY)
+        .await?;
+    stream.set_nodelay(true)?;
+    let (mut reader, mut writer) = framed(stream);
+    loop {
+        let up = async { Event::Up(rx.recv().await) };
+        let down = async { Event::Down(reader.next().await) };
+        let event = match up.race(down).timeout(idle_timeout).await {
+            Ok(event) => event,
+            Err(_) => return Ok(()),
+        };
+        match event {
+            Event::Up(Ok(datagram)) => writer.send(&datagram).await?,
+            Event::Down(Some(frame)) => {
+                local.send_to(&frame?, peer).await?;
+            }
+            Event::Up(Err(_)) | Event::Down(None) => return Ok(()),
+        }
+    }
+}
+
+/// Server half of the UDP-over-TCP relay.
+///
+/// Each accepted connection is associated with its own UDP socket connected to `target`;
+/// frames are unwrapped into datagrams and replies framed back. A connection is closed after
+/// `idle_timeout` without traffic in either direction.
+pub async fn udp_over_tcp_server(
+    listener: TcpListener,
+    target: SocketAddr,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    udp_over_tcp_server_with(
+        listener,
+        &target.to_string(),
+        &DialOptions::default(),
+        idle_timeout,
+    )
+    .await
+}
+
+/// Like [`udp_over_tcp_server`], resolving the `host:port` of `target` for each connection
+/// with [`DualAddr::resolve_with`], preferring IPv4, and dialing it with `opts`.
+pub async fn udp_over_tcp_server_with(
+    listener: TcpListener,
+    target: &str,
+    opts: &DialOptions,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (target, opts) = (target.to_string(), opts.clone());
+        task::spawn(async move {
+            let _ = server_flow(stream, &target, &opts, idle_timeout).await;
+        });
+    }
+}
+
+async fn server_flow(
+    stream: TcpStream,
+    target: &str,
+    opts: &DialOptions,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let (v4, v6) = DualAddr::resolve_with(target, FamilyPreference::PreferV4, opts)
+        .await?
+        .dial_udp_with(opts)
+        .await?;
+    let udp = v4.or(v6).expect("dialed a single family");
+    let (mut reader, mut writer) = framed(stream);
+    let mut buf = vec![0u8; DATAGRAM_BUF_SIZE];
+    loop {
+        let up = async { Event::Up(reader.next().await) };
+        let down = async { Event::Down(udp.recv(&mut buf).await) };
+        let event = match up.race(down).timeout(idle_timeout).await {
+            Ok(event) => event,
+            Err(_) => return Ok(()),
+        };
+        match event {
+            Event::Up(Some(frame)) => {
+                udp.send(&frame?).await?;
+            }
+            Event::Up(None) => return Ok(()),
+            Event::Down(n) => writer.send(&buf[..n?]).await?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Lookup, Resolver, SocketAddrExt};
+    use async_trait::async_trait;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async fn echo_server(addr: &str) -> SocketAddr {
+        let sock = UdpSocket::bind(addr).await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        task::spawn(async move {
+            let mut buf = vec![0u8; DATAGRAM_BUF_SIZE];
+            loop {
+                let (n, peer) = sock.recv_from(&mut buf).await.unwrap();
+                sock.send_to(&buf[..n], peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn relay(idle_timeout: Duration, target: &str) -> SocketAddr {
+        let target = echo_server(target).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        task::spawn(udp_over_tcp_server(listener, target, idle_timeout));
+        let local = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = local.local_addr().unwrap();
+        task::spawn(udp_over_tcp_client(local, server, idle_timeout));
+        addr
+    }
+
+    async fn roundtrip(sock: &UdpSocket, data: &[u8]) -> Vec<u8> {
+        sock.send(data).await.unwrap();
+        let mut buf = vec![0u8; DATAGRAM_BUF_SIZE];
+        let n = sock
+            .recv(&mut buf)
+            .timeout(Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[test]
+    fn test_udp_over_tcp() {
+        task::block_on(async {
+            let addr = relay(UDP_OVER_TCP_IDLE_TIMEOUT, "127.0.0.1:0").await;
+            let a = addr.dial_udp().await.unwrap();
+            let b = addr.dial_udp().await.unwrap();
+            for i in 0..20u8 {
+                assert_eq!(roundtrip(&a, &[i; 100]).await, vec![i; 100]);
+                assert_eq!(roundtrip(&b, &[!i; 1400]).await, vec![!i; 1400]);
+            }
+            assert_eq!(roundtrip(&a, &[]).await, Vec::<u8>::new());
+            let big = vec![0x5a; 60000];
+            assert_eq!(roundtrip(&b, &big).await, big);
+        });
+    }
+
+    #[test]
+    fn test_udp_over_tcp_ipv6_target() {
+        task::block_on(async {
+            let addr = relay(UDP_OVER_TCP_IDLE_TIMEOUT, "[::1]:0").await;
+            let a = addr.dial_udp().await.unwrap();
+            assert_eq!(roundtrip(&a, b"over ipv6").await, b"over ipv6");
+        });
+    }
+
+    #[test]
+    fn test_udp_over_tcp_idle() {
+        task::block_on(async {
+            let addr = relay(Duration::from_millis(100), "127.0.0.1:0").await;
+            let a = addr.dial_udp().await.unwrap();
+            assert_eq!(roundtrip(&a, b"first").await, b"first");
+            task::sleep(Duration::from_millis(300)).await;
+            assert_eq!(roundtrip(&a, b"second").await, b"second");
+        });
+    }
+
+    /// Resolves every name to the IPv4 loopback address, counting lookups.
+    #[derive(Default)]
+    struct LoopbackResolver {
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Resolver for LoopbackResolver {
+        async fn lookup(&self, _host: &str) -> io::Result<Lookup> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(Lookup {
+                addrs: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                ttl: None,
+            })
+        }
+    }
+
+    #[test]
+    fn test_udp_over_tcp_resolver() {
+        task::block_on(async {
+            let resolver = Arc::new(LoopbackResolver::default());
+            let opts = DialOptions::new().resolver(resolver.clone());
+            let target = echo_server("127.0.0.1:0").await;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = format!("relay.test:{}", listener.local_addr().unwrap().port());
+            let target = format!("echo.test:{}", target.port());
+            let (server_opts, timeout) = (opts.clone(), UDP_OVER_TCP_IDLE_TIMEOUT);
+            task::spawn(async move {
+                udp_over_tcp_server_with(listener, &target, &server_opts, timeout).await
+            });
+            let local = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = local.local_addr().unwrap();
+            task::spawn(
+                async move { udp_over_tcp_client_with(local, &server, &opts, timeout).await },
+            );
+
+            let a = addr.dial_udp().await.unwrap();
+            assert_eq!(roundtrip(&a, b"by name").await, b"by name");
+            // One lookup for the relay server, one for the target.
+            assert_eq!(resolver.lookups.load(Ordering::SeqCst), 2);
+        });
+    }
+}